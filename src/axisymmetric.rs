@@ -0,0 +1,202 @@
+use crate::set_bnd;
+use crate::utils::{pure_ix_fn, BoundaryType};
+use std::cmp;
+
+// Operators of the axisymmetric mode, where x is the distance r from the
+// axis of rotation and y runs along it. The grid is a slice through a body of
+// revolution, so every cell stands for a ring whose volume grows with r.
+// Everything is in grid units like the planar operators, the center of
+// column i sits at r = i - 0.5 and the axis on the left edge of the grid
+
+fn radius(i: u16) -> f32 {
+    i as f32 - 0.5
+}
+
+// Weights of the left and right neighbours in the radial part of the
+// laplacian, the radii of the faces between them over the radius of the
+// cell. They always add up to 2 like the planar ones, and nothing flows
+// through the axis
+fn radial_weights(i: u16) -> (f32, f32) {
+    let r = radius(i);
+    ((r - 0.5) / r, (r + 0.5) / r)
+}
+
+// Diagonal and neighbour sum of the implicit diffusion system at a cell, so
+// that diagonal * value - neighbours = the value before diffusing. radial
+// adds the -u / r^2 term the radial velocity picks up
+#[allow(clippy::too_many_arguments)]
+fn diffusion_stencil(
+    property: &[f32],
+    i: u16,
+    j: u16,
+    nw: u16,
+    nh: u16,
+    k: f32,
+    weights: (f32, f32),
+    radial: bool,
+) -> (f32, f32) {
+    let (wx, wy) = weights;
+    let (west, east) = radial_weights(i);
+    let neighbours = k
+        * (wx
+            * (west * property[pure_ix_fn(i - 1, j, nw, nh)]
+                + east * property[pure_ix_fn(i + 1, j, nw, nh)])
+            + wy * (property[pure_ix_fn(i, j - 1, nw, nh)]
+                + property[pure_ix_fn(i, j + 1, nw, nh)]))
+        / 4.0;
+    let mut diagonal = 1.0 + k * (wx + wy) / 2.0;
+    if radial {
+        diagonal += k * wx / (4.0 * radius(i) * radius(i));
+    }
+    (diagonal, neighbours)
+}
+
+// Gauss-Seidel solve of the cylindrical diffusion, the counterpart of diffuse!
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn diffuse(
+    nw: u16,
+    nh: u16,
+    b: BoundaryType,
+    property: &mut [f32],
+    prev_property: &[f32],
+    diffusion: f32,
+    dt: f32,
+    omega: f32,
+    weights: (f32, f32),
+    diffusion_field: Option<&[f32]>,
+    iterations: u16,
+    radial: bool,
+) {
+    for _ in 0..iterations {
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let k = dt * diffusion_field.map_or(diffusion, |field| field[index]);
+                let (diagonal, neighbours) =
+                    diffusion_stencil(property, i, j, nw, nh, k, weights, radial);
+                let value = (prev_property[index] + neighbours) / diagonal;
+                property[index] = (1.0 - omega) * property[index] + omega * value;
+            }
+        }
+        set_bnd!(nw, nh, b, property);
+    }
+}
+
+// Largest remaining error of the system solved by diffuse
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn diffusion_residual(
+    nw: u16,
+    nh: u16,
+    property: &[f32],
+    prev_property: &[f32],
+    diffusion: f32,
+    dt: f32,
+    weights: (f32, f32),
+    diffusion_field: Option<&[f32]>,
+    radial: bool,
+) -> f32 {
+    let mut residual: f32 = 0.0;
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let index = pure_ix_fn(i, j, nw, nh);
+            let k = dt * diffusion_field.map_or(diffusion, |field| field[index]);
+            let (diagonal, neighbours) =
+                diffusion_stencil(property, i, j, nw, nh, k, weights, radial);
+            let error = diagonal * property[index] - neighbours - prev_property[index];
+            residual = residual.max(error.abs());
+        }
+    }
+    residual
+}
+
+// (1 / r) d(r u) / dr + dv / dy at a cell, by central differences
+pub(crate) fn divergence(
+    velocity_x: &[f32],
+    velocity_y: &[f32],
+    nw: u16,
+    nh: u16,
+    i: u16,
+    j: u16,
+) -> f32 {
+    0.5 * ((radius(i + 1) * velocity_x[pure_ix_fn(i + 1, j, nw, nh)]
+        - radius(i - 1) * velocity_x[pure_ix_fn(i - 1, j, nw, nh)])
+        / radius(i)
+        + velocity_y[pure_ix_fn(i, j + 1, nw, nh)]
+        - velocity_y[pure_ix_fn(i, j - 1, nw, nh)])
+}
+
+// The weighted sum of the four neighbours in the cylindrical laplacian
+fn pressure_neighbours(pressure: &[f32], nw: u16, nh: u16, i: u16, j: u16) -> f32 {
+    let (west, east) = radial_weights(i);
+    west * pressure[pure_ix_fn(i - 1, j, nw, nh)]
+        + east * pressure[pure_ix_fn(i + 1, j, nw, nh)]
+        + pressure[pure_ix_fn(i, j - 1, nw, nh)]
+        + pressure[pure_ix_fn(i, j + 1, nw, nh)]
+}
+
+// The counterpart of project! with the cylindrical divergence and laplacian
+#[allow(clippy::too_many_arguments)]
+pub(crate) fn project(
+    nw: u16,
+    nh: u16,
+    velocity_x: &mut [f32],
+    velocity_y: &mut [f32],
+    pressure: &mut [f32],
+    divergence_values: &mut [f32],
+    omega: f32,
+    iterations: u16,
+) {
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let index = pure_ix_fn(i, j, nw, nh);
+            divergence_values[index] = divergence(velocity_x, velocity_y, nw, nh, i, j);
+            pressure[index] = 0.0;
+        }
+    }
+    set_bnd!(nw, nh, BoundaryType::NONE, divergence_values);
+    set_bnd!(nw, nh, BoundaryType::NONE, pressure);
+
+    for _ in 0..iterations {
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let value =
+                    (pressure_neighbours(pressure, nw, nh, i, j) - divergence_values[index]) / 4.0;
+                pressure[index] = (1.0 - omega) * pressure[index] + omega * value;
+            }
+        }
+        set_bnd!(nw, nh, BoundaryType::NONE, pressure);
+    }
+
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let index = pure_ix_fn(i, j, nw, nh);
+            velocity_x[index] -= 0.5
+                * (pressure[pure_ix_fn(i + 1, j, nw, nh)] - pressure[pure_ix_fn(i - 1, j, nw, nh)]);
+            velocity_y[index] -= 0.5
+                * (pressure[pure_ix_fn(i, j + 1, nw, nh)] - pressure[pure_ix_fn(i, j - 1, nw, nh)]);
+        }
+    }
+    set_bnd!(nw, nh, BoundaryType::VERTICAL, velocity_x);
+    set_bnd!(nw, nh, BoundaryType::HORIZONTAL, velocity_y);
+}
+
+// The counterpart of poisson_residual! for the pressure project leaves
+pub(crate) fn pressure_residual(
+    nw: u16,
+    nh: u16,
+    pressure: &[f32],
+    divergence_values: &[f32],
+) -> f32 {
+    let mut residual: f32 = 0.0;
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let index = pure_ix_fn(i, j, nw, nh);
+            let error = pressure_neighbours(pressure, nw, nh, i, j)
+                - 4.0 * pressure[index]
+                - divergence_values[index];
+            residual = residual.max(error.abs());
+        }
+    }
+    residual
+}
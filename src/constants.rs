@@ -1 +1,39 @@
 pub const GAUSS_SEIDEL_ITERATIONS: u8 = 10;
+
+// Factor the automatic diffusion iteration count aims to shrink the error of
+// the solve by
+pub const AUTO_ITERATIONS_REDUCTION: f32 = 1e-3;
+
+// Conjugate gradient only pulls ahead of Gauss-Seidel after a few dozen
+// iterations, so it gets more of them than the sweeps above
+pub const CONJUGATE_GRADIENT_ITERATIONS: u8 = 30;
+
+// Width of the ghost border of cells around the grid that set_bnd writes to.
+// A row of the buffer is nw + 2 * BORDER cells long
+pub const BORDER: u16 = 1;
+
+// Time step the density dissipation factor is defined for. Dissipation is
+// applied as dissipation ^ (dt / DISSIPATION_REFERENCE_DT) so the fade rate
+// does not depend on how a second of simulated time is split into steps
+pub const DISSIPATION_REFERENCE_DT: f32 = 1.0;
+
+// Gauss-Seidel sweeps the incremental diffusion solve runs between checks of
+// its residual, which costs about as much as another sweep
+pub const INCREMENTAL_CHECK_SWEEPS: u16 = 3;
+
+// Density below which a cell counts as empty for get_age_at_index
+pub const AGE_MIN_DENSITY: f32 = 1e-3;
+
+// Density the presets fill their jets and vortices with
+pub const PRESET_DENSITY: f32 = 1.0;
+
+// Peak CFL number of each vortex of preset_vortex_pair, fast enough to see
+// the pair travel and slow enough to stay stable at any dt
+pub const PRESET_VORTEX_CFL: f32 = 0.5;
+
+// Cells around the dense part of the grid that active region tracking still
+// diffuses and advects, on top of how far the flow moves in a step
+pub const ACTIVE_REGION_MARGIN: u16 = 2;
+
+// Cells around the center that add_sink pulls fluid in from and drains
+pub const SINK_RADIUS: u16 = 3;
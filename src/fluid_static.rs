@@ -0,0 +1,209 @@
+use crate::constants::GAUSS_SEIDEL_ITERATIONS;
+use crate::utils::{
+    projection_boundaries, pure_ix_fn, remove_interior_mean, sample_property_with, BoundaryType,
+    InterpolationKind, SampleMode,
+};
+use crate::{
+    add_source, advect, diffuse, pressure_divergence, project, set_bnd, subtract_pressure_gradient,
+};
+use std::cmp;
+
+// A fixed size N * N fluid backed by arrays, so it never touches the heap.
+// SIZE must be (N + 2) * (N + 2), stable const generics cannot compute it.
+// It runs the same step as Fluid with the default config, through the same
+// macros
+pub struct FluidStatic<const N: u16, const SIZE: usize> {
+    diffusion: f32,
+    dt: f32,
+    velocity_x: [f32; SIZE],
+    velocity_y: [f32; SIZE],
+    initial_velocity_x: [f32; SIZE],
+    initial_velocity_y: [f32; SIZE],
+    velocity_x_source: [f32; SIZE],
+    velocity_y_source: [f32; SIZE],
+    density: [f32; SIZE],
+    initial_density: [f32; SIZE],
+    density_source: [f32; SIZE],
+    poisson_values: [f32; SIZE],
+    divergence_values: [f32; SIZE],
+}
+
+impl<const N: u16, const SIZE: usize> FluidStatic<N, SIZE> {
+    pub fn new(diffusion: f32, dt: f32) -> FluidStatic<N, SIZE> {
+        // Evaluated at compile time for every size the struct is used with
+        const {
+            assert!(N >= 1, "the grid needs at least one interior cell");
+            assert!(
+                SIZE == (N as usize + 2) * (N as usize + 2),
+                "SIZE must be (N + 2) * (N + 2)"
+            )
+        };
+        FluidStatic {
+            diffusion,
+            dt,
+            velocity_x: [0.0; SIZE],
+            velocity_y: [0.0; SIZE],
+            initial_velocity_x: [0.0; SIZE],
+            initial_velocity_y: [0.0; SIZE],
+            velocity_x_source: [0.0; SIZE],
+            velocity_y_source: [0.0; SIZE],
+            density: [0.0; SIZE],
+            initial_density: [0.0; SIZE],
+            density_source: [0.0; SIZE],
+            poisson_values: [0.0; SIZE],
+            divergence_values: [0.0; SIZE],
+        }
+    }
+
+    pub fn ix(&self, x: u16, y: u16) -> usize {
+        pure_ix_fn(x, y, N, N)
+    }
+
+    pub fn add_density(&mut self, index: usize, value: f32) {
+        self.density_source[index] = value;
+    }
+
+    pub fn add_velocity(&mut self, index: usize, value_x: f32, value_y: f32) {
+        self.velocity_x_source[index] = value_x;
+        self.velocity_y_source[index] = value_y;
+    }
+
+    pub fn get_density_at_index(&self, index: usize) -> f32 {
+        self.density[index]
+    }
+
+    pub fn get_velocity_x(&self, index: usize) -> f32 {
+        self.velocity_x[index]
+    }
+
+    pub fn get_velocity_y(&self, index: usize) -> f32 {
+        self.velocity_y[index]
+    }
+
+    pub fn set_dt(&mut self, dt: f32) {
+        self.dt = dt;
+    }
+
+    pub fn clear(&mut self) {
+        *self = FluidStatic::new(self.diffusion, self.dt);
+    }
+
+    pub fn simulate(&mut self) {
+        add_source!(
+            self.initial_velocity_x,
+            self.velocity_x_source,
+            SIZE,
+            self.dt
+        );
+        add_source!(
+            self.initial_velocity_y,
+            self.velocity_y_source,
+            SIZE,
+            self.dt
+        );
+
+        diffuse!(
+            N,
+            N,
+            BoundaryType::VERTICAL,
+            self.velocity_x,
+            self.initial_velocity_x,
+            self.diffusion,
+            self.dt,
+            1.0,
+            (1.0, 1.0)
+        );
+        std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
+
+        diffuse!(
+            N,
+            N,
+            BoundaryType::HORIZONTAL,
+            self.velocity_y,
+            self.initial_velocity_y,
+            self.diffusion,
+            self.dt,
+            1.0,
+            (1.0, 1.0)
+        );
+        std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
+
+        self.project_velocity();
+
+        advect!(
+            N,
+            N,
+            BoundaryType::VERTICAL,
+            self.velocity_x,
+            self.initial_velocity_x,
+            self.initial_velocity_x,
+            self.initial_velocity_y,
+            self.dt,
+            SampleMode::Clamp,
+            InterpolationKind::Bilinear
+        );
+        advect!(
+            N,
+            N,
+            BoundaryType::HORIZONTAL,
+            self.velocity_y,
+            self.initial_velocity_y,
+            self.initial_velocity_x,
+            self.initial_velocity_y,
+            self.dt,
+            SampleMode::Clamp,
+            InterpolationKind::Bilinear
+        );
+
+        self.project_velocity();
+
+        add_source!(self.initial_density, self.density_source, SIZE, self.dt);
+        diffuse!(
+            N,
+            N,
+            BoundaryType::NONE,
+            self.density,
+            self.initial_density,
+            self.diffusion,
+            self.dt,
+            1.0,
+            (1.0, 1.0)
+        );
+        std::mem::swap(&mut self.density, &mut self.initial_density);
+
+        advect!(
+            N,
+            N,
+            BoundaryType::NONE,
+            self.density,
+            self.initial_density,
+            self.velocity_x,
+            self.velocity_y,
+            self.dt,
+            SampleMode::Clamp,
+            InterpolationKind::Bilinear
+        );
+        std::mem::swap(&mut self.density, &mut self.initial_density);
+
+        // Like Fluid::finalize_step, copies the frame the swaps leave in the
+        // initial buffers over to the ones the getters read
+        self.density.copy_from_slice(&self.initial_density);
+        self.velocity_x.copy_from_slice(&self.initial_velocity_x);
+        self.velocity_y.copy_from_slice(&self.initial_velocity_y);
+    }
+
+    fn project_velocity(&mut self) {
+        project!(
+            N,
+            N,
+            self.velocity_x,
+            self.velocity_y,
+            self.poisson_values,
+            self.divergence_values,
+            1.0,
+            false
+        );
+        std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
+        std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
+    }
+}
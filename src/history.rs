@@ -0,0 +1,105 @@
+use crate::utils::PropertyType;
+use crate::Fluid;
+use std::collections::VecDeque;
+
+// The density and velocity of a fluid at the start of a step. Both buffers
+// of every property are kept so a restored fluid continues exactly as the
+// original did, and so is the pressure artificial compressibility carries
+// over from step to step. The age buffers are empty while age tracking is
+// off, so they only cost memory when there are ages to restore
+struct Snapshot {
+    steps: u64,
+    elapsed_time: f64,
+    velocity_x: PropertyType,
+    velocity_y: PropertyType,
+    initial_velocity_x: PropertyType,
+    initial_velocity_y: PropertyType,
+    density: PropertyType,
+    initial_density: PropertyType,
+    pressure: PropertyType,
+    age: PropertyType,
+    initial_age: PropertyType,
+}
+
+impl Snapshot {
+    fn take(fluid: &Fluid) -> Snapshot {
+        Snapshot {
+            steps: fluid.steps,
+            elapsed_time: fluid.elapsed_time,
+            velocity_x: fluid.velocity_x.clone(),
+            velocity_y: fluid.velocity_y.clone(),
+            initial_velocity_x: fluid.initial_velocity_x.clone(),
+            initial_velocity_y: fluid.initial_velocity_y.clone(),
+            density: fluid.density.clone(),
+            initial_density: fluid.initial_density.clone(),
+            pressure: fluid.pressure.clone(),
+            age: fluid.age.clone(),
+            initial_age: fluid.initial_age.clone(),
+        }
+    }
+
+    fn restore(self, fluid: &mut Fluid) {
+        fluid.steps = self.steps;
+        fluid.elapsed_time = self.elapsed_time;
+        fluid.velocity_x = self.velocity_x;
+        fluid.velocity_y = self.velocity_y;
+        fluid.initial_velocity_x = self.initial_velocity_x;
+        fluid.initial_velocity_y = self.initial_velocity_y;
+        fluid.density = self.density;
+        fluid.initial_density = self.initial_density;
+        fluid.pressure = self.pressure;
+        // Tracking may have been switched since, keep the buffers matching it
+        if fluid.track_age && self.age.len() == fluid.size {
+            fluid.age = self.age;
+            fluid.initial_age = self.initial_age;
+        } else if fluid.track_age {
+            fluid.age = fluid.empty_property.clone();
+            fluid.initial_age = fluid.empty_property.clone();
+        }
+    }
+}
+
+// The last capacity snapshots of a fluid, oldest first. A capacity of 0
+// keeps nothing
+#[derive(Default)]
+pub(crate) struct History {
+    capacity: usize,
+    snapshots: VecDeque<Snapshot>,
+}
+
+impl History {
+    pub(crate) fn set_capacity(&mut self, capacity: usize) {
+        self.capacity = capacity;
+        while self.snapshots.len() > capacity {
+            self.snapshots.pop_front();
+        }
+    }
+
+    pub(crate) fn len(&self) -> usize {
+        self.snapshots.len()
+    }
+
+    // Drops the oldest snapshot once the buffer is full
+    pub(crate) fn record(&mut self, fluid: &Fluid) {
+        if self.capacity == 0 {
+            return;
+        }
+        if self.snapshots.len() == self.capacity {
+            self.snapshots.pop_front();
+        }
+        self.snapshots.push_back(Snapshot::take(fluid));
+    }
+
+    // Restores the state from steps snapshots ago and forgets the ones after
+    // it. Leaves the fluid alone and returns false when there are fewer
+    pub(crate) fn rewind(&mut self, fluid: &mut Fluid, steps: usize) -> bool {
+        if steps == 0 || steps > self.snapshots.len() {
+            return false;
+        }
+        self.snapshots.truncate(self.snapshots.len() - steps + 1);
+        if let Some(snapshot) = self.snapshots.pop_back() {
+            snapshot.restore(fluid);
+        }
+        true
+    }
+}
@@ -1,11 +1,54 @@
+mod axisymmetric;
 mod constants;
+mod fluid_static;
+mod history;
+mod npy;
+mod presenter;
+mod recorder;
+mod schedule;
+mod solver;
+mod spectrum;
+mod state;
+mod stepper;
+mod timings;
 mod utils;
 
-use constants::GAUSS_SEIDEL_ITERATIONS;
+use constants::{
+    ACTIVE_REGION_MARGIN, AGE_MIN_DENSITY, AUTO_ITERATIONS_REDUCTION, DISSIPATION_REFERENCE_DT,
+    GAUSS_SEIDEL_ITERATIONS, INCREMENTAL_CHECK_SWEEPS, PRESET_DENSITY, PRESET_VORTEX_CFL,
+    SINK_RADIUS,
+};
+use history::History;
+use js_sys::{Array, Float64Array, Function};
+use npy::npy_bytes;
+use presenter::FramePublisher;
+use solver::solve_checked;
 use std::cmp;
-use utils::{lerp, pure_ix_fn, set_panic_hook, BoundaryType, PropertyType};
+use std::ops::Range;
+use timings::{StageKind, Stopwatch};
+use utils::{
+    advect_around_solids, auto_iterations, box_blur, box_weights, cells_to_faces_x,
+    cells_to_faces_y, copy_outside, disc_cells, faces_x_to_cells, faces_y_to_cells, fnv1a_f32,
+    interior_sum, lerp, line_cells, mirror_horizontally, mirror_vertically, projection_boundaries,
+    pure_ix_fn, remove_interior_mean, sample_corners, sample_property, sample_property_with,
+    set_panic_hook, wall_distances, BoundaryType, Property, PropertyType,
+};
 use wasm_bindgen::prelude::*;
 
+pub use constants::BORDER;
+pub use fluid_static::FluidStatic;
+pub use presenter::{Frame, FrameReader};
+pub use recorder::{replay, Event, Record, Recorder};
+pub use schedule::DiffusionSchedule;
+pub use solver::{ConjugateGradient, GaussSeidel, LinearSystem, Solver, SolverKind};
+pub use state::FluidState;
+pub use stepper::Stepper;
+pub use timings::StageTimings;
+pub use utils::{
+    AdvectionScheme, BlendMode, BoundaryMode, Field, IncompressibilityMethod, InterpolationKind,
+    SampleMode, Side, SolidAdvection, SourceApplication, Symmetry, ToneMap,
+};
+
 // When the `wee_alloc` feature is enabled, use `wee_alloc` as the global
 // allocator.
 #[cfg(feature = "wee_alloc")]
@@ -35,27 +78,349 @@ extern "C" {
 }
 
 #[wasm_bindgen]
+#[derive(Clone)]
 pub struct FluidConfig {
     nw: u16,
     nh: u16,
     diffusion: f32,
+    // Over-relaxation factor for the Gauss-Seidel solves. 1.0 is plain
+    // Gauss-Seidel, values up to ~1.9 converge faster
+    omega: f32,
+    // Fraction of density kept per DISSIPATION_REFERENCE_DT of simulated
+    // time. 1.0 keeps all of it
+    dissipation: f32,
+    // Physical size of a cell and the world position of the grid's corner,
+    // used to scale diffusion and advection into physical units
+    dx: f32,
+    origin_x: f32,
+    origin_y: f32,
+    advection_scheme: AdvectionScheme,
+    boundary_mode: BoundaryMode,
+    // Relative diffusion along x and y, (1, 1) is isotropic
+    diffusion_weights: (f32, f32),
+    // Fraction of the velocity along a wall kept at the wall. 1, the
+    // default, is the free slip set_bnd has always applied, 0 is no slip
+    wall_friction: f32,
+    pressure_solver: SolverKind,
+    // Rescales density after advection so a closed box neither gains nor
+    // loses mass
+    conserve_mass: bool,
+    // Removes the drift of the total momentum over the diffuse, advect and
+    // project stages of a step, for closed boxes without outside forces
+    conserve_momentum: bool,
+    sample_mode: SampleMode,
+    interpolation: InterpolationKind,
+    // Mirror symmetry forced onto the fields after every step, so symmetric
+    // sources stay symmetric despite the order of the Gauss-Seidel sweeps
+    symmetry: Symmetry,
+    // Diffusion solves per step, each over dt / diffusion_substeps. More
+    // substeps follow the exact diffusion more closely at strong diffusion,
+    // at the cost of a full solve each
+    diffusion_substeps: u16,
+    // When set, the built in diffusion solve picks its sweep count from the
+    // diffusion rate, capped at this, instead of GAUSS_SEIDEL_ITERATIONS
+    auto_iterations: Option<u16>,
+    incompressibility: IncompressibilityMethod,
+    // Speed of the pressure waves of artificial compressibility. Faster
+    // waves keep the flow closer to incompressible but need a smaller dt
+    sound_speed: f32,
+    source_application: SourceApplication,
+    // Starts the built in diffusion solve from the field being diffused and
+    // stops sweeping once it has converged, instead of always running the
+    // full sweep count from the stale result of the last step
+    incremental_solve: bool,
+    solid_advection: SolidAdvection,
 }
 
 #[wasm_bindgen]
 impl FluidConfig {
+    // A grid without interior cells has nothing to simulate, and sampling a
+    // zero sized grid divides by zero, so nw and nh must be at least 1
     pub fn new(nw: u16, nh: u16, diffusion: f32) -> FluidConfig {
-        FluidConfig { nw, nh, diffusion }
+        assert!(
+            nw >= 1 && nh >= 1,
+            "the grid needs at least one interior cell"
+        );
+        FluidConfig {
+            nw,
+            nh,
+            diffusion,
+            omega: 1.0,
+            dissipation: 1.0,
+            dx: 1.0,
+            origin_x: 0.0,
+            origin_y: 0.0,
+            advection_scheme: AdvectionScheme::SemiLagrangian,
+            boundary_mode: BoundaryMode::Walls,
+            diffusion_weights: (1.0, 1.0),
+            wall_friction: 1.0,
+            pressure_solver: SolverKind::GaussSeidel,
+            conserve_mass: false,
+            conserve_momentum: false,
+            sample_mode: SampleMode::Clamp,
+            interpolation: InterpolationKind::Bilinear,
+            symmetry: Symmetry::None,
+            diffusion_substeps: 1,
+            auto_iterations: None,
+            incompressibility: IncompressibilityMethod::Projection,
+            sound_speed: 5.0,
+            source_application: SourceApplication::OncePerFrame,
+            incremental_solve: false,
+            solid_advection: SolidAdvection::Through,
+        }
     }
 
+    // Setters ignore values the solver cannot work with
+
     pub fn set_diffusion(&mut self, diffusion: f32) {
-        self.diffusion = diffusion
+        if diffusion >= 0.0 && diffusion.is_finite() {
+            self.diffusion = diffusion
+        }
     }
 
     pub fn get_diffusion(&self) -> f32 {
         self.diffusion
     }
+
+    // Lets diffusion favour one direction, e.g. (2, 1) spreads twice as fast
+    // horizontally as vertically
+    pub fn set_diffusion_weights(&mut self, weight_x: f32, weight_y: f32) {
+        if weight_x >= 0.0 && weight_y >= 0.0 {
+            self.diffusion_weights = (weight_x, weight_y)
+        }
+    }
+
+    // Between 0, where walls stop the flow along them, and 1, where it slides
+    // past freely
+    pub fn set_wall_friction(&mut self, wall_friction: f32) {
+        if (0.0..=1.0).contains(&wall_friction) {
+            self.wall_friction = wall_friction
+        }
+    }
+
+    pub fn get_wall_friction(&self) -> f32 {
+        self.wall_friction
+    }
+
+    // Over-relaxation diverges outside (0, 2)
+    pub fn set_omega(&mut self, omega: f32) {
+        if omega > 0.0 && omega < 2.0 {
+            self.omega = omega
+        }
+    }
+
+    pub fn get_omega(&self) -> f32 {
+        self.omega
+    }
+
+    pub fn set_dissipation(&mut self, dissipation: f32) {
+        if (0.0..=1.0).contains(&dissipation) {
+            self.dissipation = dissipation
+        }
+    }
+
+    pub fn get_dissipation(&self) -> f32 {
+        self.dissipation
+    }
+
+    pub fn set_dx(&mut self, dx: f32) {
+        if dx > 0.0 && dx.is_finite() {
+            self.dx = dx
+        }
+    }
+
+    pub fn get_dx(&self) -> f32 {
+        self.dx
+    }
+
+    pub fn set_advection_scheme(&mut self, scheme: AdvectionScheme) {
+        self.advection_scheme = scheme
+    }
+
+    pub fn get_advection_scheme(&self) -> AdvectionScheme {
+        self.advection_scheme
+    }
+
+    pub fn set_boundary_mode(&mut self, mode: BoundaryMode) {
+        self.boundary_mode = mode
+    }
+
+    pub fn get_boundary_mode(&self) -> BoundaryMode {
+        self.boundary_mode
+    }
+
+    pub fn set_pressure_solver(&mut self, kind: SolverKind) {
+        self.pressure_solver = kind
+    }
+
+    pub fn get_pressure_solver(&self) -> SolverKind {
+        self.pressure_solver
+    }
+
+    pub fn set_conserve_mass(&mut self, conserve_mass: bool) {
+        self.conserve_mass = conserve_mass
+    }
+
+    pub fn get_conserve_mass(&self) -> bool {
+        self.conserve_mass
+    }
+
+    pub fn set_conserve_momentum(&mut self, conserve_momentum: bool) {
+        self.conserve_momentum = conserve_momentum
+    }
+
+    pub fn get_conserve_momentum(&self) -> bool {
+        self.conserve_momentum
+    }
+
+    pub fn set_sample_mode(&mut self, mode: SampleMode) {
+        self.sample_mode = mode
+    }
+
+    pub fn get_sample_mode(&self) -> SampleMode {
+        self.sample_mode
+    }
+
+    pub fn set_interpolation(&mut self, interpolation: InterpolationKind) {
+        self.interpolation = interpolation
+    }
+
+    pub fn get_interpolation(&self) -> InterpolationKind {
+        self.interpolation
+    }
+
+    pub fn set_symmetry(&mut self, symmetry: Symmetry) {
+        self.symmetry = symmetry
+    }
+
+    pub fn get_symmetry(&self) -> Symmetry {
+        self.symmetry
+    }
+
+    pub fn set_diffusion_substeps(&mut self, substeps: u16) {
+        if substeps >= 1 {
+            self.diffusion_substeps = substeps
+        }
+    }
+
+    pub fn get_diffusion_substeps(&self) -> u16 {
+        self.diffusion_substeps
+    }
+
+    // Lets the diffusion solve run as many sweeps as its rate needs, up to
+    // max_iterations, see auto_iterations for the heuristic
+    pub fn set_auto_iterations(&mut self, max_iterations: u16) {
+        if max_iterations >= 1 {
+            self.auto_iterations = Some(max_iterations)
+        }
+    }
+
+    // Back to the fixed GAUSS_SEIDEL_ITERATIONS sweeps
+    pub fn clear_auto_iterations(&mut self) {
+        self.auto_iterations = None
+    }
+
+    pub fn get_auto_iterations(&self) -> Option<u16> {
+        self.auto_iterations
+    }
+
+    pub fn set_incompressibility(&mut self, method: IncompressibilityMethod) {
+        self.incompressibility = method
+    }
+
+    pub fn get_incompressibility(&self) -> IncompressibilityMethod {
+        self.incompressibility
+    }
+
+    pub fn set_sound_speed(&mut self, sound_speed: f32) {
+        if sound_speed > 0.0 && sound_speed.is_finite() {
+            self.sound_speed = sound_speed
+        }
+    }
+
+    pub fn get_sound_speed(&self) -> f32 {
+        self.sound_speed
+    }
+
+    pub fn set_source_application(&mut self, application: SourceApplication) {
+        self.source_application = application
+    }
+
+    pub fn get_source_application(&self) -> SourceApplication {
+        self.source_application
+    }
+
+    pub fn set_incremental_solve(&mut self, incremental_solve: bool) {
+        self.incremental_solve = incremental_solve
+    }
+
+    pub fn get_incremental_solve(&self) -> bool {
+        self.incremental_solve
+    }
+
+    pub fn set_solid_advection(&mut self, solid_advection: SolidAdvection) {
+        self.solid_advection = solid_advection
+    }
+
+    pub fn get_solid_advection(&self) -> SolidAdvection {
+        self.solid_advection
+    }
+
+    pub fn set_origin(&mut self, origin_x: f32, origin_y: f32) {
+        self.origin_x = origin_x;
+        self.origin_y = origin_y;
+    }
+
+    pub fn get_nw(&self) -> u16 {
+        self.nw
+    }
+
+    pub fn get_nh(&self) -> u16 {
+        self.nh
+    }
 }
 
+// Sweeps over the grid made by a Gauss-Seidel solve and by a projection
+const SOLVE_SWEEPS: u32 = GAUSS_SEIDEL_ITERATIONS as u32;
+const PROJECT_SWEEPS: u32 = GAUSS_SEIDEL_ITERATIONS as u32 + 3;
+
+// A stage of a step, the number of grid sweeps it costs and what
+// simulate_timed counts its time towards
+type Stage = (fn(&mut Fluid), u32, StageKind);
+
+// User code run by a step at a fixed point, see set_mid_step_hook
+type StepHook = Box<dyn FnMut(&mut Fluid)>;
+
+// Every stage of a step in the order they are run
+const STEP_STAGES: [Stage; 26] = [
+    (Fluid::apply_diffusion_schedule, 0, StageKind::Other),
+    (Fluid::add_velocity_sources, 0, StageKind::Other),
+    (Fluid::apply_density_force, 1, StageKind::Other),
+    (Fluid::apply_coriolis, 1, StageKind::Other),
+    (Fluid::record_momentum, 1, StageKind::Other),
+    (Fluid::diffuse_velocity_x, SOLVE_SWEEPS, StageKind::Diffuse),
+    (Fluid::diffuse_velocity_y, SOLVE_SWEEPS, StageKind::Diffuse),
+    (Fluid::project_velocity, PROJECT_SWEEPS, StageKind::Project),
+    (Fluid::run_mid_step_hook, 0, StageKind::Other),
+    (Fluid::advect_velocity, 2, StageKind::Advect),
+    (Fluid::project_velocity, PROJECT_SWEEPS, StageKind::Project),
+    (Fluid::conserve_velocity_momentum, 1, StageKind::Other),
+    (Fluid::apply_sponge, 1, StageKind::Other),
+    (Fluid::limit_speed, 1, StageKind::Other),
+    (Fluid::apply_smooth_density, 0, StageKind::Other),
+    (Fluid::diffuse_density, SOLVE_SWEEPS, StageKind::Diffuse),
+    (Fluid::advect_density, 1, StageKind::Advect),
+    (Fluid::advect_age, 1, StageKind::Advect),
+    (Fluid::apply_height_continuity, 1, StageKind::Other),
+    (Fluid::conserve_density_mass, 1, StageKind::Other),
+    (Fluid::dissipate_density, 1, StageKind::Other),
+    (Fluid::apply_solids, 0, StageKind::Other),
+    (Fluid::enforce_symmetry, 1, StageKind::Other),
+    (Fluid::finalize_step, 1, StageKind::Other),
+    (Fluid::measure_step_delta, 1, StageKind::Other),
+    (Fluid::count_step, 0, StageKind::Other),
+];
+
 #[wasm_bindgen]
 pub struct Fluid {
     config: FluidConfig,
@@ -70,228 +435,3001 @@ pub struct Fluid {
     density: PropertyType,
     initial_density: PropertyType,
     density_source: PropertyType,
+    // Time since the density in each cell was injected, advected like the
+    // density. Empty unless track_age is set
+    age: PropertyType,
+    initial_age: PropertyType,
+    track_age: bool,
     poisson_values: PropertyType,
     divergence_values: PropertyType,
-    size: u16,
+    // Pressure carried over between steps by artificial compressibility
+    pressure: PropertyType,
+    solids: Vec<bool>,
+    // Every solid cell with its fluid neighbours, rebuilt whenever solids
+    // change so apply_solids does not search for them each step
+    solid_neighbours: Vec<(usize, Vec<usize>)>,
+    // When set, density is carried by this field instead of the simulated one
+    advection_velocity: Option<(PropertyType, PropertyType)>,
+    // Largest solver residuals of the last step, checked against the tolerance
+    last_diffuse_residual: f32,
+    // Gauss-Seidel sweeps run by the diffusion solves of the last step
+    last_diffuse_sweeps: u32,
+    last_projection_residual: f32,
+    residual_tolerance: f32,
+    // State at the start of the current step and the largest change of any
+    // cell over the last step, kept while track_step_delta is set
+    previous_velocity_x: PropertyType,
+    previous_velocity_y: PropertyType,
+    previous_density: PropertyType,
+    last_step_delta: f32,
+    track_step_delta: bool,
+    // Cells easing towards a target density as (index, target, rate)
+    smooth_density: Vec<(usize, f32, f32)>,
+    // Completed steps
+    steps: u64,
+    // Sum of the dt of the completed steps, in f64 so long runs of small
+    // steps do not lose time to rounding
+    elapsed_time: f64,
+    // Scale of the force pushing velocity down the density gradient
+    density_force_coupling: f32,
+    // Coriolis parameter, twice the rate the frame of reference turns at
+    coriolis: f32,
+    // Gravity of the shallow water mode, which treats density as the water
+    // height. None simulates smoke
+    shallow_water: Option<f32>,
+    // Whether x is the radius of a flow with rotational symmetry
+    axisymmetric: bool,
+    // Width in cells and strength of the velocity damping next to walls
+    sponge: (u16, f32),
+    // Distance of every cell to the nearest wall or solid, up to the sponge
+    // width, found again only when either changes
+    sponge_distances: Vec<u16>,
+    // Fastest any cell may move at the end of a step
+    max_speed: f32,
+    // Next entry of STEP_STAGES to run for a partial step
+    stage_cursor: usize,
+    // Interior density once this step's sources are added
+    density_mass: f32,
+    // Total momentum once this step's sources and forces are added
+    momentum: (f32, f32),
+    // Replaces the built in Gauss-Seidel for the diffuse and project systems
+    solver: Option<Box<dyn Solver>>,
+    // Called between the diffusion and the advection of the velocity
+    mid_step_hook: Option<StepHook>,
+    // Density a cell needs for active region tracking to count it as
+    // occupied. None diffuses and advects density over the whole grid
+    active_region_threshold: Option<f32>,
+    // Columns and rows the density step of this step is restricted to
+    active_region: Option<(Range<u16>, Range<u16>)>,
+    // Overrides the configured diffusion at the start of every step
+    diffusion_schedule: DiffusionSchedule,
+    // Per cell diffusion replacing the configured one where set
+    diffusion_field: Option<Vec<Option<f32>>>,
+    // The coefficients take_diffusion_field_coefficients last found, with the
+    // diffusion and dx they were found for
+    diffusion_field_cache: Option<(f32, f32, PropertyType)>,
+    // Snapshots taken at the start of the last steps, for rewind
+    history: History,
+    // Most substeps simulate may split a frame into to keep the CFL number
+    // below 1. None runs every frame as a single step
+    auto_substep: Option<u16>,
+    // Whether the last frame needed more substeps than allowed
+    auto_substep_capped: bool,
+    // Shared with the FrameReaders once one has been asked for
+    presented: Option<FramePublisher>,
+    size: usize,
 }
 
 #[wasm_bindgen]
 impl Fluid {
     pub fn new(config: FluidConfig, dt: f32) -> Fluid {
         set_panic_hook();
-        let size = (config.nw + 2) * (config.nh + 2);
-        let vector_size = size.into();
+        let size = (config.nw as usize + 2) * (config.nh as usize + 2);
         Fluid {
             config,
             dt,
-            empty_property: vec![0.0; vector_size],
-            velocity_x: vec![0.0; vector_size],
-            velocity_y: vec![0.0; vector_size],
-            initial_velocity_x: vec![0.0; vector_size],
-            initial_velocity_y: vec![0.0; vector_size],
-            velocity_x_source: vec![0.0; vector_size],
-            velocity_y_source: vec![0.0; vector_size],
-            density: vec![0.0; vector_size],
-            initial_density: vec![0.0; vector_size],
-            density_source: vec![0.0; vector_size],
-            poisson_values: vec![0.0; vector_size],
-            divergence_values: vec![0.0; vector_size],
+            empty_property: vec![0.0; size],
+            velocity_x: vec![0.0; size],
+            velocity_y: vec![0.0; size],
+            initial_velocity_x: vec![0.0; size],
+            initial_velocity_y: vec![0.0; size],
+            velocity_x_source: vec![0.0; size],
+            velocity_y_source: vec![0.0; size],
+            density: vec![0.0; size],
+            initial_density: vec![0.0; size],
+            density_source: vec![0.0; size],
+            age: Vec::new(),
+            initial_age: Vec::new(),
+            track_age: false,
+            poisson_values: vec![0.0; size],
+            divergence_values: vec![0.0; size],
+            pressure: vec![0.0; size],
+            solids: vec![false; size],
+            solid_neighbours: Vec::new(),
+            advection_velocity: None,
+            last_diffuse_residual: 0.0,
+            last_diffuse_sweeps: 0,
+            last_projection_residual: 0.0,
+            residual_tolerance: f32::INFINITY,
+            previous_velocity_x: vec![0.0; size],
+            previous_velocity_y: vec![0.0; size],
+            previous_density: vec![0.0; size],
+            last_step_delta: 0.0,
+            track_step_delta: false,
+            smooth_density: Vec::new(),
+            steps: 0,
+            elapsed_time: 0.0,
+            density_force_coupling: 0.0,
+            coriolis: 0.0,
+            shallow_water: None,
+            axisymmetric: false,
+            sponge: (0, 0.0),
+            sponge_distances: Vec::new(),
+            max_speed: f32::INFINITY,
+            stage_cursor: STEP_STAGES.len(),
+            density_mass: 0.0,
+            momentum: (0.0, 0.0),
+            solver: None,
+            mid_step_hook: None,
+            active_region_threshold: None,
+            active_region: None,
+            diffusion_schedule: DiffusionSchedule::default(),
+            diffusion_field: None,
+            diffusion_field_cache: None,
+            history: History::default(),
+            auto_substep: None,
+            auto_substep_capped: false,
+            presented: None,
             size,
         }
     }
 
-    fn density_step(&mut self) {
+    // Bytes taken by the grid buffers of an nw * nh fluid, for checking a
+    // memory budget before allocating. Takes both sides rather than a single
+    // n since grids need not be square. A prescribed advection velocity and
+    // age tracking each add two more f32 buffers on top, and a diffusion
+    // field one of Option<f32>
+    pub fn memory_bytes(nw: u16, nh: u16) -> usize {
+        // Every PropertyType field of Fluid
+        const F32_BUFFERS: usize = 16;
+        let size = (nw as usize + 2) * (nh as usize + 2);
+        size * (F32_BUFFERS * std::mem::size_of::<f32>() + std::mem::size_of::<bool>())
+    }
+
+    // Moves each smoothly injected cell towards its target by at most rate * dt
+    // and drops the cells that have reached it
+    fn apply_smooth_density(&mut self) {
+        let dt = self.dt;
+        let density = &mut self.initial_density;
+        self.smooth_density.retain(|&(index, target, rate)| {
+            let max_change = rate * dt;
+            let difference = target - density[index];
+            if difference.abs() <= max_change {
+                density[index] = target;
+                false
+            } else {
+                density[index] += max_change.copysign(difference);
+                true
+            }
+        });
+    }
+
+    // Sources go into the initial buffers the step continues from, once per
+    // step and before any diffusion substeps, so substeps never add them
+    // twice. Unless they are kept for every step they are used up here
+    fn add_density_sources(&mut self) {
+        if self.track_age {
+            for index in 0..self.size {
+                if self.density_source[index] > 0.0 {
+                    self.initial_age[index] = 0.0;
+                }
+            }
+        }
         add_source!(
             self.initial_density,
             self.density_source,
-            self.size as usize,
-            self.dt
+            self.size,
+            self.dt,
+            self.keep_sources()
         );
+        self.density_mass = interior_sum(&self.initial_density, self.config.nw, self.config.nh);
+    }
 
-        diffuse!(
-            self.config.nw,
-            self.config.nh,
-            BoundaryType::NONE,
-            self.density,
-            self.initial_density,
-            self.config.diffusion,
-            self.dt
-        );
+    fn diffuse_density(&mut self) {
+        self.add_density_sources();
+        self.active_region = self.find_active_region();
+        self.diffuse_property(Property::Density);
+    }
+
+    // The cells of disc_cells with their distance from the center and the
+    // unit vector pointing away from it, which is 0 at the center itself
+    fn radial_cells(&self, cx: u16, cy: u16, radius: u16) -> Vec<(usize, f32, f32, f32)> {
+        disc_cells(cx, cy, radius, self.config.nw, self.config.nh)
+            .into_iter()
+            .map(|(index, dx, dy)| {
+                let r = (dx * dx + dy * dy).sqrt();
+                if r > 0.0 {
+                    (index, r, dx / r, dy / r)
+                } else {
+                    (index, r, 0.0, 0.0)
+                }
+            })
+            .collect()
+    }
+
+    // The box around every cell above the tracking threshold, grown by how
+    // far density can move this step. None when tracking is off, the box
+    // covers the whole grid or the step takes a path that always runs over
+    // all of it: periodic wrapping, a prescribed advection velocity, a
+    // custom solver, the axisymmetric or incremental diffusion and BFECC
+    fn find_active_region(&self) -> Option<(Range<u16>, Range<u16>)> {
+        let threshold = self.active_region_threshold?;
+        if self.config.boundary_mode == BoundaryMode::Periodic
+            || self.advection_velocity.is_some()
+            || self.solver.is_some()
+            || self.axisymmetric()
+            || self.config.incremental_solve
+            || self.config.advection_scheme != AdvectionScheme::SemiLagrangian
+        {
+            return None;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let (mut min_x, mut max_x, mut min_y, mut max_y) = (nw + 1, 0, nh + 1, 0);
+        // The fastest cell bounds how far density moves, found in the same
+        // pass so the box costs one sweep of the grid
+        let mut speed: f32 = 0.0;
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                speed = speed.max(self.velocity_x[index].hypot(self.velocity_y[index]));
+                if self.initial_density[index] > threshold {
+                    min_x = cmp::min(min_x, i);
+                    max_x = cmp::max(max_x, i);
+                    min_y = cmp::min(min_y, j);
+                    max_y = cmp::max(max_y, j);
+                }
+            }
+        }
+        if max_x == 0 {
+            return Some((1..1, 1..1));
+        }
+        let reach = (speed * self.dt / self.config.dx).ceil();
+        let margin = ACTIVE_REGION_MARGIN.saturating_add(reach.min(u16::MAX as f32) as u16);
+        let columns = cmp::max(min_x.saturating_sub(margin), 1)
+            ..cmp::min(max_x.saturating_add(margin), nw) + 1;
+        let rows = cmp::max(min_y.saturating_sub(margin), 1)
+            ..cmp::min(max_y.saturating_add(margin), nh) + 1;
+        if columns.len() == nw as usize && rows.len() == nh as usize {
+            return None;
+        }
+        Some((columns, rows))
+    }
 
+    // The active region only holds for the density diffuse_density found it
+    // on, a later step_advect_density on its own sweeps the whole grid
+    fn advect_density(&mut self) {
+        self.advect_property(Property::Density);
+        self.active_region = None;
         std::mem::swap(&mut self.density, &mut self.initial_density);
+    }
 
-        advect!(
-            self.config.nw,
-            self.config.nh,
-            BoundaryType::NONE,
-            self.density,
-            self.initial_density,
-            self.velocity_x,
-            self.velocity_y,
-            self.dt
+    // Everything grows a step older, then the ages move with the density
+    fn advect_age(&mut self) {
+        if !self.track_age {
+            return;
+        }
+        for age in self.initial_age.iter_mut() {
+            *age += self.dt;
+        }
+        self.advect_property(Property::Age);
+        std::mem::swap(&mut self.age, &mut self.initial_age);
+    }
+
+    // Advection only moves the water around. Where the flow spreads out the
+    // height also drops by h * div(u) * dt, and where it converges it rises,
+    // which is what turns a bump into outgoing ripples
+    fn apply_height_continuity(&mut self) {
+        if self.shallow_water.is_none() {
+            return;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let scale = self.dt / (2.0 * self.config.dx);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let divergence = self.initial_velocity_x[pure_ix_fn(i + 1, j, nw, nh)]
+                    - self.initial_velocity_x[pure_ix_fn(i - 1, j, nw, nh)]
+                    + self.initial_velocity_y[pure_ix_fn(i, j + 1, nw, nh)]
+                    - self.initial_velocity_y[pure_ix_fn(i, j - 1, nw, nh)];
+                let factor = (1.0 - scale * divergence).max(0.0);
+                self.density[index] *= factor;
+                self.initial_density[index] *= factor;
+            }
+        }
+        let b = self.boundary(BoundaryType::NONE);
+        set_bnd!(nw, nh, b, self.density);
+        set_bnd!(nw, nh, b, self.initial_density);
+    }
+
+    // Damps the velocity within the sponge width of a wall or solid, hardest
+    // right next to it, so flow hitting the edges does not bounce back
+    fn apply_sponge(&mut self) {
+        let (width, strength) = self.sponge;
+        if width == 0 || strength == 0.0 {
+            return;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let distance = self.sponge_distances[index];
+                if distance == 0 || distance > width {
+                    continue;
+                }
+                let weight = (width + 1 - distance) as f32 / width as f32;
+                let factor = (1.0 - strength * self.dt * weight).max(0.0);
+                self.velocity_x[index] *= factor;
+                self.velocity_y[index] *= factor;
+                self.initial_velocity_x[index] *= factor;
+                self.initial_velocity_y[index] *= factor;
+            }
+        }
+    }
+
+    // Scales the velocity of cells faster than max_speed down to it, keeping
+    // the direction
+    fn limit_speed(&mut self) {
+        if self.max_speed == f32::INFINITY {
+            return;
+        }
+        for (velocity_x, velocity_y) in [
+            (&mut self.velocity_x, &mut self.velocity_y),
+            (&mut self.initial_velocity_x, &mut self.initial_velocity_y),
+        ] {
+            for index in 0..self.size {
+                let speed = velocity_x[index].hypot(velocity_y[index]);
+                if speed > self.max_speed {
+                    let factor = self.max_speed / speed;
+                    velocity_x[index] *= factor;
+                    velocity_y[index] *= factor;
+                }
+            }
+        }
+    }
+
+    // Semi-lagrangian advection does not conserve mass, and back-traces that
+    // land in the border read values that never flow back into the interior.
+    // Scale both density buffers back to the mass the step started with
+    fn conserve_density_mass(&mut self) {
+        if !self.config.conserve_mass {
+            return;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let b = self.boundary(BoundaryType::NONE);
+        for property in [&mut self.density, &mut self.initial_density] {
+            let mass = interior_sum(property, nw, nh);
+            if mass <= 0.0 {
+                continue;
+            }
+            let factor = self.density_mass / mass;
+            for j in 1..nh + 1 {
+                for i in 1..nw + 1 {
+                    property[pure_ix_fn(i, j, nw, nh)] *= factor;
+                }
+            }
+            set_bnd!(nw, nh, b, property);
+        }
+    }
+
+    fn record_momentum(&mut self) {
+        self.momentum = self.total_momentum();
+    }
+
+    // Spreads the momentum gained or lost since record_momentum evenly over
+    // the interior and takes it back out. Both velocity buffers are shifted
+    // since the next step continues from the initial ones
+    fn conserve_velocity_momentum(&mut self) {
+        if !self.config.conserve_momentum {
+            return;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let (momentum_x, momentum_y) = self.total_momentum();
+        let cells = nw as f32 * nh as f32;
+        let drift_x = (momentum_x - self.momentum.0) / cells;
+        let drift_y = (momentum_y - self.momentum.1) / cells;
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                self.velocity_x[index] -= drift_x;
+                self.initial_velocity_x[index] -= drift_x;
+                self.velocity_y[index] -= drift_y;
+                self.initial_velocity_y[index] -= drift_y;
+            }
+        }
+        let (x_bnd, y_bnd) = (
+            self.boundary(BoundaryType::VERTICAL),
+            self.boundary(BoundaryType::HORIZONTAL),
         );
+        set_bnd!(nw, nh, x_bnd, self.velocity_x);
+        set_bnd!(nw, nh, x_bnd, self.initial_velocity_x);
+        set_bnd!(nw, nh, y_bnd, self.velocity_y);
+        set_bnd!(nw, nh, y_bnd, self.initial_velocity_y);
+    }
 
-        std::mem::swap(&mut self.density, &mut self.initial_density);
+    fn dissipate_density(&mut self) {
+        if self.config.dissipation == 1.0 {
+            return;
+        }
+        let factor = self
+            .config
+            .dissipation
+            .powf(self.dt / DISSIPATION_REFERENCE_DT);
+        // The next step diffuses from initial_density, so both buffers decay
+        for index in 0..self.size {
+            self.density[index] *= factor;
+            self.initial_density[index] *= factor;
+        }
     }
 
-    fn velocity_step(&mut self) {
+    fn add_velocity_sources(&mut self) {
         add_source!(
             self.initial_velocity_x,
             self.velocity_x_source,
-            self.size as usize,
-            self.dt
+            self.size,
+            self.dt,
+            self.keep_sources()
         );
 
         add_source!(
             self.initial_velocity_y,
             self.velocity_y_source,
-            self.size as usize,
-            self.dt
+            self.size,
+            self.dt,
+            self.keep_sources()
         );
+    }
 
-        diffuse!(
-            self.config.nw,
-            self.config.nh,
-            BoundaryType::VERTICAL,
-            self.velocity_x,
-            self.initial_velocity_x,
-            self.config.diffusion,
-            self.dt
-        );
+    fn keep_sources(&self) -> bool {
+        self.config.source_application == SourceApplication::EveryStep
+    }
 
-        std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
+    // Dense regions push the flow away from themselves. In shallow water mode
+    // gravity adds to the coupling, pulling water down from the high areas
+    fn apply_density_force(&mut self) {
+        let coupling = self.density_force_coupling + self.shallow_water.unwrap_or(0.0);
+        if coupling == 0.0 {
+            return;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let scale = coupling * self.dt / (2.0 * self.config.dx);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let gradient_x = self.initial_density[pure_ix_fn(i + 1, j, nw, nh)]
+                    - self.initial_density[pure_ix_fn(i - 1, j, nw, nh)];
+                let gradient_y = self.initial_density[pure_ix_fn(i, j + 1, nw, nh)]
+                    - self.initial_density[pure_ix_fn(i, j - 1, nw, nh)];
+                self.initial_velocity_x[index] -= scale * gradient_x;
+                self.initial_velocity_y[index] -= scale * gradient_y;
+            }
+        }
+    }
 
-        diffuse!(
-            self.config.nw,
-            self.config.nh,
-            BoundaryType::HORIZONTAL,
-            self.velocity_y,
-            self.initial_velocity_y,
-            self.config.diffusion,
-            self.dt
+    // Turns the flow clockwise for a positive parameter, by f radians per
+    // unit of time, like on the northern half of a spinning planet. Each cell
+    // is rotated exactly, so the force only turns the flow and never speeds
+    // it up
+    fn apply_coriolis(&mut self) {
+        if self.coriolis == 0.0 {
+            return;
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let (sin, cos) = (self.coriolis * self.dt).sin_cos();
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let (velocity_x, velocity_y) = (
+                    self.initial_velocity_x[index],
+                    self.initial_velocity_y[index],
+                );
+                self.initial_velocity_x[index] = cos * velocity_x + sin * velocity_y;
+                self.initial_velocity_y[index] = cos * velocity_y - sin * velocity_x;
+            }
+        }
+        let (x_bnd, y_bnd) = (
+            self.boundary(BoundaryType::VERTICAL),
+            self.boundary(BoundaryType::HORIZONTAL),
         );
+        set_bnd!(nw, nh, x_bnd, self.initial_velocity_x);
+        set_bnd!(nw, nh, y_bnd, self.initial_velocity_y);
+    }
 
-        std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
+    fn diffuse_velocity_x(&mut self) {
+        self.diffuse_property(Property::VelocityX);
+    }
 
-        project!(
-            self.config.nw,
-            self.config.nh,
-            self.velocity_x,
-            self.velocity_y,
-            self.poisson_values,
-            self.divergence_values
-        );
+    fn diffuse_velocity_y(&mut self) {
+        self.diffuse_property(Property::VelocityY);
+    }
 
-        std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
-        std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
+    fn project_velocity(&mut self) {
+        // Shallow water waves are the divergence of the flow, so it is kept.
+        // The buffers are still swapped to leave them where the step expects
+        if self.shallow_water.is_some() {
+            std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
+            std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
+            return;
+        }
+        let periodic = self.config.boundary_mode == BoundaryMode::Periodic;
+        let axisymmetric = self.axisymmetric();
+        if !axisymmetric
+            && self.config.incompressibility == IncompressibilityMethod::ArtificialCompressibility
+        {
+            self.relax_pressure(periodic);
+            std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
+            std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
+            return;
+        }
+        // A custom solver takes precedence over the configured one
+        let conjugate_gradient = ConjugateGradient::default();
+        let solver: Option<&dyn Solver> = match (&self.solver, self.config.pressure_solver) {
+            (Some(solver), _) => Some(solver.as_ref()),
+            (None, SolverKind::ConjugateGradient) => Some(&conjugate_gradient),
+            (None, SolverKind::GaussSeidel) => None,
+        };
+        if axisymmetric {
+            axisymmetric::project(
+                self.config.nw,
+                self.config.nh,
+                &mut self.velocity_x,
+                &mut self.velocity_y,
+                &mut self.poisson_values,
+                &mut self.divergence_values,
+                self.config.omega,
+                GAUSS_SEIDEL_ITERATIONS as u16,
+            );
+        } else if let Some(solver) = solver {
+            let (scalar_bnd, x_bnd, y_bnd) = projection_boundaries(periodic);
+            pressure_divergence!(
+                self.config.nw,
+                self.config.nh,
+                self.velocity_x,
+                self.velocity_y,
+                self.poisson_values,
+                self.divergence_values,
+                scalar_bnd
+            );
 
-        advect!(
-            self.config.nw,
-            self.config.nh,
-            BoundaryType::VERTICAL,
-            self.velocity_x,
-            self.initial_velocity_x,
-            self.initial_velocity_x,
-            self.initial_velocity_y,
-            self.dt
-        );
+            self.poisson_values = solve_checked(
+                solver,
+                &LinearSystem::poisson(
+                    self.config.nw,
+                    self.config.nh,
+                    &self.divergence_values,
+                    periodic,
+                ),
+            );
+            set_bnd!(
+                self.config.nw,
+                self.config.nh,
+                scalar_bnd,
+                self.poisson_values
+            );
 
-        advect!(
-            self.config.nw,
-            self.config.nh,
-            BoundaryType::HORIZONTAL,
-            self.velocity_y,
-            self.initial_velocity_y,
-            self.initial_velocity_x,
-            self.initial_velocity_y,
-            self.dt
-        );
-        project!(
-            self.config.nw,
-            self.config.nh,
-            self.velocity_x,
-            self.velocity_y,
-            self.poisson_values,
-            self.divergence_values
-        );
+            subtract_pressure_gradient!(
+                self.config.nw,
+                self.config.nh,
+                self.velocity_x,
+                self.velocity_y,
+                self.poisson_values,
+                x_bnd,
+                y_bnd
+            );
+        } else {
+            project!(
+                self.config.nw,
+                self.config.nh,
+                self.velocity_x,
+                self.velocity_y,
+                self.poisson_values,
+                self.divergence_values,
+                self.config.omega,
+                periodic
+            );
+        }
+        if self.tracks_residuals() {
+            let (nw, nh) = (self.config.nw, self.config.nh);
+            let residual = if axisymmetric {
+                axisymmetric::pressure_residual(
+                    nw,
+                    nh,
+                    &self.poisson_values,
+                    &self.divergence_values,
+                )
+            } else {
+                poisson_residual!(nw, nh, self.poisson_values, self.divergence_values)
+            };
+            self.track_projection_residual(residual);
+        }
+
+        // The projection refreshes the velocity border without friction
+        if !periodic && self.config.wall_friction < 1.0 {
+            let (nw, nh) = (self.config.nw, self.config.nh);
+            let (x_bnd, y_bnd) = (
+                self.boundary(BoundaryType::VERTICAL),
+                self.boundary(BoundaryType::HORIZONTAL),
+            );
+            set_bnd!(nw, nh, x_bnd, self.velocity_x);
+            set_bnd!(nw, nh, y_bnd, self.velocity_y);
+        }
 
         std::mem::swap(&mut self.velocity_x, &mut self.initial_velocity_x);
         std::mem::swap(&mut self.velocity_y, &mut self.initial_velocity_y);
     }
 
-    // All public methods
+    // Artificial compressibility. The pressure grows where the flow diverges,
+    // dp/dt = -c^2 div(u), and its gradient pushes back, du/dt = -grad(p).
+    // A step projects twice, so each call advances both by half a step. The
+    // projection residual reports the largest divergence left
+    fn relax_pressure(&mut self, periodic: bool) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let (scalar_bnd, _, _) = projection_boundaries(periodic);
+        let (x_bnd, y_bnd) = (
+            self.boundary(BoundaryType::VERTICAL),
+            self.boundary(BoundaryType::HORIZONTAL),
+        );
+        let half_dt = 0.5 * self.dt;
+        let scale = 0.5 / self.config.dx;
+        let stiffness = self.config.sound_speed * self.config.sound_speed;
+        let mut max_divergence: f32 = 0.0;
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let divergence = scale
+                    * (self.velocity_x[pure_ix_fn(i + 1, j, nw, nh)]
+                        - self.velocity_x[pure_ix_fn(i - 1, j, nw, nh)]
+                        + self.velocity_y[pure_ix_fn(i, j + 1, nw, nh)]
+                        - self.velocity_y[pure_ix_fn(i, j - 1, nw, nh)]);
+                self.divergence_values[index] = divergence;
+                self.pressure[index] -= half_dt * stiffness * divergence;
+                max_divergence = max_divergence.max(divergence.abs());
+            }
+        }
+        set_bnd!(nw, nh, scalar_bnd, self.pressure);
 
-    pub fn clear(&mut self) {
-        self.velocity_x = self.empty_property.clone();
-        self.velocity_y = self.empty_property.clone();
-        self.initial_velocity_x = self.empty_property.clone();
-        self.initial_velocity_y = self.empty_property.clone();
-        self.velocity_x_source = self.empty_property.clone();
-        self.velocity_y_source = self.empty_property.clone();
-        self.density = self.empty_property.clone();
-        self.initial_density = self.empty_property.clone();
-        self.density_source = self.empty_property.clone();
-        self.poisson_values = self.empty_property.clone();
-        self.divergence_values = self.empty_property.clone();
-    }
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                self.velocity_x[index] -= half_dt
+                    * scale
+                    * (self.pressure[pure_ix_fn(i + 1, j, nw, nh)]
+                        - self.pressure[pure_ix_fn(i - 1, j, nw, nh)]);
+                self.velocity_y[index] -= half_dt
+                    * scale
+                    * (self.pressure[pure_ix_fn(i, j + 1, nw, nh)]
+                        - self.pressure[pure_ix_fn(i, j - 1, nw, nh)]);
+            }
+        }
+        set_bnd!(nw, nh, x_bnd, self.velocity_x);
+        set_bnd!(nw, nh, y_bnd, self.velocity_y);
 
-    pub fn add_density(&mut self, index: usize, value: f32) {
-        self.density_source[index] = value;
+        self.track_projection_residual(max_divergence);
     }
 
-    pub fn add_velocity(&mut self, index: usize, value_x: f32, value_y: f32) {
-        self.velocity_x_source[index] = value_x;
-        self.velocity_y_source[index] = value_y;
+    // The velocity the step continues from sits in the initial buffers at
+    // this point. The current ones are scratch until advection writes them,
+    // so they get a copy for the getters the hook reads through
+    fn run_mid_step_hook(&mut self) {
+        if let Some(mut hook) = self.mid_step_hook.take() {
+            self.velocity_x.copy_from_slice(&self.initial_velocity_x);
+            self.velocity_y.copy_from_slice(&self.initial_velocity_y);
+            hook(self);
+            if self.mid_step_hook.is_none() {
+                self.mid_step_hook = Some(hook);
+            }
+        }
     }
 
-    pub fn simulate(&mut self) {
-        self.velocity_step();
-        self.density_step();
+    // Both components are traced back along the velocity from before the
+    // advection, so the buffers are only swapped by the projection after it
+    fn advect_velocity(&mut self) {
+        self.advect_property(Property::VelocityX);
+        self.advect_property(Property::VelocityY);
     }
 
-    pub fn get_density_at_index(&self, index: usize) -> f32 {
-        self.density[index]
+    // The buffer a property is written into and the one the step continues
+    // from. Every property the step diffuses or advects has an entry here
+    fn property_buffers(&mut self, property: Property) -> (&mut PropertyType, &mut PropertyType) {
+        match property {
+            Property::Density => (&mut self.density, &mut self.initial_density),
+            Property::Age => (&mut self.age, &mut self.initial_age),
+            Property::VelocityX => (&mut self.velocity_x, &mut self.initial_velocity_x),
+            Property::VelocityY => (&mut self.velocity_y, &mut self.initial_velocity_y),
+        }
     }
 
-    pub fn ix(&self, x: u16, y: u16) -> u16 {
-        pure_ix_fn(x, y, self.config.nw, self.config.nh) as u16
-    }
+    // Diffuses a property from its initial buffer, swapping the result back
+    // into it after every substep
+    fn diffuse_property(&mut self, property: Property) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let b = self.boundary(property.boundary());
+        let (diffusion, dt) = (self.diffusion_coefficient(), self.diffusion_dt());
+        let (omega, weights) = (self.config.omega, self.config.diffusion_weights);
+        let diffusion_field = self.take_diffusion_field_coefficients();
+        let iterations = self.diffusion_iterations(diffusion_field.as_deref());
+        let incremental = self.config.incremental_solve;
+        let track_residuals = self.tracks_residuals();
+        let axisymmetric = self.axisymmetric();
+        let radial = matches!(property, Property::VelocityX);
+        let region = match property {
+            Property::Density => self.active_region.clone(),
+            _ => None,
+        };
+        let mut sweeps = 0;
+        // Taken out for the solve so the buffers can be borrowed alongside it
+        let solver = self.solver.take();
+        for _ in 0..self.config.diffusion_substeps {
+            let (current, initial) = self.property_buffers(property);
+            // Set by the incremental solve, whose last check is already the
+            // residual of its result
+            let mut checked_residual = None;
+            if axisymmetric {
+                axisymmetric::diffuse(
+                    nw,
+                    nh,
+                    b,
+                    current,
+                    initial,
+                    diffusion,
+                    dt,
+                    omega,
+                    weights,
+                    diffusion_field.as_deref(),
+                    iterations,
+                    radial,
+                );
+                sweeps += iterations as u32;
+            } else if let Some(solver) = &solver {
+                *current = solve_checked(
+                    solver.as_ref(),
+                    &LinearSystem::diffusion(nw, nh, b, initial, current, dt * diffusion, weights),
+                );
+            } else if incremental {
+                // One step of diffusion barely changes a slowly varying field,
+                // so the field itself is a close first guess. Sweeping stops
+                // once the residual is as small as the full solve aims for
+                current.clone_from(initial);
+                let tolerance = AUTO_ITERATIONS_REDUCTION
+                    * initial
+                        .iter()
+                        .fold(0.0, |max: f32, value| max.max(value.abs()));
+                // A residual costs about as much as a sweep, so it is only
+                // checked every few sweeps
+                let mut swept = 0;
+                while swept < iterations {
+                    let batch = cmp::min(INCREMENTAL_CHECK_SWEEPS, iterations - swept);
+                    diffuse!(
+                        nw,
+                        nh,
+                        b,
+                        current,
+                        initial,
+                        diffusion,
+                        dt,
+                        omega,
+                        weights,
+                        diffusion_field.as_deref(),
+                        batch
+                    );
+                    swept += batch;
+                    let error = diffuse_residual!(
+                        nw,
+                        nh,
+                        current,
+                        initial,
+                        diffusion,
+                        dt,
+                        weights,
+                        diffusion_field.as_deref()
+                    );
+                    sweeps += batch as u32 + 1;
+                    checked_residual = Some(error);
+                    if error <= tolerance {
+                        break;
+                    }
+                }
+            } else if let Some((columns, rows)) = region.clone() {
+                // Cells outside the region hold next to no density and keep it
+                copy_outside(current, initial, nw, nh, columns.clone(), rows.clone());
+                diffuse!(
+                    nw,
+                    nh,
+                    b,
+                    current,
+                    initial,
+                    diffusion,
+                    dt,
+                    omega,
+                    weights,
+                    diffusion_field.as_deref(),
+                    iterations,
+                    columns,
+                    rows
+                );
+                sweeps += iterations as u32;
+            } else {
+                diffuse!(
+                    nw,
+                    nh,
+                    b,
+                    current,
+                    initial,
+                    diffusion,
+                    dt,
+                    omega,
+                    weights,
+                    diffusion_field.as_deref(),
+                    iterations
+                );
+                sweeps += iterations as u32;
+            }
 
-    pub fn get_nw(&self) -> u16 {
-        self.config.nw
+            // The incremental solve's last check comes for free, a residual
+            // pass of its own only runs when the residuals are tracked
+            let residual = if let Some(residual) = checked_residual {
+                Some(residual)
+            } else if !track_residuals {
+                None
+            } else if axisymmetric {
+                sweeps += 1;
+                Some(axisymmetric::diffusion_residual(
+                    nw,
+                    nh,
+                    current,
+                    initial,
+                    diffusion,
+                    dt,
+                    weights,
+                    diffusion_field.as_deref(),
+                    radial,
+                ))
+            } else {
+                sweeps += 1;
+                Some(diffuse_residual!(
+                    nw,
+                    nh,
+                    current,
+                    initial,
+                    diffusion,
+                    dt,
+                    weights,
+                    diffusion_field.as_deref()
+                ))
+            };
+            std::mem::swap(current, initial);
+            if let Some(residual) = residual {
+                self.track_diffuse_residual(residual);
+            }
+        }
+        self.solver = solver;
+        if let Some(coefficients) = diffusion_field {
+            self.diffusion_field_cache =
+                Some((self.config.diffusion, self.config.dx, coefficients));
+        }
+        self.last_diffuse_sweeps += sweeps;
     }
 
-    pub fn get_nh(&self) -> u16 {
-        self.config.nh
+    // Traces a property back from its initial buffer into the current one.
+    // Density moves with the prescribed advection velocity when one is set and
+    // otherwise with the current velocity, and so does the age. The velocity
+    // moves with itself from before the advection
+    fn advect_property(&mut self, property: Property) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let b = self.boundary(property.boundary());
+        let dt = self.dt / self.config.dx;
+        let (mode, interpolation) = (self.config.sample_mode, self.config.interpolation);
+        let (density_velocity_x, density_velocity_y) = match &self.advection_velocity {
+            Some((velocity_x, velocity_y)) => (velocity_x, velocity_y),
+            None => (&self.velocity_x, &self.velocity_y),
+        };
+        let (current, initial, velocity_x, velocity_y) = match property {
+            Property::Density => (
+                &mut self.density,
+                &self.initial_density,
+                density_velocity_x,
+                density_velocity_y,
+            ),
+            Property::Age => (
+                &mut self.age,
+                &self.initial_age,
+                density_velocity_x,
+                density_velocity_y,
+            ),
+            Property::VelocityX => (
+                &mut self.velocity_x,
+                &self.initial_velocity_x,
+                &self.initial_velocity_x,
+                &self.initial_velocity_y,
+            ),
+            Property::VelocityY => (
+                &mut self.velocity_y,
+                &self.initial_velocity_y,
+                &self.initial_velocity_x,
+                &self.initial_velocity_y,
+            ),
+        };
+
+        let region = match property {
+            Property::Density => self.active_region.clone(),
+            _ => None,
+        };
+        // Tracing around solids takes precedence over the configured scheme,
+        // BFECC's correction would carry values through them again
+        let solids = (self.config.solid_advection == SolidAdvection::Clamp
+            && self.solids.contains(&true))
+        .then_some(&self.solids);
+        match (self.config.advection_scheme, region, solids) {
+            (_, region, Some(solids)) => {
+                // Cells outside an active region keep their value
+                let (columns, rows) = region.unwrap_or((1..nw + 1, 1..nh + 1));
+                copy_outside(current, initial, nw, nh, columns.clone(), rows.clone());
+                advect_around_solids(
+                    nw, nh, b, current, initial, velocity_x, velocity_y, dt, mode, solids, columns,
+                    rows,
+                );
+            }
+            (AdvectionScheme::SemiLagrangian, Some((columns, rows)), None) => {
+                // Cells outside the region hold next to no density and keep it
+                copy_outside(current, initial, nw, nh, columns.clone(), rows.clone());
+                advect!(
+                    nw,
+                    nh,
+                    b,
+                    current,
+                    initial,
+                    velocity_x,
+                    velocity_y,
+                    dt,
+                    mode,
+                    interpolation,
+                    columns,
+                    rows
+                );
+            }
+            (AdvectionScheme::SemiLagrangian, None, None) => {
+                advect!(
+                    nw,
+                    nh,
+                    b,
+                    current,
+                    initial,
+                    velocity_x,
+                    velocity_y,
+                    dt,
+                    mode,
+                    interpolation
+                );
+            }
+            (AdvectionScheme::Bfecc, _, None) => {
+                bfecc_advect!(
+                    nw,
+                    nh,
+                    b,
+                    current,
+                    initial,
+                    velocity_x,
+                    velocity_y,
+                    dt,
+                    mode,
+                    interpolation
+                );
+            }
+        }
     }
 
-    pub fn get_size(&self) -> u16 {
-        self.size
+    // Wrapping replaces the boundary of every property on periodic grids.
+    // Walls with friction hold back the velocity along them
+    fn boundary(&self, b: BoundaryType) -> BoundaryType {
+        let slip = self.config.wall_friction;
+        match (self.config.boundary_mode, b) {
+            (BoundaryMode::Periodic, _) => BoundaryType::PERIODIC,
+            (BoundaryMode::Walls, BoundaryType::VERTICAL) if slip < 1.0 => {
+                BoundaryType::VERTICAL_SLIP(slip)
+            }
+            (BoundaryMode::Walls, BoundaryType::HORIZONTAL) if slip < 1.0 => {
+                BoundaryType::HORIZONTAL_SLIP(slip)
+            }
+            (BoundaryMode::Walls, b) => b,
+        }
     }
 
-    pub fn set_dt(&mut self, dt: f32) {
-        self.dt = dt
+    fn axisymmetric(&self) -> bool {
+        self.axisymmetric && self.config.boundary_mode == BoundaryMode::Walls
     }
 
-    pub fn get_velocity_x(&self, index: usize) -> f32 {
-        self.velocity_x[index]
+    // Time covered by each diffusion substep
+    fn diffusion_dt(&self) -> f32 {
+        self.dt / self.config.diffusion_substeps as f32
     }
-    pub fn get_velocity_y(&self, index: usize) -> f32 {
-        self.velocity_y[index]
+
+    // Diffusion rate in cells, as the solver works in grid units
+    fn diffusion_coefficient(&self) -> f32 {
+        self.config.diffusion / (self.config.dx * self.config.dx)
     }
-    pub fn get_density_expensive(&self) -> PropertyType {
-        self.density.clone()
+
+    // diffusion_coefficient for every cell when a diffusion field is set.
+    // Cells without a value of their own follow the configured diffusion.
+    // Custom solvers only take a uniform system, so they ignore the field.
+    // Taken out of the cache while the diffusion and dx stay the same, the
+    // caller hands them back
+    fn take_diffusion_field_coefficients(&mut self) -> Option<PropertyType> {
+        if self.solver.is_some() {
+            return None;
+        }
+        let (diffusion, dx) = (self.config.diffusion, self.config.dx);
+        match self.diffusion_field_cache.take() {
+            Some((cached_diffusion, cached_dx, coefficients))
+                if cached_diffusion == diffusion && cached_dx == dx =>
+            {
+                Some(coefficients)
+            }
+            _ => {
+                let scale = 1.0 / (dx * dx);
+                self.diffusion_field.as_ref().map(|field| {
+                    field
+                        .iter()
+                        .map(|value| value.unwrap_or(diffusion) * scale)
+                        .collect()
+                })
+            }
+        }
     }
 
-    pub fn get_velocity_x_expensive(&self) -> PropertyType {
-        self.velocity_x.clone()
+    // Sweeps of the built in diffusion solve. Automatic iterations follow the
+    // fastest diffusing cell, so every cell gets enough of them
+    fn diffusion_iterations(&self, diffusion_field: Option<&[f32]>) -> u16 {
+        match self.config.auto_iterations {
+            None => GAUSS_SEIDEL_ITERATIONS as u16,
+            Some(max) => {
+                let coefficient = diffusion_field.map_or(self.diffusion_coefficient(), |field| {
+                    field.iter().cloned().fold(0.0, f32::max)
+                });
+                let (wx, wy) = self.config.diffusion_weights;
+                let k = self.diffusion_dt() * coefficient * (wx + wy) / 2.0;
+                auto_iterations(k, max)
+            }
+        }
     }
 
-    pub fn get_velocity_y_expensive(&self) -> PropertyType {
-        self.velocity_y.clone()
+    // Steps continue from the initial buffers, so those hold the state
+    fn measure_step_delta(&mut self) {
+        if !self.track_step_delta {
+            self.last_step_delta = 0.0;
+            return;
+        }
+        let mut delta: f32 = 0.0;
+        for index in 0..self.size {
+            delta = delta
+                .max((self.initial_velocity_x[index] - self.previous_velocity_x[index]).abs())
+                .max((self.initial_velocity_y[index] - self.previous_velocity_y[index]).abs())
+                .max((self.initial_density[index] - self.previous_density[index]).abs());
+        }
+        self.last_step_delta = delta;
+    }
+
+    fn enforce_symmetry(&mut self) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let symmetry = self.config.symmetry;
+        let fields = [
+            (&mut self.density, 1.0, 1.0),
+            (&mut self.initial_density, 1.0, 1.0),
+            (&mut self.velocity_x, -1.0, 1.0),
+            (&mut self.initial_velocity_x, -1.0, 1.0),
+            (&mut self.velocity_y, 1.0, -1.0),
+            (&mut self.initial_velocity_y, 1.0, -1.0),
+        ];
+        for (property, sign_x, sign_y) in fields {
+            if let Symmetry::Horizontal | Symmetry::Both = symmetry {
+                mirror_horizontally(property, sign_x, nw, nh);
+            }
+            if let Symmetry::Vertical | Symmetry::Both = symmetry {
+                mirror_vertically(property, sign_y, nw, nh);
+            }
+        }
+    }
+
+    fn apply_diffusion_schedule(&mut self) {
+        if let Some(diffusion) = self.diffusion_schedule.value_at(self.steps) {
+            self.config.set_diffusion(diffusion);
+        }
+    }
+
+    // The last swaps leave the finished frame in the initial buffers and an
+    // intermediate one in the current buffers the getters read, the density
+    // before advection and the velocity before the second projection. Copies
+    // the frame over so both hold it once a step is complete
+    fn finalize_step(&mut self) {
+        self.density.copy_from_slice(&self.initial_density);
+        self.velocity_x.copy_from_slice(&self.initial_velocity_x);
+        self.velocity_y.copy_from_slice(&self.initial_velocity_y);
+        if self.track_age {
+            self.age.copy_from_slice(&self.initial_age);
+        }
+    }
+
+    // Whether the current buffers hold the same frame as the initial ones,
+    // bit for bit so NaNs compare equal
+    fn is_finalized(&self) -> bool {
+        let same = |current: &[f32], initial: &[f32]| {
+            current
+                .iter()
+                .zip(initial)
+                .all(|(current, initial)| current.to_bits() == initial.to_bits())
+        };
+        same(&self.density, &self.initial_density)
+            && same(&self.velocity_x, &self.initial_velocity_x)
+            && same(&self.velocity_y, &self.initial_velocity_y)
+    }
+
+    fn count_step(&mut self) {
+        self.steps += 1;
+        self.elapsed_time += self.dt as f64;
+    }
+
+    // The residual passes after the solves cost a sweep of the grid each, so
+    // they only run once a tolerance is set
+    fn tracks_residuals(&self) -> bool {
+        self.residual_tolerance.is_finite()
+    }
+
+    fn track_diffuse_residual(&mut self, residual: f32) {
+        self.last_diffuse_residual = self.last_diffuse_residual.max(residual);
+    }
+
+    fn track_projection_residual(&mut self, residual: f32) {
+        self.last_projection_residual = self.last_projection_residual.max(residual);
+    }
+
+    // Solid cells act as walls the way set_bnd treats the border. Cells next
+    // to fluid mirror their fluid neighbours' velocity so the flow at the wall
+    // cancels out, and copy their density. Cells buried inside a solid are
+    // emptied
+    fn apply_solids(&mut self) {
+        for (index, neighbours) in &self.solid_neighbours {
+            let count = cmp::max(neighbours.len(), 1) as f32;
+            let mean = |property: &PropertyType| {
+                neighbours.iter().map(|&n| property[n]).sum::<f32>() / count
+            };
+            let velocity_x = -mean(&self.velocity_x);
+            let velocity_y = -mean(&self.velocity_y);
+            let initial_velocity_x = -mean(&self.initial_velocity_x);
+            let initial_velocity_y = -mean(&self.initial_velocity_y);
+            let density = mean(&self.density);
+            let initial_density = mean(&self.initial_density);
+
+            self.velocity_x[*index] = velocity_x;
+            self.velocity_y[*index] = velocity_y;
+            self.initial_velocity_x[*index] = initial_velocity_x;
+            self.initial_velocity_y[*index] = initial_velocity_y;
+            self.density[*index] = density;
+            self.initial_density[*index] = initial_density;
+        }
+    }
+
+    fn solids_changed(&mut self) {
+        self.update_solid_neighbours();
+        self.update_sponge_distances();
+    }
+
+    fn update_sponge_distances(&mut self) {
+        let width = self.sponge.0;
+        self.sponge_distances = if width == 0 {
+            Vec::new()
+        } else {
+            wall_distances(&self.solids, self.config.nw, self.config.nh, width)
+        };
+    }
+
+    fn update_solid_neighbours(&mut self) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        self.solid_neighbours.clear();
+        for j in 0..nh + 2 {
+            for i in 0..nw + 2 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                if !self.solids[index] {
+                    continue;
+                }
+
+                let mut neighbours = Vec::with_capacity(4);
+                if i > 0 {
+                    neighbours.push(pure_ix_fn(i - 1, j, nw, nh));
+                }
+                if i < nw + 1 {
+                    neighbours.push(pure_ix_fn(i + 1, j, nw, nh));
+                }
+                if j > 0 {
+                    neighbours.push(pure_ix_fn(i, j - 1, nw, nh));
+                }
+                if j < nh + 1 {
+                    neighbours.push(pure_ix_fn(i, j + 1, nw, nh));
+                }
+                neighbours.retain(|&neighbour| !self.solids[neighbour]);
+                self.solid_neighbours.push((index, neighbours));
+            }
+        }
+    }
+
+    fn interior_array(&self, property: &[f32]) -> Float64Array {
+        let values: Vec<f64> = self
+            .interior_values(property)
+            .into_iter()
+            .map(f64::from)
+            .collect();
+        Float64Array::from(&values[..])
+    }
+
+    // Copies the interior cells of a property row by row, without the border
+    fn interior_values(&self, property: &[f32]) -> Vec<f32> {
+        let mut values = Vec::with_capacity(self.config.nw as usize * self.config.nh as usize);
+        for j in 1..self.config.nh + 1 {
+            for i in 1..self.config.nw + 1 {
+                values.push(property[pure_ix_fn(i, j, self.config.nw, self.config.nh)]);
+            }
+        }
+        values
+    }
+
+    // Inverse of interior_values, refreshing the border afterwards
+    fn set_interior_values(
+        &mut self,
+        values: &[f32],
+        b: BoundaryType,
+        property: fn(&mut Fluid) -> &mut PropertyType,
+    ) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        assert_eq!(
+            values.len(),
+            nw as usize * nh as usize,
+            "grid must have nw * nh values"
+        );
+        let property = property(self);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                property[pure_ix_fn(i, j, nw, nh)] =
+                    values[(j as usize - 1) * nw as usize + i as usize - 1];
+            }
+        }
+        set_bnd!(nw, nh, b, property);
+    }
+
+    // Vorticity of interior cell (x, y), dvy/dx - dvx/dy by central
+    // differences. Positive for counterclockwise rotation
+    fn curl(&self, x: u16, y: u16) -> f32 {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        (self.velocity_y[pure_ix_fn(x + 1, y, nw, nh)]
+            - self.velocity_y[pure_ix_fn(x - 1, y, nw, nh)]
+            - self.velocity_x[pure_ix_fn(x, y + 1, nw, nh)]
+            + self.velocity_x[pure_ix_fn(x, y - 1, nw, nh)])
+            / (2.0 * self.config.dx)
+    }
+
+    fn sample_velocity(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            sample_property(&self.velocity_x, x, y, self.config.nw, self.config.nh),
+            sample_property(&self.velocity_y, x, y, self.config.nw, self.config.nh),
+        )
+    }
+
+    // All public methods
+
+    // Empties every buffer and sets the elapsed time back to 0. The step
+    // counter keeps running, use reset to start it over as well
+    pub fn clear(&mut self) {
+        self.elapsed_time = 0.0;
+        self.velocity_x = self.empty_property.clone();
+        self.velocity_y = self.empty_property.clone();
+        self.initial_velocity_x = self.empty_property.clone();
+        self.initial_velocity_y = self.empty_property.clone();
+        self.velocity_x_source = self.empty_property.clone();
+        self.velocity_y_source = self.empty_property.clone();
+        self.density = self.empty_property.clone();
+        self.initial_density = self.empty_property.clone();
+        self.density_source = self.empty_property.clone();
+        self.poisson_values = self.empty_property.clone();
+        self.divergence_values = self.empty_property.clone();
+        self.pressure = self.empty_property.clone();
+        if self.track_age {
+            self.age = self.empty_property.clone();
+            self.initial_age = self.empty_property.clone();
+        }
+        self.smooth_density.clear();
+    }
+
+    // Drops the pending density and velocity sources. Stops the emitters of
+    // SourceApplication::EveryStep, which add += stamps like add_vortex to
+    // what is already there
+    pub fn clear_sources(&mut self) {
+        self.velocity_x_source = self.empty_property.clone();
+        self.velocity_y_source = self.empty_property.clone();
+        self.density_source = self.empty_property.clone();
+    }
+
+    // clear and reset_steps together, for starting a fresh run
+    pub fn reset(&mut self) {
+        self.clear();
+        self.reset_steps();
+    }
+
+    // Number of steps completed by simulate, simulate_steps or step_partial
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+
+    // Simulated time so far, the dt of every completed step added up. With
+    // auto substeps each substep adds its share of the frame
+    pub fn get_elapsed_time(&self) -> f64 {
+        self.elapsed_time
+    }
+
+    pub fn reset_steps(&mut self) {
+        self.steps = 0;
+    }
+
+    // Keeps the age of the density, the time since it was injected, for
+    // colouring smoke by freshness. Cells start at age 0 and are set back to
+    // it wherever a density source adds to them. Disabling frees the buffers
+    pub fn set_track_age(&mut self, enabled: bool) {
+        if enabled && !self.track_age {
+            self.age = self.empty_property.clone();
+            self.initial_age = self.empty_property.clone();
+        } else if !enabled {
+            self.age = Vec::new();
+            self.initial_age = Vec::new();
+        }
+        self.track_age = enabled;
+    }
+
+    // Age of the density in a cell as of the end of the last step. Cells with
+    // less than AGE_MIN_DENSITY of density hold no smoke to have an age and
+    // read 0, as does every cell while age tracking is off
+    pub fn get_age_at_index(&self, index: usize) -> f32 {
+        if !self.track_age || self.initial_density[index] < AGE_MIN_DENSITY {
+            return 0.0;
+        }
+        self.initial_age[index]
+    }
+
+    // Out of range indices are ignored, the return value says whether the
+    // source was set. Same for add_velocity
+    pub fn add_density(&mut self, index: usize, value: f32) -> bool {
+        if index >= self.size {
+            return false;
+        }
+        self.density_source[index] = value;
+        true
+    }
+
+    // Eases the density of a cell towards target by rate per unit of time over
+    // the following steps instead of adding it all at once. A new call for the
+    // same cell replaces the previous target. Out of range indices are
+    // ignored like in add_density
+    pub fn add_density_smooth(&mut self, index: usize, target: f32, rate: f32) -> bool {
+        if index >= self.size {
+            return false;
+        }
+        self.smooth_density
+            .retain(|&(pending, _, _)| pending != index);
+        if rate > 0.0 {
+            self.smooth_density.push((index, target, rate));
+        }
+        true
+    }
+
+    pub fn add_velocity(&mut self, index: usize, value_x: f32, value_y: f32) -> bool {
+        if index >= self.size {
+            return false;
+        }
+        self.velocity_x_source[index] = value_x;
+        self.velocity_y_source[index] = value_y;
+        true
+    }
+
+    // add_velocity from an angle in radians, counterclockwise from the x
+    // axis, and a speed. Inverse of get_velocity_polar_at_index
+    pub fn add_velocity_polar(&mut self, index: usize, angle: f32, speed: f32) -> bool {
+        self.add_velocity(index, speed * angle.cos(), speed * angle.sin())
+    }
+
+    // Sets the velocity of every cell between two grid points, so fast drags
+    // leave a continuous stroke instead of isolated points
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_velocity_line(
+        &mut self,
+        x0: u16,
+        y0: u16,
+        x1: u16,
+        y1: u16,
+        value_x: f32,
+        value_y: f32,
+        strength: f32,
+    ) {
+        for index in line_cells(x0, y0, x1, y1, self.config.nw, self.config.nh) {
+            self.add_velocity(index, value_x * strength, value_y * strength);
+        }
+    }
+
+    pub fn add_density_line(&mut self, x0: u16, y0: u16, x1: u16, y1: u16, value: f32) {
+        for index in line_cells(x0, y0, x1, y1, self.config.nw, self.config.nh) {
+            self.add_density(index, value);
+        }
+    }
+
+    // Stamps a grayscale image, e.g. text or a logo, as a density source. The
+    // stencil is sw * sh bytes row by row, and its first pixel lands on cell
+    // (ox, oy). Each cell gets value * pixel / 255 added to its source, and
+    // pixels falling outside the interior are dropped
+    #[allow(clippy::too_many_arguments)]
+    pub fn add_density_stencil(
+        &mut self,
+        stencil: &[u8],
+        sw: u16,
+        sh: u16,
+        ox: u16,
+        oy: u16,
+        value: f32,
+    ) {
+        assert_eq!(
+            stencil.len(),
+            sw as usize * sh as usize,
+            "stencil must have sw * sh pixels"
+        );
+        let (nw, nh) = (self.config.nw as u32, self.config.nh as u32);
+        for sy in 0..sh as u32 {
+            for sx in 0..sw as u32 {
+                let (x, y) = (ox as u32 + sx, oy as u32 + sy);
+                if x < 1 || x > nw || y < 1 || y > nh {
+                    continue;
+                }
+                let index = self.ix(x as u16, y as u16);
+                let pixel = stencil[(sy * sw as u32 + sx) as usize];
+                self.density_source[index] += value * pixel as f32 / 255.0;
+            }
+        }
+    }
+
+    // Adds a swirl around (cx, cy). The velocity is tangential to the center
+    // and fades out towards the radius, so the added field is divergence free
+    pub fn add_vortex(&mut self, cx: u16, cy: u16, radius: u16, strength: f32) {
+        for (index, r, direction_x, direction_y) in self.radial_cells(cx, cy, radius) {
+            if r == 0.0 {
+                continue;
+            }
+            let magnitude = strength * (1.0 - r / radius as f32);
+            self.velocity_x_source[index] += -direction_y * magnitude;
+            self.velocity_y_source[index] += direction_x * magnitude;
+        }
+    }
+
+    // Pulls fluid towards (cx, cy) and drains density there, the opposite of
+    // an emitter. Both fade out over SINK_RADIUS cells, and the center cell
+    // loses up to strength * dt of density per call
+    pub fn add_sink(&mut self, cx: u16, cy: u16, strength: f32) {
+        let radius = SINK_RADIUS;
+        for (index, r, direction_x, direction_y) in self.radial_cells(cx, cy, radius) {
+            let falloff = 1.0 - r / (radius as f32 + 1.0);
+            self.velocity_x_source[index] -= direction_x * strength * falloff;
+            self.velocity_y_source[index] -= direction_y * strength * falloff;
+
+            let drained = strength * falloff * self.dt;
+            self.density[index] = (self.density[index] - drained).max(0.0);
+            self.initial_density[index] = (self.initial_density[index] - drained).max(0.0);
+        }
+    }
+
+    // A burst pushing fluid away from (cx, cy), the outward counterpart of
+    // add_vortex, with a puff of smoke over the inner half of the radius.
+    // Both fade out towards the radius the way add_sink's do. The puff holds
+    // strength.abs() of density at the center, so a negative strength pulls
+    // fluid in but still adds smoke rather than taking it away. Stamped onto
+    // the sources, so it lasts one step unless SourceApplication::EveryStep
+    // keeps it going
+    pub fn explode(&mut self, cx: u16, cy: u16, radius: u16, strength: f32) {
+        for (index, r, direction_x, direction_y) in self.radial_cells(cx, cy, radius) {
+            let falloff = 1.0 - r / (radius as f32 + 1.0);
+            self.velocity_x_source[index] += direction_x * strength * falloff;
+            self.velocity_y_source[index] += direction_y * strength * falloff;
+            if 2.0 * r <= radius as f32 {
+                self.density_source[index] += strength.abs() * falloff;
+            }
+        }
+    }
+
+    // A continuous jet of smoke blowing in from the middle of a side, width
+    // cells wide. Sets density and velocity sources of speed pointing into
+    // the grid on a nozzle as deep as it is wide, since the projection would
+    // cancel a push on the cells along the wall alone, and switches sources
+    // to SourceApplication::EveryStep so they keep blowing until
+    // clear_sources
+    pub fn preset_jet(&mut self, side: Side, speed: f32, width: u16) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let (length, depth) = match side {
+            Side::Left | Side::Right => (nh, nw),
+            Side::Bottom | Side::Top => (nw, nh),
+        };
+        let width = cmp::min(width, length);
+        let start = (length - width) / 2 + 1;
+        for along in start..start + width {
+            for into in 1..cmp::min(width, depth) + 1 {
+                let (x, y, value_x, value_y) = match side {
+                    Side::Left => (into, along, speed, 0.0),
+                    Side::Right => (nw + 1 - into, along, -speed, 0.0),
+                    Side::Bottom => (along, into, 0.0, speed),
+                    Side::Top => (along, nh + 1 - into, 0.0, -speed),
+                };
+                let index = self.ix(x, y);
+                self.add_velocity(index, value_x, value_y);
+                self.add_density(index, PRESET_DENSITY);
+            }
+        }
+        self.config.source_application = SourceApplication::EveryStep;
+    }
+
+    // Two counter-rotating vortices side by side in the bottom part of the
+    // grid, with smoke in their cores. The swirl of each reaches past the
+    // core of the other and pushes it along, so the pair travels up as one.
+    // Writes the velocity and density straight into the state, on top of
+    // what is there
+    pub fn preset_vortex_pair(&mut self) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let core = cmp::max(cmp::min(nw, nh) / 8, 1);
+        let radius = 3 * core;
+        let strength = PRESET_VORTEX_CFL * self.config.dx / self.dt;
+        let cy = cmp::max(nh / 3, 1);
+        let centers = [
+            (nw / 2 - cmp::min(core, nw / 2), strength),
+            (nw / 2 + core, -strength),
+        ];
+        for (cx, strength) in centers {
+            for (index, dx, dy) in disc_cells(cx, cy, radius, nw, nh) {
+                let r = (dx * dx + dy * dy).sqrt();
+                if r > 0.0 {
+                    let magnitude = strength * (1.0 - r / radius as f32);
+                    self.velocity_x[index] -= dy / r * magnitude;
+                    self.initial_velocity_x[index] -= dy / r * magnitude;
+                    self.velocity_y[index] += dx / r * magnitude;
+                    self.initial_velocity_y[index] += dx / r * magnitude;
+                }
+                if r <= core as f32 {
+                    self.density[index] += PRESET_DENSITY;
+                    self.initial_density[index] += PRESET_DENSITY;
+                }
+            }
+        }
+    }
+
+    // Folds the pending density and velocity sources and one dt of the smooth
+    // density targets into the state, without diffusing or advecting. Lets a
+    // custom pipeline built from the step_* stages commit sources on its own
+    pub fn apply_sources(&mut self) {
+        self.add_velocity_sources();
+        self.apply_smooth_density();
+        self.add_density_sources();
+    }
+
+    pub fn simulate(&mut self) {
+        self.simulate_frame(None);
+    }
+
+    // simulate, also measuring how long the diffusion, advection and
+    // projection stages take. Timing every stage has a small cost of its own,
+    // so use simulate when the numbers are not needed
+    pub fn simulate_timed(&mut self) -> StageTimings {
+        let mut timings = StageTimings::default();
+        self.simulate_frame(Some(&mut timings));
+        timings
+    }
+
+    fn simulate_frame(&mut self, mut timings: Option<&mut StageTimings>) {
+        // One snapshot per frame, so rewind undoes all of its substeps
+        self.record_history();
+        let substeps = self.auto_substeps();
+        if substeps == 1 {
+            self.simulate_step(timings);
+            return;
+        }
+
+        let (dt, source_application) = (self.dt, self.config.source_application);
+        self.dt = dt / substeps as f32;
+        // Sources are scaled by dt, so ones added for this frame are kept
+        // until the last substep to land in full
+        self.config.source_application = SourceApplication::EveryStep;
+        for substep in 1..=substeps {
+            if substep == substeps {
+                self.config.source_application = source_application;
+            }
+            self.simulate_step(timings.as_deref_mut());
+        }
+        self.dt = dt;
+    }
+
+    fn simulate_step(&mut self, mut timings: Option<&mut StageTimings>) {
+        self.start_step();
+        for &(stage, _, kind) in STEP_STAGES.iter() {
+            match timings.as_deref_mut() {
+                Some(timings) => {
+                    let stopwatch = Stopwatch::start();
+                    stage(self);
+                    timings.add(kind, stopwatch.elapsed_ms());
+                }
+                None => stage(self),
+            }
+        }
+        self.stage_cursor = STEP_STAGES.len();
+        debug_assert!(
+            self.is_finalized(),
+            "a step must leave the current frame in the buffers the getters read"
+        );
+    }
+
+    // Lets simulate split a frame into as many equal substeps as it takes to
+    // keep the CFL number of each below 1, so dt no longer needs tuning to the
+    // flow. Each substep is a full step and counts towards get_steps, but the
+    // history keeps one snapshot per frame. Frames needing more than
+    // max_substeps run max_substeps and can move fluid more than a cell per
+    // substep; a warning is logged when that starts happening. Disabling goes
+    // back to one step per frame
+    pub fn set_auto_substep(&mut self, enabled: bool, max_substeps: u16) {
+        if !enabled {
+            self.auto_substep = None;
+        } else if max_substeps >= 1 {
+            self.auto_substep = Some(max_substeps);
+        }
+    }
+
+    // Substeps the next simulate will run
+    fn auto_substeps(&mut self) -> u16 {
+        let max_substeps = match self.auto_substep {
+            Some(max_substeps) => max_substeps,
+            None => return 1,
+        };
+        let cfl = self.cfl_number();
+        let needed = if cfl.is_finite() {
+            cfl.floor() + 1.0
+        } else {
+            f32::INFINITY
+        };
+        let capped = needed > max_substeps as f32;
+        if capped && !self.auto_substep_capped {
+            #[cfg(target_arch = "wasm32")]
+            log_f32(
+                cfl,
+                "CFL number needs more substeps than set_auto_substep allows",
+            );
+        }
+        self.auto_substep_capped = capped;
+        needed.min(max_substeps as f32) as u16
+    }
+
+    // Individual density stages of simulate, for driving or testing the
+    // pipeline stage by stage. step_diffuse_density also applies the pending
+    // density sources
+    pub fn step_diffuse_density(&mut self) {
+        self.diffuse_density()
+    }
+
+    pub fn step_advect_density(&mut self) {
+        self.advect_density()
+    }
+
+    // Largest change of any velocity or density value over the last step.
+    // 0 unless set_step_delta_tracking is on
+    pub fn last_step_delta(&self) -> f32 {
+        self.last_step_delta
+    }
+
+    // Measuring last_step_delta copies the velocity and density at the start
+    // of every step, so it is off until asked for. simulate_until_steady
+    // turns it on while it runs
+    pub fn set_step_delta_tracking(&mut self, enabled: bool) {
+        self.track_step_delta = enabled;
+    }
+
+    // Runs simulate steps times
+    pub fn simulate_steps(&mut self, steps: u32) {
+        for _ in 0..steps {
+            self.simulate();
+        }
+    }
+
+    // simulate_steps_with for JavaScript. The callback gets the index of the
+    // step just run and returning false, or throwing, stops the run
+    pub fn simulate_steps_with_callback(&mut self, steps: u32, callback: &Function) -> u32 {
+        self.simulate_steps_with(steps, |step| {
+            callback
+                .call1(&JsValue::NULL, &JsValue::from(step))
+                .map(|keep_going| keep_going.as_bool() != Some(false))
+                .unwrap_or(false)
+        })
+    }
+
+    // set_velocity_from_fn for JavaScript. The callback gets x and y and
+    // returns [velocity_x, velocity_y]. Cells where it throws or returns
+    // anything else are left at rest
+    pub fn set_velocity_from_callback(&mut self, callback: &Function) {
+        self.set_velocity_from_fn(|x, y| {
+            let velocity = callback
+                .call2(&JsValue::NULL, &JsValue::from(x), &JsValue::from(y))
+                .ok()
+                .and_then(|value| value.dyn_into::<Array>().ok());
+            match velocity.map(|velocity| (velocity.get(0).as_f64(), velocity.get(1).as_f64())) {
+                Some((Some(velocity_x), Some(velocity_y))) => {
+                    (velocity_x as f32, velocity_y as f32)
+                }
+                _ => (0.0, 0.0),
+            }
+        })
+    }
+
+    // Steps until the largest change over a step drops below tol, or
+    // max_steps have run. Returns the number of steps taken
+    pub fn simulate_until_steady(&mut self, tol: f32, max_steps: u32) -> u32 {
+        let track_step_delta = self.track_step_delta;
+        self.track_step_delta = true;
+        let mut steps = max_steps;
+        for step in 1..=max_steps {
+            self.simulate();
+            if self.last_step_delta < tol {
+                steps = step;
+                break;
+            }
+        }
+        self.track_step_delta = track_step_delta;
+        steps
+    }
+
+    // Starts a step that is then run piece by piece with step_partial, so a
+    // heavy step can be spread over several frames. Calling simulate in the
+    // middle of a partial step abandons it
+    pub fn begin_step(&mut self) {
+        self.record_history();
+        self.start_step();
+    }
+
+    fn record_history(&mut self) {
+        let mut history = std::mem::take(&mut self.history);
+        history.record(self);
+        self.history = history;
+    }
+
+    fn start_step(&mut self) {
+        if self.track_step_delta {
+            self.previous_velocity_x
+                .clone_from(&self.initial_velocity_x);
+            self.previous_velocity_y
+                .clone_from(&self.initial_velocity_y);
+            self.previous_density.clone_from(&self.initial_density);
+        }
+        self.last_diffuse_residual = 0.0;
+        self.last_diffuse_sweeps = 0;
+        self.last_projection_residual = 0.0;
+        self.stage_cursor = 0;
+    }
+
+    // Keeps the state from the start of the last capacity frames so they can
+    // be undone with rewind. A frame is a simulate call, however many auto
+    // substeps it runs, or a step started with begin_step. Each snapshot
+    // holds seven buffers, so this costs capacity * 7 * get_size() * 4 bytes,
+    // two buffers more with age tracking on. 0, the default, turns it off
+    // and frees the snapshots
+    pub fn set_history_capacity(&mut self, capacity: usize) {
+        self.history.set_capacity(capacity)
+    }
+
+    // Steps that can currently be rewound
+    pub fn history_len(&self) -> usize {
+        self.history.len()
+    }
+
+    // Goes back steps frames, see set_history_capacity, to the density,
+    // velocity and step count from the start of that frame. Returns false and
+    // changes nothing when the history does not reach that far
+    pub fn rewind(&mut self, steps: usize) -> bool {
+        let mut history = std::mem::take(&mut self.history);
+        let rewound = history.rewind(self, steps);
+        self.history = history;
+        rewound
+    }
+
+    // Runs whole stages of the current step while they fit in a budget of
+    // row_budget row sweeps, and returns true once the step is complete. A
+    // stage costs its sweep count times nh rows. Stages are never split into
+    // row bands, so the budget is a target rather than a limit: every call
+    // runs at least one whole stage, and a projection alone is
+    // PROJECT_SWEEPS full grid sweeps however small the budget. The finished
+    // step is identical to a single simulate
+    pub fn step_partial(&mut self, row_budget: u16) -> bool {
+        let mut budget = row_budget as u32;
+        let mut ran_stage = false;
+        while self.stage_cursor < STEP_STAGES.len() {
+            let (stage, sweeps, _) = STEP_STAGES[self.stage_cursor];
+            let cost = sweeps * self.config.nh as u32;
+            if ran_stage && cost > budget {
+                break;
+            }
+            stage(self);
+            self.stage_cursor += 1;
+            budget = budget.saturating_sub(cost);
+            ran_stage = true;
+        }
+        self.stage_cursor == STEP_STAGES.len()
+    }
+
+    pub fn get_density_at_index(&self, index: usize) -> f32 {
+        self.density[index]
+    }
+
+    pub fn ix(&self, x: u16, y: u16) -> usize {
+        pure_ix_fn(x, y, self.config.nw, self.config.nh)
+    }
+
+    pub fn get_nw(&self) -> u16 {
+        self.config.nw
+    }
+
+    pub fn get_nh(&self) -> u16 {
+        self.config.nh
+    }
+
+    // Number of cells in a row of the buffers, including the border
+    pub fn get_stride(&self) -> u16 {
+        self.config.nw + 2 * BORDER
+    }
+
+    pub fn get_border(&self) -> u16 {
+        BORDER
+    }
+
+    pub fn get_size(&self) -> usize {
+        self.size
+    }
+
+    pub fn set_dt(&mut self, dt: f32) {
+        self.dt = dt
+    }
+
+    pub fn get_velocity_x(&self, index: usize) -> f32 {
+        self.velocity_x[index]
+    }
+    pub fn get_velocity_y(&self, index: usize) -> f32 {
+        self.velocity_y[index]
+    }
+    pub fn get_density_expensive(&self) -> PropertyType {
+        self.density.clone()
+    }
+
+    pub fn get_velocity_x_expensive(&self) -> PropertyType {
+        self.velocity_x.clone()
+    }
+
+    pub fn get_velocity_y_expensive(&self) -> PropertyType {
+        self.velocity_y.clone()
+    }
+
+    // Velocity in staggered (MAC) form for interop with other CFD tools. See
+    // cells_to_faces_x and cells_to_faces_y for the layout
+    pub fn get_velocity_x_faces(&self) -> PropertyType {
+        cells_to_faces_x(&self.velocity_x, self.config.nw, self.config.nh)
+    }
+
+    pub fn get_velocity_y_faces(&self) -> PropertyType {
+        cells_to_faces_y(&self.velocity_y, self.config.nw, self.config.nh)
+    }
+
+    // Replaces the velocity with one given in staggered (MAC) form, averaging
+    // the faces back onto the cell centers the solver works with
+    pub fn set_velocity_faces(&mut self, faces_x: PropertyType, faces_y: PropertyType) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        assert_eq!(
+            faces_x.len(),
+            (nw as usize + 1) * nh as usize,
+            "faces_x must have (nw + 1) * nh values"
+        );
+        assert_eq!(
+            faces_y.len(),
+            nw as usize * (nh as usize + 1),
+            "faces_y must have nw * (nh + 1) values"
+        );
+        faces_x_to_cells(&faces_x, &mut self.velocity_x, nw, nh);
+        faces_y_to_cells(&faces_y, &mut self.velocity_y, nw, nh);
+        let (x_bnd, y_bnd) = (
+            self.boundary(BoundaryType::VERTICAL),
+            self.boundary(BoundaryType::HORIZONTAL),
+        );
+        set_bnd!(nw, nh, x_bnd, self.velocity_x);
+        set_bnd!(nw, nh, y_bnd, self.velocity_y);
+
+        // The next step continues from the initial buffers
+        self.initial_velocity_x.clone_from(&self.velocity_x);
+        self.initial_velocity_y.clone_from(&self.velocity_y);
+    }
+
+    // The interior density box averaged down to target_nw * target_nh cells,
+    // row by row, for thumbnails. Source cells straddling two target cells
+    // count towards both by how much of them each covers
+    pub fn density_downsampled(&self, target_nw: u16, target_nh: u16) -> Vec<f32> {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        assert!(
+            target_nw >= 1 && target_nw <= nw && target_nh >= 1 && target_nh <= nh,
+            "target size must be between 1 and the grid size"
+        );
+        let (weights_x, weights_y) = (box_weights(nw, target_nw), box_weights(nh, target_nh));
+        let mut values = Vec::with_capacity(target_nw as usize * target_nh as usize);
+        for row in &weights_y {
+            for column in &weights_x {
+                let mut value = 0.0;
+                for &(j, weight_y) in row {
+                    for &(i, weight_x) in column {
+                        value += weight_x * weight_y * self.density[pure_ix_fn(i, j, nw, nh)];
+                    }
+                }
+                values.push(value);
+            }
+        }
+        values
+    }
+
+    // The interior density as RGBA pixels, row by row, coloured like the
+    // WebGL renderer's shader does. Density is divided by max and passed
+    // through tone_map before colouring. Wrap the bytes in a
+    // Uint8ClampedArray to hand them to ImageData
+    pub fn render_to_rgba(&self, max: f32, tone_map: &ToneMap) -> Vec<u8> {
+        let channel = |value: f32| (value * 255.0).round() as u8;
+        let mut pixels = Vec::with_capacity(4 * self.config.nw as usize * self.config.nh as usize);
+        for density in self.interior_values(&self.density) {
+            let normalized = if max > 0.0 { density / max } else { 0.0 };
+            let value = tone_map.apply(normalized);
+            pixels.extend_from_slice(&[channel(value * value), 0, channel(value), 255]);
+        }
+        pixels
+    }
+
+    // The velocity at every spacing-th cell in both directions, for drawing
+    // an arrow field, as x, y, velocity_x, velocity_y for each sampled cell in
+    // turn, row by row. x and y are cell coordinates like those of ix. When
+    // spacing does not divide the grid the left over cells are split
+    // between both sides, so the samples stay centered. A spacing of 0
+    // samples nothing
+    pub fn velocity_samples(&self, spacing: u16) -> Vec<f32> {
+        if spacing == 0 {
+            return Vec::new();
+        }
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let first = |n: u16| 1 + (n - 1) % spacing / 2;
+        let mut samples = Vec::new();
+        for j in (first(nh)..nh + 1).step_by(spacing as usize) {
+            for i in (first(nw)..nw + 1).step_by(spacing as usize) {
+                let index = pure_ix_fn(i, j, nw, nh);
+                samples.extend_from_slice(&[
+                    i as f32,
+                    j as f32,
+                    self.velocity_x[index],
+                    self.velocity_y[index],
+                ]);
+            }
+        }
+        samples
+    }
+
+    // The interior density as text for eyeballing a field in a terminal or a
+    // failing test. Each cell becomes a character of levels, from the first
+    // for no density to the last for the densest cell of the grid, one line
+    // per row starting from y = 1. " .:-=+*#%@" makes a good ramp
+    pub fn to_ascii(&self, levels: &str) -> String {
+        let levels: Vec<char> = levels.chars().collect();
+        assert!(!levels.is_empty(), "levels needs at least one character");
+        let values = self.interior_values(&self.density);
+        let max = values.iter().cloned().fold(0.0, f32::max);
+        let mut ascii = String::with_capacity(values.len() + self.config.nh as usize);
+        for row in values.chunks(self.config.nw as usize) {
+            for &value in row {
+                let level = if max > 0.0 {
+                    (value.max(0.0) / max * (levels.len() - 1) as f32).round() as usize
+                } else {
+                    0
+                };
+                ascii.push(levels[level]);
+            }
+            ascii.push('\n');
+        }
+        ascii
+    }
+
+    // Copies of the interior cells as JS typed arrays, row by row. The f32
+    // values are widened to the Float64Array JavaScript numbers use
+    pub fn get_density_array(&self) -> Float64Array {
+        self.interior_array(&self.density)
+    }
+
+    pub fn get_velocity_x_array(&self) -> Float64Array {
+        self.interior_array(&self.velocity_x)
+    }
+
+    pub fn get_velocity_y_array(&self) -> Float64Array {
+        self.interior_array(&self.velocity_y)
+    }
+
+    // The initial buffers are where sources land and where the next step
+    // starts from: they are diffused and advected into the current buffers,
+    // which are then swapped back in. Once a step is complete both sets hold
+    // the finished frame, so these grids match what get_density_at_index and
+    // friends read. Grids are the interior cells row by row
+
+    pub fn get_initial_density_grid(&self) -> Vec<f32> {
+        self.interior_values(&self.initial_density)
+    }
+
+    pub fn get_initial_velocity_x_grid(&self) -> Vec<f32> {
+        self.interior_values(&self.initial_velocity_x)
+    }
+
+    pub fn get_initial_velocity_y_grid(&self) -> Vec<f32> {
+        self.interior_values(&self.initial_velocity_y)
+    }
+
+    pub fn set_initial_density_grid(&mut self, values: &[f32]) {
+        let b = self.boundary(BoundaryType::NONE);
+        self.set_interior_values(values, b, |fluid| &mut fluid.initial_density);
+    }
+
+    pub fn set_initial_velocity_x_grid(&mut self, values: &[f32]) {
+        let b = self.boundary(BoundaryType::VERTICAL);
+        self.set_interior_values(values, b, |fluid| &mut fluid.initial_velocity_x);
+    }
+
+    pub fn set_initial_velocity_y_grid(&mut self, values: &[f32]) {
+        let b = self.boundary(BoundaryType::HORIZONTAL);
+        self.set_interior_values(values, b, |fluid| &mut fluid.initial_velocity_y);
+    }
+
+    // Sets the velocity the next step starts from to scale times the density
+    // gradient, so a drawn density pattern spreads out from its dense parts,
+    // or with a negative scale falls in on them. swirl turns the gradient a
+    // quarter counterclockwise, sending the flow around the dense parts
+    // along the density contours instead
+    pub fn velocity_from_density_gradient(&mut self, scale: f32, swirl: bool) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let scale = scale / (2.0 * self.config.dx);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let gradient_x = scale
+                    * (self.initial_density[pure_ix_fn(i + 1, j, nw, nh)]
+                        - self.initial_density[pure_ix_fn(i - 1, j, nw, nh)]);
+                let gradient_y = scale
+                    * (self.initial_density[pure_ix_fn(i, j + 1, nw, nh)]
+                        - self.initial_density[pure_ix_fn(i, j - 1, nw, nh)]);
+                let (velocity_x, velocity_y) = if swirl {
+                    (-gradient_y, gradient_x)
+                } else {
+                    (gradient_x, gradient_y)
+                };
+                self.initial_velocity_x[index] = velocity_x;
+                self.initial_velocity_y[index] = velocity_y;
+            }
+        }
+        let (x_bnd, y_bnd) = (
+            self.boundary(BoundaryType::VERTICAL),
+            self.boundary(BoundaryType::HORIZONTAL),
+        );
+        set_bnd!(nw, nh, x_bnd, self.initial_velocity_x);
+        set_bnd!(nw, nh, y_bnd, self.initial_velocity_y);
+    }
+
+    // The whole buffers row by row, border cells included, for checking what
+    // set_bnd wrote there. Rows are get_stride cells long and start from the
+    // border row below y = 1
+    pub fn get_density_grid_with_border(&self) -> Vec<f32> {
+        self.density.clone()
+    }
+
+    pub fn get_velocity_x_grid_with_border(&self) -> Vec<f32> {
+        self.velocity_x.clone()
+    }
+
+    pub fn get_velocity_y_grid_with_border(&self) -> Vec<f32> {
+        self.velocity_y.clone()
+    }
+
+    // Blends layer, the interior cells row by row, into the density. Both
+    // density buffers are blended so the next step continues from the result
+    pub fn composite_density(&mut self, layer: &[f32], mode: BlendMode) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        assert_eq!(
+            layer.len(),
+            nw as usize * nh as usize,
+            "layer must have nw * nh values"
+        );
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let value = layer[(j as usize - 1) * nw as usize + i as usize - 1];
+                self.density[index] = mode.apply(self.density[index], value);
+                self.initial_density[index] = mode.apply(self.initial_density[index], value);
+            }
+        }
+        let b = self.boundary(BoundaryType::NONE);
+        set_bnd!(nw, nh, b, self.density);
+        set_bnd!(nw, nh, b, self.initial_density);
+    }
+
+    // Animates the diffusion over the steps. steps[i] is the step count at
+    // which the diffusion reaches values[i], with linear interpolation in
+    // between. The scheduled value is written to the config at the start of
+    // each step, replacing set_config_diffusion until the schedule is cleared
+    pub fn set_diffusion_schedule(&mut self, steps: &[u32], values: &[f32]) {
+        assert_eq!(
+            steps.len(),
+            values.len(),
+            "every keyframe needs a step and a value"
+        );
+        let keyframes: Vec<(u64, f32)> = steps
+            .iter()
+            .zip(values)
+            .map(|(&step, &value)| (step as u64, value))
+            .collect();
+        self.diffusion_schedule = DiffusionSchedule::new(&keyframes);
+    }
+
+    pub fn clear_diffusion_schedule(&mut self) {
+        self.diffusion_schedule = DiffusionSchedule::default();
+    }
+
+    // Gives one cell its own diffusion, e.g. a near zero value for a barrier
+    // that resists flow. Negative and non finite values are ignored like
+    // set_diffusion does, and so are indices outside the grid. Only the built
+    // in Gauss-Seidel diffusion reads the field, a solver set with set_solver
+    // keeps diffusing uniformly
+    pub fn set_diffusion_at(&mut self, index: usize, value: f32) {
+        if index >= self.size || value < 0.0 || !value.is_finite() {
+            return;
+        }
+        let size = self.size;
+        self.diffusion_field.get_or_insert_with(|| vec![None; size])[index] = Some(value);
+        self.diffusion_field_cache = None;
+    }
+
+    // Goes back to the configured diffusion for every cell
+    pub fn clear_diffusion_field(&mut self) {
+        self.diffusion_field = None;
+        self.diffusion_field_cache = None;
     }
 
     pub fn set_config_diffusion(&mut self, value: f32) {
         self.config.set_diffusion(value)
     }
+
+    pub fn set_config_wall_friction(&mut self, value: f32) {
+        self.config.set_wall_friction(value)
+    }
+
+    pub fn set_config_omega(&mut self, value: f32) {
+        self.config.set_omega(value)
+    }
+
+    pub fn set_config_dissipation(&mut self, value: f32) {
+        self.config.set_dissipation(value)
+    }
+
+    pub fn set_config_dx(&mut self, value: f32) {
+        self.config.set_dx(value)
+    }
+
+    // BFECC gives way to the plain clamped trace while solid_advection is
+    // Clamp and the grid has solids, see SolidAdvection
+    pub fn set_config_advection_scheme(&mut self, scheme: AdvectionScheme) {
+        self.config.set_advection_scheme(scheme)
+    }
+
+    pub fn set_config_boundary_mode(&mut self, mode: BoundaryMode) {
+        self.config.set_boundary_mode(mode)
+    }
+
+    pub fn set_config_pressure_solver(&mut self, kind: SolverKind) {
+        self.config.set_pressure_solver(kind)
+    }
+
+    pub fn set_config_conserve_mass(&mut self, conserve_mass: bool) {
+        self.config.set_conserve_mass(conserve_mass)
+    }
+
+    pub fn set_config_conserve_momentum(&mut self, conserve_momentum: bool) {
+        self.config.set_conserve_momentum(conserve_momentum)
+    }
+
+    pub fn set_config_sample_mode(&mut self, mode: SampleMode) {
+        self.config.set_sample_mode(mode)
+    }
+
+    pub fn set_config_interpolation(&mut self, interpolation: InterpolationKind) {
+        self.config.set_interpolation(interpolation)
+    }
+
+    pub fn set_config_symmetry(&mut self, symmetry: Symmetry) {
+        self.config.set_symmetry(symmetry)
+    }
+
+    pub fn set_config_diffusion_substeps(&mut self, substeps: u16) {
+        self.config.set_diffusion_substeps(substeps)
+    }
+
+    pub fn set_config_incompressibility(&mut self, method: IncompressibilityMethod) {
+        self.config.set_incompressibility(method)
+    }
+
+    pub fn set_config_sound_speed(&mut self, sound_speed: f32) {
+        self.config.set_sound_speed(sound_speed)
+    }
+
+    pub fn set_config_source_application(&mut self, application: SourceApplication) {
+        self.config.set_source_application(application)
+    }
+
+    pub fn set_config_incremental_solve(&mut self, incremental_solve: bool) {
+        self.config.set_incremental_solve(incremental_solve)
+    }
+
+    // Through by default, which is how advection treated solids before Clamp
+    pub fn set_config_solid_advection(&mut self, solid_advection: SolidAdvection) {
+        self.config.set_solid_advection(solid_advection)
+    }
+
+    pub fn set_config_auto_iterations(&mut self, max_iterations: u16) {
+        self.config.set_auto_iterations(max_iterations)
+    }
+
+    pub fn clear_config_auto_iterations(&mut self) {
+        self.config.clear_auto_iterations()
+    }
+
+    pub fn get_config(&self) -> FluidConfig {
+        self.config.clone()
+    }
+
+    // Replaces every setting at once. The grid size is fixed when the fluid
+    // is created, so config must have the same nw and nh
+    pub fn set_config(&mut self, config: FluidConfig) {
+        assert!(
+            config.nw == self.config.nw && config.nh == self.config.nh,
+            "set_config cannot change the grid size"
+        );
+        self.config = config;
+    }
+
+    // Advects density through a fixed velocity field instead of the simulated
+    // one. Both buffers cover the whole grid including the border, like ix
+    pub fn set_advection_velocity(&mut self, velocity_x: PropertyType, velocity_y: PropertyType) {
+        assert_eq!(
+            velocity_x.len(),
+            self.size,
+            "velocity_x must have get_size() values"
+        );
+        assert_eq!(
+            velocity_y.len(),
+            self.size,
+            "velocity_y must have get_size() values"
+        );
+        self.advection_velocity = Some((velocity_x, velocity_y));
+    }
+
+    pub fn clear_advection_velocity(&mut self) {
+        self.advection_velocity = None;
+    }
+
+    // Largest residual left by the diffusion solves of the last step. Only
+    // measured while set_residual_tolerance is set, 0 otherwise
+    pub fn last_diffuse_residual(&self) -> f32 {
+        self.last_diffuse_residual
+    }
+
+    // Sweeps the built in diffusion solves of the last step took, over all
+    // properties and substeps. Every residual check reads the grid like a
+    // sweep and counts as one. A custom solver's own work is not counted
+    pub fn last_diffuse_sweeps(&self) -> u32 {
+        self.last_diffuse_sweeps
+    }
+
+    // Largest residual left by the pressure solves of the last step, measured
+    // like last_diffuse_residual
+    pub fn last_projection_residual(&self) -> f32 {
+        self.last_projection_residual
+    }
+
+    // has_converged reports false once a residual of the last step exceeds
+    // this. Any finite value also turns on measuring the residuals, which
+    // costs a sweep of the grid per solve; f32::MAX measures them without
+    // ever flagging a step. Defaults to infinity, which measures nothing
+    pub fn set_residual_tolerance(&mut self, tolerance: f32) {
+        self.residual_tolerance = tolerance
+    }
+
+    pub fn has_converged(&self) -> bool {
+        self.last_diffuse_residual <= self.residual_tolerance
+            && self.last_projection_residual <= self.residual_tolerance
+    }
+
+    // Coordinates outside the grid are clamped to the nearest border cell
+    pub fn set_solid(&mut self, x: u16, y: u16, solid: bool) {
+        let index = pure_ix_fn(x, y, self.config.nw, self.config.nh);
+        self.solids[index] = solid;
+        self.solids_changed();
+    }
+
+    // Confines the fluid to the circle inscribed in the grid by marking every
+    // cell outside it as solid, giving a petri dish shaped domain. The curved
+    // edge is handled by the solid cell walls, like any other obstacle, so
+    // apply_solids keeps the fluid and its density inside the circle
+    pub fn set_circular_domain(&mut self) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let center_x = (nw as f32 + 1.0) / 2.0;
+        let center_y = (nh as f32 + 1.0) / 2.0;
+        let radius = cmp::min(nw, nh) as f32 / 2.0;
+        for j in 0..nh + 2 {
+            for i in 0..nw + 2 {
+                let dx = i as f32 - center_x;
+                let dy = j as f32 - center_y;
+                if dx * dx + dy * dy > radius * radius {
+                    self.solids[pure_ix_fn(i, j, nw, nh)] = true;
+                }
+            }
+        }
+        self.solids_changed();
+    }
+
+    // Damps velocity in the width cells next to the border and any solid,
+    // removing up to strength of it per unit of time. A width of 0 turns the
+    // sponge off
+    pub fn set_sponge(&mut self, width: u16, strength: f32) {
+        if strength >= 0.0 && strength.is_finite() {
+            self.sponge = (width, strength);
+            self.update_sponge_distances();
+        }
+    }
+
+    // Adds the Coriolis force of a rotating frame, f * (vy, -vx), to the
+    // velocity every step, as a rotation by f * dt that keeps the speed. Flows
+    // swing into circles and spread out into swirling storms instead of running
+    // straight, especially together with the shallow water mode. 0, the
+    // default, turns it off
+    pub fn set_coriolis(&mut self, f: f32) {
+        if f.is_finite() {
+            self.coriolis = f;
+        }
+    }
+
+    // Caps the speed of every cell at max after each step, a safety valve
+    // against huge impulses blowing the simulation up. Directions are kept.
+    // f32::INFINITY, the default, removes the cap
+    pub fn set_max_speed(&mut self, max: f32) {
+        if max >= 0.0 {
+            self.max_speed = max;
+        }
+    }
+
+    // Restricts the density diffusion and advection to the box around the
+    // cells holding more than threshold of density, grown by a margin, for
+    // sparse scenes of a small plume in a large grid. Cells outside the box
+    // keep their density unchanged that step, which is what makes threshold
+    // an accuracy knob. The box is found again every step, and configurations
+    // that always sweep the whole grid ignore it, see find_active_region.
+    // Negative thresholds are ignored
+    pub fn set_active_region_tracking(&mut self, enabled: bool, threshold: f32) {
+        if !enabled {
+            self.active_region_threshold = None;
+        } else if threshold >= 0.0 {
+            self.active_region_threshold = Some(threshold);
+        }
+    }
+
+    // Adds -strength * grad(density) to the velocity every step, so dense
+    // areas repel the flow. 0 turns the coupling off
+    pub fn set_density_force_coupling(&mut self, strength: f32) {
+        if strength.is_finite() {
+            self.density_force_coupling = strength;
+        }
+    }
+
+    // Simulates shallow water waves instead of smoke. Density becomes the
+    // water height, gravity accelerates the flow down its slope and the flow
+    // is no longer made divergence free, so the height rises and falls with
+    // it. Disabling goes back to the incompressible smoke step
+    pub fn set_shallow_water(&mut self, enabled: bool, gravity: f32) {
+        if !enabled {
+            self.shallow_water = None;
+        } else if gravity >= 0.0 && gravity.is_finite() {
+            self.shallow_water = Some(gravity);
+        }
+    }
+
+    // Simulates a flow with rotational symmetry about a vertical axis, like a
+    // rising plume, from a slice through it. x becomes the distance from the
+    // axis, which runs along the left edge of the grid, and the diffusion and
+    // projection take the ring each cell stands for into account. The left
+    // wall mirrors the flow like any other, which is exactly what the axis
+    // needs: no flow across it and scalars as flat there as beside it.
+    // Advection is unchanged, as there is no swirl around the axis. The mode
+    // always diffuses and projects with the built in Gauss-Seidel, whatever
+    // solver, incompressibility method or incremental_solve is set, and
+    // needs walls, periodic grids ignore it
+    pub fn set_axisymmetric(&mut self, enabled: bool) {
+        self.axisymmetric = enabled;
+    }
+
+    pub fn is_solid(&self, x: u16, y: u16) -> bool {
+        self.solids[pure_ix_fn(x, y, self.config.nw, self.config.nh)]
+    }
+
+    // Blends this fluid's state towards other's, t = 0 keeps this state and
+    // t = 1 copies other's. Both fluids must share the same grid size
+    pub fn lerp_from(&mut self, other: &Fluid, t: f32) {
+        assert!(
+            self.config.nw == other.config.nw && self.config.nh == other.config.nh,
+            "lerp_from needs fluids with matching grid sizes"
+        );
+        let blend = |property: &mut PropertyType, target: &PropertyType| {
+            for (value, target) in property.iter_mut().zip(target.iter()) {
+                *value = lerp(*value, *target, t);
+            }
+        };
+        blend(&mut self.velocity_x, &other.velocity_x);
+        blend(&mut self.velocity_y, &other.velocity_y);
+        blend(&mut self.initial_velocity_x, &other.initial_velocity_x);
+        blend(&mut self.initial_velocity_y, &other.initial_velocity_y);
+        blend(&mut self.density, &other.density);
+        blend(&mut self.initial_density, &other.initial_density);
+    }
+
+    // Joins this fluid to other along side of this one, e.g. Right when other
+    // sits to the right, for tiling a large world out of several grids. The
+    // facing edge cells of both get their average density and velocity, so
+    // flow crosses the seam. Call it after every step for each shared edge.
+    // The coupled edges must be the same length
+    pub fn couple_boundary(&mut self, other: &mut Fluid, side: Side) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let (other_nw, other_nh) = (other.config.nw, other.config.nh);
+        let pairs: Vec<(usize, usize)> = match side {
+            Side::Left | Side::Right => {
+                assert_eq!(nh, other_nh, "coupled edges must have the same length");
+                let (x, other_x) = if side == Side::Left {
+                    (1, other_nw)
+                } else {
+                    (nw, 1)
+                };
+                (1..nh + 1)
+                    .map(|y| {
+                        (
+                            pure_ix_fn(x, y, nw, nh),
+                            pure_ix_fn(other_x, y, other_nw, other_nh),
+                        )
+                    })
+                    .collect()
+            }
+            Side::Bottom | Side::Top => {
+                assert_eq!(nw, other_nw, "coupled edges must have the same length");
+                let (y, other_y) = if side == Side::Bottom {
+                    (1, other_nh)
+                } else {
+                    (nh, 1)
+                };
+                (1..nw + 1)
+                    .map(|x| {
+                        (
+                            pure_ix_fn(x, y, nw, nh),
+                            pure_ix_fn(x, other_y, other_nw, other_nh),
+                        )
+                    })
+                    .collect()
+            }
+        };
+
+        let properties: [fn(&mut Fluid) -> &mut PropertyType; 6] = [
+            |fluid| &mut fluid.velocity_x,
+            |fluid| &mut fluid.velocity_y,
+            |fluid| &mut fluid.initial_velocity_x,
+            |fluid| &mut fluid.initial_velocity_y,
+            |fluid| &mut fluid.density,
+            |fluid| &mut fluid.initial_density,
+        ];
+        for property in properties {
+            let (values, other_values) = (property(self), property(other));
+            for &(index, other_index) in &pairs {
+                let average = 0.5 * (values[index] + other_values[other_index]);
+                values[index] = average;
+                other_values[other_index] = average;
+            }
+        }
+    }
+
+    // Box blurs the density passes times. Both density buffers are blurred
+    // since the next step continues from initial_density
+    pub fn blur_density(&mut self, passes: u16) {
+        let b = self.boundary(BoundaryType::NONE);
+        for _ in 0..passes {
+            box_blur(&mut self.density, b, self.config.nw, self.config.nh);
+            box_blur(&mut self.initial_density, b, self.config.nw, self.config.nh);
+        }
+    }
+
+    // Step count, mass, energy and the other aggregates in one snapshot
+    pub fn inspect(&self) -> FluidState {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let mut energy = 0.0;
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                energy += 0.5
+                    * (self.velocity_x[index] * self.velocity_x[index]
+                        + self.velocity_y[index] * self.velocity_y[index]);
+            }
+        }
+        FluidState {
+            step_count: self.steps,
+            dt: self.dt,
+            mass: interior_sum(&self.density, nw, nh),
+            energy,
+            max_speed: self.max_velocity(),
+            max_divergence: self.max_divergence(),
+        }
+    }
+
+    // Fills out with the speed of every interior cell, row by row, in one
+    // pass. out must hold nw * nh values
+    pub fn compute_speed(&self, out: &mut [f32]) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        assert_eq!(
+            out.len(),
+            nw as usize * nh as usize,
+            "out must have nw * nh values"
+        );
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                out[(j as usize - 1) * nw as usize + i as usize - 1] =
+                    self.velocity_x[index].hypot(self.velocity_y[index]);
+            }
+        }
+    }
+
+    // Kinetic energy of the velocity binned by wavenumber, from the mean flow
+    // in bin 0 up to the finest swirls the grid holds. The bins sum to the
+    // energy of inspect
+    pub fn energy_spectrum(&self) -> Vec<f32> {
+        spectrum::energy_spectrum(
+            &self.interior_values(&self.velocity_x),
+            &self.interior_values(&self.velocity_y),
+            self.config.nw as usize,
+            self.config.nh as usize,
+        )
+    }
+
+    // Hash of the bits of every density and velocity buffer, for checking a
+    // refactor still produces the exact same state after the same inputs.
+    // Stable across platforms and builds, but any change to a single bit of
+    // the state changes it
+    pub fn state_checksum(&self) -> u64 {
+        const FNV_OFFSET_BASIS: u64 = 0xcbf2_9ce4_8422_2325;
+        [
+            &self.velocity_x,
+            &self.velocity_y,
+            &self.initial_velocity_x,
+            &self.initial_velocity_y,
+            &self.density,
+            &self.initial_density,
+        ]
+        .iter()
+        .fold(FNV_OFFSET_BASIS, |hash, property| fnv1a_f32(hash, property))
+    }
+
+    // Half the sum of the squared curl over the interior. How fast it decays
+    // shows how much rotation the solver smears away each step
+    pub fn enstrophy(&self) -> f32 {
+        let mut enstrophy = 0.0;
+        for j in 1..self.config.nh + 1 {
+            for i in 1..self.config.nw + 1 {
+                let curl = self.curl(i, j);
+                enstrophy += 0.5 * curl * curl;
+            }
+        }
+        enstrophy
+    }
+
+    // Largest speed of any interior cell
+    pub fn max_velocity(&self) -> f32 {
+        let mut max: f32 = 0.0;
+        for j in 1..self.config.nh + 1 {
+            for i in 1..self.config.nw + 1 {
+                let index = pure_ix_fn(i, j, self.config.nw, self.config.nh);
+                max = max.max(self.velocity_x[index].hypot(self.velocity_y[index]));
+            }
+        }
+        max
+    }
+
+    // Largest absolute divergence of the velocity left by the last projection.
+    // The projected field is swapped into the initial buffers at the end of
+    // a step, which is what the next step continues from
+    pub fn max_divergence(&self) -> f32 {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let mut max: f32 = 0.0;
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let divergence = 0.5
+                    * (self.initial_velocity_x[pure_ix_fn(i + 1, j, nw, nh)]
+                        - self.initial_velocity_x[pure_ix_fn(i - 1, j, nw, nh)]
+                        + self.initial_velocity_y[pure_ix_fn(i, j + 1, nw, nh)]
+                        - self.initial_velocity_y[pure_ix_fn(i, j - 1, nw, nh)])
+                    / self.config.dx;
+                max = max.max(divergence.abs());
+            }
+        }
+        max
+    }
+
+    // Panics in debug builds when the projected velocity diverges by more
+    // than tol anywhere. Compiles to nothing in release builds
+    pub fn assert_divergence_free(&self, tol: f32) {
+        debug_assert!(
+            self.max_divergence() <= tol,
+            "velocity divergence {} exceeds {}",
+            self.max_divergence(),
+            tol
+        );
+    }
+
+    // Cells the fastest fluid crosses in one step. The semi-lagrangian
+    // advection stays stable above 1, but accuracy drops as it grows
+    pub fn cfl_number(&self) -> f32 {
+        self.max_velocity() * self.dt / self.config.dx
+    }
+
+    // Buckets the interior densities into bins evenly spread over [0, max].
+    // Values below 0 land in the first bin and values above max in the last
+    pub fn density_histogram(&self, bins: usize, max: f32) -> Vec<u32> {
+        let mut histogram = vec![0; bins];
+        if bins == 0 {
+            return histogram;
+        }
+        for j in 1..self.config.nh + 1 {
+            for i in 1..self.config.nw + 1 {
+                let value = self.density[pure_ix_fn(i, j, self.config.nw, self.config.nh)];
+                let bin = if max > 0.0 {
+                    ((value / max) * bins as f32).max(0.0) as usize
+                } else {
+                    0
+                };
+                histogram[cmp::min(bin, bins - 1)] += 1;
+            }
+        }
+        histogram
+    }
+
+    // Interpolated density at a fractional cell position. Positions outside the
+    // grid follow the configured sample mode
+    pub fn sample_density(&self, x: f32, y: f32) -> f32 {
+        sample_property_with(
+            &self.density,
+            x,
+            y,
+            self.config.nw,
+            self.config.nh,
+            self.config.sample_mode,
+            self.config.interpolation,
+        )
+    }
+
+    // Marches steps samples along a ray from (x0, y0) advancing (dx, dy) cells
+    // each step and returns the integrated density, weighting every sample
+    // by the step length. Useful as an opacity or shadow term
+    pub fn integrate_density_along_ray(
+        &self,
+        x0: f32,
+        y0: f32,
+        dx: f32,
+        dy: f32,
+        steps: u32,
+    ) -> f32 {
+        let step_length = dx.hypot(dy);
+        let mut total = 0.0;
+        for step in 0..steps {
+            let t = step as f32;
+            total += self.sample_density(x0 + t * dx, y0 + t * dy) * step_length;
+        }
+        total
+    }
+
+    // Traces a streamline from a seed point using midpoint integration and
+    // returns the visited points flattened as [x0, y0, x1, y1, ...]. Tracing
+    // stops once the line leaves the grid, stalls, or max_steps is reached
+    pub fn trace_streamline(
+        &self,
+        start_x: f32,
+        start_y: f32,
+        step: f32,
+        max_steps: u32,
+    ) -> Vec<f32> {
+        let max_x = self.config.nw as f32 + 0.5;
+        let max_y = self.config.nh as f32 + 0.5;
+        let inside = |x: f32, y: f32| (0.5..=max_x).contains(&x) && (0.5..=max_y).contains(&y);
+
+        let mut points = Vec::new();
+        if !inside(start_x, start_y) {
+            return points;
+        }
+
+        let (mut x, mut y) = (start_x, start_y);
+        points.push(x);
+        points.push(y);
+
+        for _ in 0..max_steps {
+            let (vx, vy) = self.sample_velocity(x, y);
+            if vx == 0.0 && vy == 0.0 {
+                break;
+            }
+            let (mid_vx, mid_vy) = self.sample_velocity(x + 0.5 * step * vx, y + 0.5 * step * vy);
+            // Flow into a wall cancels halfway there, which would repeat the
+            // last point until max_steps
+            if mid_vx == 0.0 && mid_vy == 0.0 {
+                break;
+            }
+            x += step * mid_vx;
+            y += step * mid_vy;
+            if !inside(x, y) {
+                break;
+            }
+            points.push(x);
+            points.push(y);
+        }
+
+        points
+    }
+}
+
+impl Fluid {
+    // Runs up to steps steps, calling callback with the index of each step
+    // once it is done. Stops early when the callback returns false, and
+    // returns the number of steps run
+    pub fn simulate_steps_with(
+        &mut self,
+        steps: u32,
+        mut callback: impl FnMut(u32) -> bool,
+    ) -> u32 {
+        for step in 0..steps {
+            self.simulate();
+            if !callback(step) {
+                return step + 1;
+            }
+        }
+        steps
+    }
+
+    // Sets the velocity of every interior cell to f(x, y) of its center in
+    // world coordinates, see cell_to_world, for flows given by a formula
+    pub fn set_velocity_from_fn(&mut self, f: impl Fn(f32, f32) -> (f32, f32)) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                let (x, y) = self.cell_to_world(i as f32, j as f32);
+                let (velocity_x, velocity_y) = f(x, y);
+                self.initial_velocity_x[index] = velocity_x;
+                self.initial_velocity_y[index] = velocity_y;
+            }
+        }
+        let (x_bnd, y_bnd) = (
+            self.boundary(BoundaryType::VERTICAL),
+            self.boundary(BoundaryType::HORIZONTAL),
+        );
+        set_bnd!(nw, nh, x_bnd, self.initial_velocity_x);
+        set_bnd!(nw, nh, y_bnd, self.initial_velocity_y);
+        self.velocity_x.clone_from(&self.initial_velocity_x);
+        self.velocity_y.clone_from(&self.initial_velocity_y);
+    }
+
+    // Fills out with the fractional cell coordinates the density advection
+    // traces each interior cell back to, row by row. It follows the
+    // advection velocity when one is set, like the density does. Sampling a
+    // field at these points with sample_mode and interpolation is what
+    // advect does with them
+    pub fn backtrace_map(&self, out: &mut [(f32, f32)]) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        assert_eq!(
+            out.len(),
+            nw as usize * nh as usize,
+            "out must have nw * nh entries"
+        );
+        let (velocity_x, velocity_y) = match &self.advection_velocity {
+            Some((velocity_x, velocity_y)) => (velocity_x, velocity_y),
+            None => (&self.velocity_x, &self.velocity_y),
+        };
+        let dt = self.dt / self.config.dx;
+        let mut positions = out.iter_mut();
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                let index = pure_ix_fn(i, j, nw, nh);
+                if let Some(position) = positions.next() {
+                    *position = (
+                        i as f32 - velocity_x[index] * dt,
+                        j as f32 - velocity_y[index] * dt,
+                    );
+                }
+            }
+        }
+    }
+
+    // What sample_density does at (x, y), for tracking down sampling
+    // artifacts: the four cells it reads, the bilinear weight of each and the
+    // sampled value. Cells come bottom left, bottom right, top left, top right
+    // and follow the configured sample mode. None where SampleMode::Zero
+    // reads nothing. The weights are the bilinear ones even when bicubic
+    // interpolation is configured, and so is the value
+    pub fn sample_density_debug(&self, x: f32, y: f32) -> Option<([usize; 4], [f32; 4], f32)> {
+        let (corners, fx, fy) = sample_corners(
+            x,
+            y,
+            self.config.nw,
+            self.config.nh,
+            self.config.sample_mode,
+        )?;
+        let weights = [
+            (1.0 - fx) * (1.0 - fy),
+            fx * (1.0 - fy),
+            (1.0 - fx) * fy,
+            fx * fy,
+        ];
+        let value = lerp(
+            lerp(self.density[corners[0]], self.density[corners[1]], fx),
+            lerp(self.density[corners[2]], self.density[corners[3]], fx),
+            fy,
+        );
+        Some((corners, weights, value))
+    }
+
+    // A handle for reading presented frames from another thread. The first
+    // call presents the current state so there is always a frame to read
+    pub fn frame_reader(&mut self) -> FrameReader {
+        if self.presented.is_none() {
+            self.presented = Some(FramePublisher::new(Frame::take(self)));
+        }
+        self.presented.as_ref().unwrap().reader()
+    }
+
+    // Publishes a copy of the interior density and velocity to the frame
+    // readers, typically after each simulate. The copy is made first and then
+    // swapped in atomically, so a reader gets either the frame before or the
+    // one after, never a mix of the two, and neither side takes a lock. Readers
+    // slower than the simulation skip frames. Does nothing until frame_reader
+    // has been called
+    pub fn present(&mut self) {
+        if let Some(mut publisher) = self.presented.take() {
+            publisher.publish(Frame::take(self));
+            self.presented = Some(publisher);
+        }
+    }
+
+    // Flat indices of the interior cells of row y, where y runs from 1 to nh
+    // like the y of ix. Slicing a buffer with it skips the border cells
+    pub fn interior_row_range(&self, row: u16) -> std::ops::Range<usize> {
+        assert!(
+            row >= 1 && row <= self.config.nh,
+            "row must be an interior row"
+        );
+        let start = self.ix(1, row);
+        start..start + self.config.nw as usize
+    }
+
+    // Solves the diffuse and project systems with a custom solver from now on
+    pub fn set_solver(&mut self, solver: Box<dyn Solver>) {
+        self.solver = Some(solver);
+    }
+
+    // Goes back to the built in Gauss-Seidel
+    pub fn clear_solver(&mut self) {
+        self.solver = None;
+    }
+
+    // Calls hook every step once the velocity is diffused and projected, just
+    // before it is advected, for custom forcing or analysis. The getters
+    // show the velocity at that point, and changes to it have to reach the
+    // initial buffers the step continues from, as set_velocity_from_fn and
+    // set_initial_velocity_x_grid do. Density is as the last step left it.
+    // The hook must not step the fluid it is given, that would run a step
+    // in the middle of this one. A hook set from inside the hook replaces it
+    // from the next step on
+    pub fn set_mid_step_hook(&mut self, hook: impl FnMut(&mut Fluid) + 'static) {
+        self.mid_step_hook = Some(Box::new(hook));
+    }
+
+    pub fn clear_mid_step_hook(&mut self) {
+        self.mid_step_hook = None;
+    }
+
+    // World position of the center of cell (x, y). Interior cells start at 1,
+    // so the corner of cell (1, 1) sits on the origin
+    pub fn cell_to_world(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            self.config.origin_x + (x - 0.5) * self.config.dx,
+            self.config.origin_y + (y - 0.5) * self.config.dx,
+        )
+    }
+
+    // Inverse of cell_to_world, giving fractional cell coordinates
+    pub fn world_to_cell(&self, x: f32, y: f32) -> (f32, f32) {
+        (
+            (x - self.config.origin_x) / self.config.dx + 0.5,
+            (y - self.config.origin_y) / self.config.dx + 0.5,
+        )
+    }
+
+    // Sum of the interior velocity, taking every cell to hold a unit mass of
+    // fluid. Like max_divergence it reads the initial buffers the next step
+    // continues from
+    pub fn total_momentum(&self) -> (f32, f32) {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        (
+            interior_sum(&self.initial_velocity_x, nw, nh),
+            interior_sum(&self.initial_velocity_y, nw, nh),
+        )
+    }
+
+    // The velocity of a cell as (angle in radians, magnitude). A still cell
+    // has angle 0
+    pub fn get_velocity_polar_at_index(&self, index: usize) -> (f32, f32) {
+        let (x, y) = (self.velocity_x[index], self.velocity_y[index]);
+        let magnitude = x.hypot(y);
+        if magnitude == 0.0 {
+            (0.0, 0.0)
+        } else {
+            (y.atan2(x), magnitude)
+        }
+    }
+
+    // The interior cells of a field as a NumPy .npy file, an array of shape
+    // (nh, nw) whose first row is y = 1. Load it with numpy.load
+    pub fn to_npy(&self, field: Field) -> Vec<u8> {
+        let property = match field {
+            Field::Density => &self.density,
+            Field::VelocityX => &self.velocity_x,
+            Field::VelocityY => &self.velocity_y,
+        };
+        npy_bytes(
+            &self.interior_values(property),
+            self.config.nh as usize,
+            self.config.nw as usize,
+        )
+    }
+
+    // get_velocity_polar_at_index for every interior cell, row by row
+    pub fn get_velocity_polar_grid(&self) -> Vec<(f32, f32)> {
+        let (nw, nh) = (self.config.nw, self.config.nh);
+        let mut polar = Vec::with_capacity(nw as usize * nh as usize);
+        for j in 1..nh + 1 {
+            for i in 1..nw + 1 {
+                polar.push(self.get_velocity_polar_at_index(pure_ix_fn(i, j, nw, nh)));
+            }
+        }
+        polar
+    }
+}
+
+#[cfg(not(target_arch = "wasm32"))]
+impl Fluid {
+    // The solid mask over the whole grid including the border, indexed like ix
+    pub fn solids_slice(&self) -> &[bool] {
+        &self.solids
+    }
+
+    // Writes to_npy to a file at path
+    #[cfg(feature = "npy")]
+    pub fn save_npy(&self, path: &str, field: Field) -> std::io::Result<()> {
+        std::fs::write(path, self.to_npy(field))
+    }
 }
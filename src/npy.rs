@@ -0,0 +1,34 @@
+// NumPy's .npy format, version 1.0: a magic string, the version, the length
+// of a header describing the array as a Python dict literal and then the raw
+// values. The header is padded with spaces so the values start on a 64 byte
+// boundary
+const MAGIC: &[u8] = b"\x93NUMPY\x01\x00";
+const ALIGNMENT: usize = 64;
+
+// A rows * columns array of little endian f32 in C (row major) order
+pub(crate) fn npy_bytes(values: &[f32], rows: usize, columns: usize) -> Vec<u8> {
+    assert_eq!(
+        values.len(),
+        rows * columns,
+        "values must be rows * columns long"
+    );
+    let mut header = format!(
+        "{{'descr': '<f4', 'fortran_order': False, 'shape': ({}, {}), }}",
+        rows, columns
+    );
+    // The magic string and a u16 header length come first, and the header
+    // ends with a newline
+    let unpadded = MAGIC.len() + 2 + header.len() + 1;
+    let padding = (ALIGNMENT - unpadded % ALIGNMENT) % ALIGNMENT;
+    header.push_str(&" ".repeat(padding));
+    header.push('\n');
+
+    let mut bytes = Vec::with_capacity(unpadded + padding + 4 * values.len());
+    bytes.extend_from_slice(MAGIC);
+    bytes.extend_from_slice(&(header.len() as u16).to_le_bytes());
+    bytes.extend_from_slice(header.as_bytes());
+    for value in values {
+        bytes.extend_from_slice(&value.to_le_bytes());
+    }
+    bytes
+}
@@ -0,0 +1,147 @@
+use crate::Fluid;
+use std::sync::atomic::{AtomicPtr, AtomicUsize, Ordering};
+use std::sync::Arc;
+
+// The interior of a fluid after a completed step, as published by
+// Fluid::present. Buffers hold the interior cells row by row
+pub struct Frame {
+    steps: u64,
+    nw: u16,
+    nh: u16,
+    density: Vec<f32>,
+    velocity_x: Vec<f32>,
+    velocity_y: Vec<f32>,
+}
+
+impl Frame {
+    pub(crate) fn take(fluid: &Fluid) -> Frame {
+        Frame {
+            steps: fluid.steps,
+            nw: fluid.config.nw,
+            nh: fluid.config.nh,
+            density: fluid.interior_values(&fluid.density),
+            velocity_x: fluid.interior_values(&fluid.velocity_x),
+            velocity_y: fluid.interior_values(&fluid.velocity_y),
+        }
+    }
+
+    pub fn get_steps(&self) -> u64 {
+        self.steps
+    }
+
+    pub fn get_nw(&self) -> u16 {
+        self.nw
+    }
+
+    pub fn get_nh(&self) -> u16 {
+        self.nh
+    }
+
+    pub fn get_density(&self) -> &[f32] {
+        &self.density
+    }
+
+    pub fn get_velocity_x(&self) -> &[f32] {
+        &self.velocity_x
+    }
+
+    pub fn get_velocity_y(&self) -> &[f32] {
+        &self.velocity_y
+    }
+}
+
+// Where present publishes the latest frame, shared by the fluid and its
+// readers. latest owns one strong count of the frame it points to, as made by
+// Arc::into_raw. readers counts the readers between loading latest and taking
+// their own count of it, the only window in which a swapped out frame can
+// still be reached through the raw pointer
+pub(crate) struct FrameSlot {
+    latest: AtomicPtr<Frame>,
+    readers: AtomicUsize,
+}
+
+impl Drop for FrameSlot {
+    fn drop(&mut self) {
+        // Nothing can be reading once the last handle on the slot is gone
+        drop(unsafe { Arc::from_raw(*self.latest.get_mut()) });
+    }
+}
+
+// The fluid's end of a slot. Swapped out frames are retired rather than freed
+// until no reader is in the middle of taking one, which is checked again on
+// every present, so neither side ever waits on the other
+pub(crate) struct FramePublisher {
+    slot: Arc<FrameSlot>,
+    retired: Vec<Arc<Frame>>,
+}
+
+impl FramePublisher {
+    pub(crate) fn new(frame: Frame) -> FramePublisher {
+        FramePublisher {
+            slot: Arc::new(FrameSlot {
+                latest: AtomicPtr::new(Arc::into_raw(Arc::new(frame)) as *mut Frame),
+                readers: AtomicUsize::new(0),
+            }),
+            retired: Vec::new(),
+        }
+    }
+
+    pub(crate) fn reader(&self) -> FrameReader {
+        FrameReader {
+            slot: Arc::clone(&self.slot),
+        }
+    }
+
+    pub(crate) fn publish(&mut self, frame: Frame) {
+        let frame = Arc::into_raw(Arc::new(frame)) as *mut Frame;
+        let previous = self.slot.latest.swap(frame, Ordering::SeqCst);
+        self.retired.push(unsafe { Arc::from_raw(previous) });
+        self.reclaim();
+    }
+
+    // A reader counted after the swap loads the new frame, so once the count
+    // is seen at 0 none of the retired frames can be reached any more. Readers
+    // still holding one keep it alive through their own Arc
+    fn reclaim(&mut self) {
+        if self.slot.readers.load(Ordering::SeqCst) == 0 {
+            self.retired.clear();
+        }
+    }
+}
+
+impl Drop for FramePublisher {
+    fn drop(&mut self) {
+        // Readers leave the window within a few instructions
+        while !self.retired.is_empty() {
+            self.reclaim();
+            std::hint::spin_loop();
+        }
+    }
+}
+
+// A handle on the frames a fluid presents, for a renderer on another thread.
+// Cheap to clone, and every clone sees the same frames
+#[derive(Clone)]
+pub struct FrameReader {
+    slot: Arc<FrameSlot>,
+}
+
+impl FrameReader {
+    // The last presented frame. Lock free: it never waits on a present or a
+    // step, and present never waits on it. The frame stays valid and
+    // unchanged for as long as it is held, however many frames are presented
+    // meanwhile
+    pub fn latest(&self) -> Arc<Frame> {
+        let slot = &self.slot;
+        slot.readers.fetch_add(1, Ordering::SeqCst);
+        let frame = slot.latest.load(Ordering::SeqCst);
+        // The publisher frees no frame while a reader is counted, so the
+        // pointer is still alive here and holds a strong count to add to
+        let latest = unsafe {
+            Arc::increment_strong_count(frame);
+            Arc::from_raw(frame)
+        };
+        slot.readers.fetch_sub(1, Ordering::SeqCst);
+        latest
+    }
+}
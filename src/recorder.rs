@@ -0,0 +1,99 @@
+use crate::Fluid;
+use serde::{Deserialize, Serialize};
+use wasm_bindgen::prelude::*;
+
+// One input to a fluid
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub enum Event {
+    AddDensity { index: usize, value: f32 },
+    AddVelocity { index: usize, x: f32, y: f32 },
+    SetDt { dt: f32 },
+    Simulate,
+}
+
+// An event and the number of steps completed when it was applied, which stands in for a
+// timestamp since the simulation only moves on in whole steps
+#[derive(Serialize, Deserialize, Clone, Debug, PartialEq)]
+pub struct Record {
+    pub step: u64,
+    pub event: Event,
+}
+
+// Applies inputs to a fluid and logs them, so a run can be saved as JSON and
+// replayed exactly. The fluid is passed in on every call, like Stepper does
+#[wasm_bindgen]
+#[derive(Default)]
+pub struct Recorder {
+    log: Vec<Record>,
+}
+
+#[wasm_bindgen]
+impl Recorder {
+    pub fn new() -> Recorder {
+        Recorder::default()
+    }
+
+    pub fn add_density(&mut self, fluid: &mut Fluid, index: usize, value: f32) {
+        self.apply(fluid, Event::AddDensity { index, value });
+    }
+
+    pub fn add_velocity(&mut self, fluid: &mut Fluid, index: usize, x: f32, y: f32) {
+        self.apply(fluid, Event::AddVelocity { index, x, y });
+    }
+
+    pub fn set_dt(&mut self, fluid: &mut Fluid, dt: f32) {
+        self.apply(fluid, Event::SetDt { dt });
+    }
+
+    pub fn simulate(&mut self, fluid: &mut Fluid) {
+        self.apply(fluid, Event::Simulate);
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(&self.log).expect("a log of plain values always serializes")
+    }
+
+    pub fn clear(&mut self) {
+        self.log.clear();
+    }
+
+    // Re-applies a log made by to_json to fluid. Replaying onto a fluid in
+    // the state the recording started from reproduces it exactly
+    pub fn replay(fluid: &mut Fluid, json: &str) -> Result<(), JsValue> {
+        replay(fluid, json).map_err(|error| JsValue::from_str(&error))
+    }
+}
+
+impl Recorder {
+    pub fn records(&self) -> &[Record] {
+        &self.log
+    }
+
+    fn apply(&mut self, fluid: &mut Fluid, event: Event) {
+        let step = fluid.get_steps();
+        apply_event(fluid, &event);
+        self.log.push(Record { step, event });
+    }
+}
+
+fn apply_event(fluid: &mut Fluid, event: &Event) {
+    match *event {
+        Event::AddDensity { index, value } => {
+            fluid.add_density(index, value);
+        }
+        Event::AddVelocity { index, x, y } => {
+            fluid.add_velocity(index, x, y);
+        }
+        Event::SetDt { dt } => fluid.set_dt(dt),
+        Event::Simulate => fluid.simulate(),
+    }
+}
+
+// Rust side of Recorder::replay, with the parse error as a string
+pub fn replay(fluid: &mut Fluid, json: &str) -> Result<(), String> {
+    let log: Vec<Record> = serde_json::from_str(json).map_err(|error| error.to_string())?;
+    for record in &log {
+        apply_event(fluid, &record.event);
+    }
+    Ok(())
+}
@@ -0,0 +1,31 @@
+use crate::utils::lerp;
+
+// Diffusion as a function of the step count, given by (step, value)
+// keyframes. Values between keyframes are interpolated linearly and the
+// first and last values hold before and after them, so a single keyframe
+// is a constant
+#[derive(Clone, Debug, Default)]
+pub struct DiffusionSchedule {
+    keyframes: Vec<(u64, f32)>,
+}
+
+impl DiffusionSchedule {
+    pub fn new(keyframes: &[(u64, f32)]) -> DiffusionSchedule {
+        let mut keyframes = keyframes.to_vec();
+        keyframes.sort_by_key(|&(step, _)| step);
+        DiffusionSchedule { keyframes }
+    }
+
+    pub fn value_at(&self, step: u64) -> Option<f32> {
+        let next = self.keyframes.iter().position(|&(key, _)| key > step);
+        match next {
+            Some(0) => Some(self.keyframes[0].1),
+            Some(next) => {
+                let (start, from) = self.keyframes[next - 1];
+                let (end, to) = self.keyframes[next];
+                Some(lerp(from, to, (step - start) as f32 / (end - start) as f32))
+            }
+            None => self.keyframes.last().map(|&(_, value)| value),
+        }
+    }
+}
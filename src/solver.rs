@@ -0,0 +1,291 @@
+use crate::constants::{CONJUGATE_GRADIENT_ITERATIONS, GAUSS_SEIDEL_ITERATIONS};
+use crate::set_bnd;
+use crate::utils::{pure_ix_fn, remove_interior_mean, BoundaryType, PropertyType};
+use std::cmp;
+use wasm_bindgen::prelude::*;
+
+// Which solver the projection step uses for the pressure equation
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SolverKind {
+    GaussSeidel,
+    // Incomplete Cholesky preconditioned conjugate gradient. Each iteration
+    // costs about three Gauss-Seidel sweeps but converges much faster on
+    // large grids
+    ConjugateGradient,
+}
+
+// A five point system over the interior of the grid. For every interior cell
+// diagonal * x - wx * (left + right) - wy * (down + up) = rhs
+pub struct LinearSystem {
+    pub nw: u16,
+    pub nh: u16,
+    pub diagonal: f32,
+    pub weights: (f32, f32),
+    pub rhs: PropertyType,
+    // Starting guess, including the border cells
+    pub initial: PropertyType,
+    boundary: Option<BoundaryType>,
+    // The periodic pressure is only defined up to a constant
+    remove_mean: bool,
+}
+
+impl LinearSystem {
+    // The implicit diffusion step solved by diffuse
+    pub(crate) fn diffusion(
+        nw: u16,
+        nh: u16,
+        b: BoundaryType,
+        prev: &[f32],
+        current: &[f32],
+        k: f32,
+        weights: (f32, f32),
+    ) -> LinearSystem {
+        let (wx, wy) = weights;
+        LinearSystem {
+            nw,
+            nh,
+            diagonal: 1.0 + k * (wx + wy) / 2.0,
+            weights: (k * wx / 4.0, k * wy / 4.0),
+            rhs: prev.to_vec(),
+            initial: current.to_vec(),
+            boundary: Some(b),
+            remove_mean: false,
+        }
+    }
+
+    // The pressure equation solved by project, starting from zero
+    pub fn poisson(nw: u16, nh: u16, divergence: &[f32], periodic: bool) -> LinearSystem {
+        LinearSystem {
+            nw,
+            nh,
+            diagonal: 4.0,
+            weights: (1.0, 1.0),
+            rhs: divergence.iter().map(|value| -value).collect(),
+            initial: vec![0.0; divergence.len()],
+            // With walls the border pressure is only filled in once solved
+            boundary: if periodic {
+                Some(BoundaryType::PERIODIC)
+            } else {
+                None
+            },
+            remove_mean: periodic,
+        }
+    }
+
+    pub fn ix(&self, x: u16, y: u16) -> usize {
+        pure_ix_fn(x, y, self.nw, self.nh)
+    }
+
+    // Refreshes the border cells of x the way the built in solver does after
+    // every sweep
+    pub fn apply_boundary(&self, x: &mut [f32]) {
+        if self.remove_mean {
+            remove_interior_mean(x, self.nw, self.nh);
+        }
+        self.fill_border(x);
+    }
+
+    // Fills only the border cells of x from the interior
+    pub fn fill_border(&self, x: &mut [f32]) {
+        if let Some(b) = self.boundary {
+            set_bnd!(self.nw, self.nh, b, x);
+        }
+    }
+
+    // Dot product over the interior
+    pub fn dot(&self, a: &[f32], b: &[f32]) -> f32 {
+        let mut sum = 0.0;
+        for j in 1..self.nh + 1 {
+            for i in 1..self.nw + 1 {
+                sum += a[self.ix(i, j)] * b[self.ix(i, j)];
+            }
+        }
+        sum
+    }
+
+    // The left hand side of the system for x. Border cells are left at zero
+    pub fn apply(&self, x: &[f32]) -> PropertyType {
+        let (wx, wy) = self.weights;
+        let mut result = vec![0.0; x.len()];
+        for j in 1..self.nh + 1 {
+            for i in 1..self.nw + 1 {
+                result[self.ix(i, j)] = self.diagonal * x[self.ix(i, j)]
+                    - wx * (x[self.ix(i - 1, j)] + x[self.ix(i + 1, j)])
+                    - wy * (x[self.ix(i, j - 1)] + x[self.ix(i, j + 1)]);
+            }
+        }
+        result
+    }
+
+    // Largest error of x over the interior
+    pub fn residual(&self, x: &[f32]) -> f32 {
+        let applied = self.apply(x);
+        let mut residual: f32 = 0.0;
+        for j in 1..self.nh + 1 {
+            for i in 1..self.nw + 1 {
+                let index = self.ix(i, j);
+                residual = residual.max((self.rhs[index] - applied[index]).abs());
+            }
+        }
+        residual
+    }
+}
+
+// Solves the systems built by the diffuse and project steps. The returned
+// buffer replaces the property for the whole grid, border included
+pub trait Solver {
+    fn solve(&self, system: &LinearSystem) -> PropertyType;
+}
+
+// The built in Gauss-Seidel solver with over-relaxation
+pub struct GaussSeidel {
+    pub iterations: u32,
+    pub omega: f32,
+}
+
+impl Default for GaussSeidel {
+    fn default() -> GaussSeidel {
+        GaussSeidel {
+            iterations: GAUSS_SEIDEL_ITERATIONS as u32,
+            omega: 1.0,
+        }
+    }
+}
+
+impl Solver for GaussSeidel {
+    fn solve(&self, system: &LinearSystem) -> PropertyType {
+        let (wx, wy) = system.weights;
+        let mut x = system.initial.clone();
+        for _ in 0..self.iterations {
+            for j in 1..system.nh + 1 {
+                for i in 1..system.nw + 1 {
+                    let index = system.ix(i, j);
+                    let value = (system.rhs[index]
+                        + wx * (x[system.ix(i - 1, j)] + x[system.ix(i + 1, j)])
+                        + wy * (x[system.ix(i, j - 1)] + x[system.ix(i, j + 1)]))
+                        / system.diagonal;
+                    x[index] = (1.0 - self.omega) * x[index] + self.omega * value;
+                }
+            }
+            system.apply_boundary(&mut x);
+        }
+        x
+    }
+}
+
+// Preconditioned conjugate gradient. Needs a symmetric positive-definite
+// system, which the pressure equation and the diffusion step both are
+pub struct ConjugateGradient {
+    pub iterations: u32,
+}
+
+impl Default for ConjugateGradient {
+    fn default() -> ConjugateGradient {
+        ConjugateGradient {
+            iterations: CONJUGATE_GRADIENT_ITERATIONS as u32,
+        }
+    }
+}
+
+impl ConjugateGradient {
+    // Incomplete Cholesky factorisation of the system, storing the inverse of
+    // the factor's diagonal. The off diagonal entries follow from the weights
+    fn preconditioner(system: &LinearSystem) -> PropertyType {
+        let (wx, wy) = system.weights;
+        let mut precon = vec![0.0; system.initial.len()];
+        for j in 1..system.nh + 1 {
+            for i in 1..system.nw + 1 {
+                let left = wx * precon[system.ix(i - 1, j)];
+                let down = wy * precon[system.ix(i, j - 1)];
+                let diagonal = system.diagonal - left * left - down * down;
+                precon[system.ix(i, j)] = 1.0 / diagonal.sqrt();
+            }
+        }
+        precon
+    }
+
+    // Solves L * L^T * z = r with a forward and a backward sweep
+    fn apply_preconditioner(system: &LinearSystem, precon: &[f32], r: &[f32]) -> PropertyType {
+        let (wx, wy) = system.weights;
+        let mut z = vec![0.0; r.len()];
+        for j in 1..system.nh + 1 {
+            for i in 1..system.nw + 1 {
+                let index = system.ix(i, j);
+                let left = system.ix(i - 1, j);
+                let down = system.ix(i, j - 1);
+                z[index] = (r[index] + wx * precon[left] * z[left] + wy * precon[down] * z[down])
+                    * precon[index];
+            }
+        }
+        for j in (1..system.nh + 1).rev() {
+            for i in (1..system.nw + 1).rev() {
+                let index = system.ix(i, j);
+                z[index] = (z[index]
+                    + precon[index] * (wx * z[system.ix(i + 1, j)] + wy * z[system.ix(i, j + 1)]))
+                    * precon[index];
+            }
+        }
+        z
+    }
+}
+
+impl Solver for ConjugateGradient {
+    fn solve(&self, system: &LinearSystem) -> PropertyType {
+        let precon = ConjugateGradient::preconditioner(system);
+        let mut x = system.initial.clone();
+        system.fill_border(&mut x);
+
+        let applied = system.apply(&x);
+        let mut r = vec![0.0; x.len()];
+        for j in 1..system.nh + 1 {
+            for i in 1..system.nw + 1 {
+                let index = system.ix(i, j);
+                r[index] = system.rhs[index] - applied[index];
+            }
+        }
+        // A singular system only has a solution for a right hand side with
+        // no constant part
+        if system.remove_mean {
+            remove_interior_mean(&mut r, system.nw, system.nh);
+        }
+
+        let mut z = ConjugateGradient::apply_preconditioner(system, &precon, &r);
+        let mut p = z.clone();
+        let mut rz = system.dot(&r, &z);
+        for _ in 0..self.iterations {
+            // Already converged, the next step would divide by zero
+            if rz <= 0.0 || !rz.is_finite() {
+                break;
+            }
+            system.fill_border(&mut p);
+            let ap = system.apply(&p);
+            let alpha = rz / system.dot(&p, &ap);
+            for index in 0..x.len() {
+                x[index] += alpha * p[index];
+                r[index] -= alpha * ap[index];
+            }
+
+            z = ConjugateGradient::apply_preconditioner(system, &precon, &r);
+            let next_rz = system.dot(&r, &z);
+            let beta = next_rz / rz;
+            for index in 0..p.len() {
+                p[index] = z[index] + beta * p[index];
+            }
+            rz = next_rz;
+        }
+
+        system.apply_boundary(&mut x);
+        x
+    }
+}
+
+pub(crate) fn solve_checked(solver: &dyn Solver, system: &LinearSystem) -> PropertyType {
+    let solution = solver.solve(system);
+    assert_eq!(
+        solution.len(),
+        system.initial.len(),
+        "solver must return a value for every cell of the grid"
+    );
+    solution
+}
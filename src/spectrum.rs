@@ -0,0 +1,89 @@
+use std::f32::consts::PI;
+
+// Discrete Fourier transform of a row major width * height grid of real
+// values, as (re, im) pairs in the same layout. Transforms the rows and then
+// the columns, O(width * height * (width + height)). That is fine at the
+// grid sizes the simulation runs at and keeps the crate free of an FFT
+// dependency
+fn dft_2d(values: &[f32], width: usize, height: usize) -> Vec<(f32, f32)> {
+    // e^(-2 pi i k n / len) only depends on k * n mod len, so each axis
+    // needs a single table
+    let twiddles = |len: usize| -> Vec<(f32, f32)> {
+        (0..len)
+            .map(|n| {
+                let angle = -2.0 * PI * n as f32 / len as f32;
+                (angle.cos(), angle.sin())
+            })
+            .collect()
+    };
+    let (twiddles_x, twiddles_y) = (twiddles(width), twiddles(height));
+
+    let mut rows = vec![(0.0, 0.0); width * height];
+    for y in 0..height {
+        for k in 0..width {
+            let (mut re, mut im) = (0.0, 0.0);
+            for x in 0..width {
+                let (cos, sin) = twiddles_x[k * x % width];
+                re += values[y * width + x] * cos;
+                im += values[y * width + x] * sin;
+            }
+            rows[y * width + k] = (re, im);
+        }
+    }
+
+    let mut spectrum = vec![(0.0, 0.0); width * height];
+    for x in 0..width {
+        for k in 0..height {
+            let (mut re, mut im) = (0.0, 0.0);
+            for y in 0..height {
+                let (cos, sin) = twiddles_y[k * y % height];
+                let (value_re, value_im) = rows[y * width + x];
+                re += value_re * cos - value_im * sin;
+                im += value_re * sin + value_im * cos;
+            }
+            spectrum[k * width + x] = (re, im);
+        }
+    }
+    spectrum
+}
+
+// Wavenumber of the k-th output of a transform over len values. Outputs past
+// the middle are the negative frequencies
+fn wavenumber(k: usize, len: usize) -> f32 {
+    if k <= len / 2 {
+        k as f32
+    } else {
+        k as f32 - len as f32
+    }
+}
+
+// Kinetic energy of the row major width * height velocity grids binned by
+// wavenumber, the number of periods across the grid rounded to the nearest
+// whole one. Bin 0 holds the mean flow. Scaled so the bins sum to the kinetic
+// energy of the grid, half the sum of the squared speeds
+pub fn energy_spectrum(
+    velocity_x: &[f32],
+    velocity_y: &[f32],
+    width: usize,
+    height: usize,
+) -> Vec<f32> {
+    let cells = (width * height) as f32;
+    let bins = (width as f32 / 2.0).hypot(height as f32 / 2.0).round() as usize + 1;
+    let mut spectrum = vec![0.0; bins];
+    let (modes_x, modes_y) = (
+        dft_2d(velocity_x, width, height),
+        dft_2d(velocity_y, width, height),
+    );
+    for ky in 0..height {
+        for kx in 0..width {
+            let index = ky * width + kx;
+            let (x_re, x_im) = modes_x[index];
+            let (y_re, y_im) = modes_y[index];
+            // Parseval: the squared values sum to the squared modes / cells
+            let energy = 0.5 * (x_re * x_re + x_im * x_im + y_re * y_re + y_im * y_im) / cells;
+            let k = wavenumber(kx, width).hypot(wavenumber(ky, height)).round() as usize;
+            spectrum[k.min(bins - 1)] += energy;
+        }
+    }
+    spectrum
+}
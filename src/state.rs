@@ -0,0 +1,43 @@
+use wasm_bindgen::prelude::*;
+
+// Aggregates of a fluid at one moment, for debugging and tests. Holds no
+// buffers, so it is cheap to take every frame
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug)]
+pub struct FluidState {
+    pub(crate) step_count: u64,
+    pub(crate) dt: f32,
+    // Sum of the interior density
+    pub(crate) mass: f32,
+    // Kinetic energy, half the sum of the squared interior speeds
+    pub(crate) energy: f32,
+    pub(crate) max_speed: f32,
+    pub(crate) max_divergence: f32,
+}
+
+#[wasm_bindgen]
+impl FluidState {
+    pub fn get_step_count(&self) -> u64 {
+        self.step_count
+    }
+
+    pub fn get_dt(&self) -> f32 {
+        self.dt
+    }
+
+    pub fn get_mass(&self) -> f32 {
+        self.mass
+    }
+
+    pub fn get_energy(&self) -> f32 {
+        self.energy
+    }
+
+    pub fn get_max_speed(&self) -> f32 {
+        self.max_speed
+    }
+
+    pub fn get_max_divergence(&self) -> f32 {
+        self.max_divergence
+    }
+}
@@ -0,0 +1,60 @@
+use crate::Fluid;
+use wasm_bindgen::prelude::*;
+
+// Drives a fluid at a fixed step from variable frame durations. Elapsed time is
+// accumulated and consumed in whole steps, with the remainder carried over to
+// the next frame so the simulation does not depend on the frame rate
+#[wasm_bindgen]
+pub struct Stepper {
+    step: f32,
+    max_steps: u32,
+    accumulator: f32,
+}
+
+#[wasm_bindgen]
+impl Stepper {
+    // step is the wall-clock duration of one simulate call in seconds and
+    // max_steps caps how many steps a single frame may run, so a long pause
+    // does not stall the page trying to catch up
+    pub fn new(step: f32, max_steps: u32) -> Stepper {
+        Stepper {
+            step,
+            max_steps,
+            accumulator: 0.0,
+        }
+    }
+
+    // Runs as many whole steps as have elapsed and returns how many were run
+    pub fn advance(&mut self, fluid: &mut Fluid, elapsed_seconds: f32) -> u32 {
+        if self.step <= 0.0 {
+            return 0;
+        }
+        self.accumulator += elapsed_seconds.max(0.0);
+
+        let mut steps = 0;
+        while self.accumulator >= self.step && steps < self.max_steps {
+            fluid.simulate();
+            self.accumulator -= self.step;
+            steps += 1;
+        }
+
+        // Drop the whole steps that could not be caught up on instead of
+        // carrying them, keeping the fraction of a step get_alpha reports
+        if steps == self.max_steps {
+            self.accumulator %= self.step;
+        }
+        steps
+    }
+
+    // Fraction of a step left in the accumulator, for interpolating renders
+    pub fn get_alpha(&self) -> f32 {
+        if self.step <= 0.0 {
+            return 0.0;
+        }
+        self.accumulator / self.step
+    }
+
+    pub fn reset(&mut self) {
+        self.accumulator = 0.0
+    }
+}
@@ -0,0 +1,89 @@
+use wasm_bindgen::prelude::*;
+
+#[cfg(target_arch = "wasm32")]
+#[wasm_bindgen]
+extern "C" {
+    // performance.now, the only clock with sub millisecond resolution in a
+    // browser. std::time::Instant panics on wasm32-unknown-unknown
+    #[wasm_bindgen(js_namespace = performance, js_name = now)]
+    fn performance_now() -> f64;
+}
+
+// What a stage of a step spends its time on
+#[derive(Clone, Copy)]
+pub(crate) enum StageKind {
+    Diffuse,
+    Advect,
+    Project,
+    // Sources, forces, corrections and bookkeeping
+    Other,
+}
+
+// Wall clock milliseconds spent in each kind of stage by simulate_timed
+#[wasm_bindgen]
+#[derive(Clone, Copy, Debug, Default)]
+pub struct StageTimings {
+    pub(crate) diffuse: f64,
+    pub(crate) advect: f64,
+    pub(crate) project: f64,
+    pub(crate) other: f64,
+}
+
+#[wasm_bindgen]
+impl StageTimings {
+    pub fn get_diffuse_ms(&self) -> f64 {
+        self.diffuse
+    }
+
+    pub fn get_advect_ms(&self) -> f64 {
+        self.advect
+    }
+
+    pub fn get_project_ms(&self) -> f64 {
+        self.project
+    }
+
+    pub fn get_other_ms(&self) -> f64 {
+        self.other
+    }
+
+    pub fn get_total_ms(&self) -> f64 {
+        self.diffuse + self.advect + self.project + self.other
+    }
+}
+
+impl StageTimings {
+    pub(crate) fn add(&mut self, kind: StageKind, ms: f64) {
+        match kind {
+            StageKind::Diffuse => self.diffuse += ms,
+            StageKind::Advect => self.advect += ms,
+            StageKind::Project => self.project += ms,
+            StageKind::Other => self.other += ms,
+        }
+    }
+}
+
+pub(crate) struct Stopwatch {
+    #[cfg(target_arch = "wasm32")]
+    start: f64,
+    #[cfg(not(target_arch = "wasm32"))]
+    start: std::time::Instant,
+}
+
+impl Stopwatch {
+    pub(crate) fn start() -> Stopwatch {
+        Stopwatch {
+            #[cfg(target_arch = "wasm32")]
+            start: performance_now(),
+            #[cfg(not(target_arch = "wasm32"))]
+            start: std::time::Instant::now(),
+        }
+    }
+
+    pub(crate) fn elapsed_ms(&self) -> f64 {
+        #[cfg(target_arch = "wasm32")]
+        return performance_now() - self.start;
+        #[cfg(not(target_arch = "wasm32"))]
+        return self.start.elapsed().as_secs_f64() * 1000.0;
+    }
+}
@@ -1,4 +1,6 @@
+use crate::constants::AUTO_ITERATIONS_REDUCTION;
 use std::cmp;
+use std::collections::VecDeque;
 use wasm_bindgen::prelude::*;
 
 pub fn set_panic_hook() {
@@ -13,10 +15,225 @@ pub fn set_panic_hook() {
 }
 
 // ENUMS
+#[allow(clippy::upper_case_acronyms, non_camel_case_types)]
+#[derive(Clone, Copy)]
 pub enum BoundaryType {
     VERTICAL,
     HORIZONTAL,
     NONE,
+    // Border cells mirror the opposite edge so the grid wraps around
+    PERIODIC,
+    // VERTICAL and HORIZONTAL for walls that keep only this fraction of the
+    // velocity along them. The plain ones keep all of it
+    VERTICAL_SLIP(f32),
+    HORIZONTAL_SLIP(f32),
+}
+
+// The fields the step diffuses and advects. Adding one takes its pair of
+// buffers in Fluid::property_buffers and the boundary it reflects off walls
+// with, and the shared diffuse and advect stages then handle it
+#[derive(Clone, Copy)]
+pub(crate) enum Property {
+    Density,
+    VelocityX,
+    VelocityY,
+    Age,
+}
+
+impl Property {
+    pub(crate) fn boundary(self) -> BoundaryType {
+        match self {
+            Property::Density | Property::Age => BoundaryType::NONE,
+            Property::VelocityX => BoundaryType::VERTICAL,
+            Property::VelocityY => BoundaryType::HORIZONTAL,
+        }
+    }
+}
+
+// What happens to fluid at the edges of the grid
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BoundaryMode {
+    // Closed box, fluid reflects off the edges
+    Walls,
+    // Fluid leaving one edge re-enters from the opposite one
+    Periodic,
+}
+
+// How advect carries a property along the velocity field
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum AdvectionScheme {
+    // Single semi-lagrangian back-trace with bilinear interpolation
+    SemiLagrangian,
+    // Back and Forth Error Compensation and Correction. Costs three advections
+    // but diffuses far less, and is clamped so it cannot create new extrema
+    Bfecc,
+}
+
+// How sampling treats positions outside the grid, like back-traces that
+// leave it during advection
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SampleMode {
+    // Stick to the nearest edge
+    Clamp,
+    // Re-enter from the opposite edge
+    Wrap,
+    // Read nothing, as if the outside were empty
+    Zero,
+}
+
+// How sampling interpolates between cell centers
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum InterpolationKind {
+    // Blends the 2x2 cells around the position
+    Bilinear,
+    // Catmull-Rom spline through the 4x4 cells around the position. Smoother,
+    // but may overshoot the values it interpolates
+    Bicubic,
+}
+
+// Mirror symmetry restored after every step
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Symmetry {
+    None,
+    // The left half mirrors the right one
+    Horizontal,
+    // The top half mirrors the bottom one
+    Vertical,
+    Both,
+}
+
+// How render_to_rgba maps density, normalized so the chosen maximum is 1,
+// to a brightness in [0, 1]. Built with linear, gamma or reinhard
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub struct ToneMap {
+    curve: ToneCurve,
+}
+
+#[derive(Clone, Copy, PartialEq, Debug)]
+enum ToneCurve {
+    Linear,
+    Gamma(f32),
+    Reinhard,
+}
+
+#[wasm_bindgen]
+impl ToneMap {
+    // v, clamped to 1
+    pub fn linear() -> ToneMap {
+        ToneMap {
+            curve: ToneCurve::Linear,
+        }
+    }
+
+    // v ^ (1 / gamma), clamped to 1. A gamma above 1 lifts the faint wisps
+    // that linear mapping leaves nearly black
+    pub fn gamma(gamma: f32) -> ToneMap {
+        ToneMap {
+            curve: ToneCurve::Gamma(gamma),
+        }
+    }
+
+    // v / (1 + v). Never quite reaches 1, so dense areas keep their detail
+    // instead of clipping
+    pub fn reinhard() -> ToneMap {
+        ToneMap {
+            curve: ToneCurve::Reinhard,
+        }
+    }
+
+    pub fn apply(&self, value: f32) -> f32 {
+        let value = value.max(0.0);
+        match self.curve {
+            ToneCurve::Linear => value.min(1.0),
+            ToneCurve::Gamma(gamma) => value.powf(1.0 / gamma).min(1.0),
+            ToneCurve::Reinhard => value / (1.0 + value),
+        }
+    }
+}
+
+// A quantity of the fluid to export
+#[derive(Clone, Copy, PartialEq, Debug)]
+pub enum Field {
+    Density,
+    VelocityX,
+    VelocityY,
+}
+
+// How composite_density combines a layer with the density under it
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum BlendMode {
+    Add,
+    Max,
+    Multiply,
+    // The layer on top, hiding the density under it in proportion to its
+    // own, with 1 and up fully opaque
+    Over,
+}
+
+impl BlendMode {
+    pub(crate) fn apply(self, under: f32, layer: f32) -> f32 {
+        match self {
+            BlendMode::Add => under + layer,
+            BlendMode::Max => under.max(layer),
+            BlendMode::Multiply => under * layer,
+            BlendMode::Over => layer + under * (1.0 - layer.clamp(0.0, 1.0)),
+        }
+    }
+}
+
+// An edge of the grid. Bottom is the row of y = 1 and top the row of y = nh
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum Side {
+    Left,
+    Right,
+    Bottom,
+    Top,
+}
+
+// How a step keeps the flow incompressible
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum IncompressibilityMethod {
+    // Solves for the pressure that removes all divergence
+    Projection,
+    // Evolves a pressure field with the divergence instead of solving for
+    // it, so the fluid is slightly compressible. One sweep instead of an
+    // iterative solve, but stable only while sound_speed * dt / dx stays
+    // below about 0.7
+    ArtificialCompressibility,
+}
+
+// How long the sources set by add_density, add_velocity and the stamps
+// built on them last
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SourceApplication {
+    // Added by the next step only, however many steps a simulate_steps call
+    // runs. A one shot splat
+    OncePerFrame,
+    // Added by every step until clear_sources, like a persistent emitter
+    EveryStep,
+}
+
+// What advection does with a back-trace that runs into a solid cell
+#[wasm_bindgen]
+#[derive(Clone, Copy, PartialEq, Eq, Debug)]
+pub enum SolidAdvection {
+    // Stops the trace at the last point before the solid and samples the
+    // fluid cells around it only, so nothing is carried through obstacles.
+    // Always bilinear, and takes the place of BFECC while there are solids
+    Clamp,
+    // Samples wherever the trace ends, reading the mirrored values solids
+    // hold and crossing thin walls
+    Through,
 }
 
 // TYPES
@@ -35,15 +252,534 @@ pub fn pure_ix_fn(x: u16, y: u16, nw: u16, nh: u16) -> usize {
     new_x = cmp::max(0, new_x);
     let mut new_y = cmp::min(y, nh + 1);
     new_y = cmp::max(0, new_y);
-    ((new_x + new_y) + ((nw + 1) * new_y)) as usize
+    // Widen before multiplying, (nw + 2) * y overflows u16 on larger grids
+    new_x as usize + (nw as usize + 2) * new_y as usize
+}
+
+// The four cells around a fractional grid position and the interpolation
+// weights between them, or None when mode is Zero and the position lies
+// outside the grid. The cells come bottom left, bottom right, top left, top
+// right, and the weights are how far the position is past the left and
+// bottom ones. Clamp and Zero stop at the centers of the border cells, so
+// the corners never leave 0..=n + 1 and a position on an edge reads the
+// border cell the boundary conditions set. Wrap only reads interior cells,
+// with the cell after n being 1 again
+pub fn sample_corners(
+    x: f32,
+    y: f32,
+    nw: u16,
+    nh: u16,
+    mode: SampleMode,
+) -> Option<([usize; 4], f32, f32)> {
+    let (fnw, fnh) = (nw as f32, nh as f32);
+    match mode {
+        SampleMode::Clamp | SampleMode::Zero => {
+            if mode == SampleMode::Zero && (x < 0.5 || x > fnw + 0.5 || y < 0.5 || y > fnh + 0.5) {
+                return None;
+            }
+            let x = x.clamp(0.5, fnw + 0.5);
+            let y = y.clamp(0.5, fnh + 0.5);
+            let (x0, y0) = (x.floor() as u16, y.floor() as u16);
+            Some((
+                [
+                    pure_ix_fn(x0, y0, nw, nh),
+                    pure_ix_fn(x0 + 1, y0, nw, nh),
+                    pure_ix_fn(x0, y0 + 1, nw, nh),
+                    pure_ix_fn(x0 + 1, y0 + 1, nw, nh),
+                ],
+                x.fract(),
+                y.fract(),
+            ))
+        }
+        SampleMode::Wrap => {
+            // Interior cell centers 1..=n repeat every n cells, and the cell
+            // after n is 1 again
+            let x = (x - 1.0).rem_euclid(fnw) + 1.0;
+            let y = (y - 1.0).rem_euclid(fnh) + 1.0;
+            let x0 = cmp::min(x.floor() as u16, nw);
+            let y0 = cmp::min(y.floor() as u16, nh);
+            let x1 = if x0 == nw { 1 } else { x0 + 1 };
+            let y1 = if y0 == nh { 1 } else { y0 + 1 };
+            Some((
+                [
+                    pure_ix_fn(x0, y0, nw, nh),
+                    pure_ix_fn(x1, y0, nw, nh),
+                    pure_ix_fn(x0, y1, nw, nh),
+                    pure_ix_fn(x1, y1, nw, nh),
+                ],
+                x - x0 as f32,
+                y - y0 as f32,
+            ))
+        }
+    }
+}
+
+// Weights of the four Catmull-Rom taps around a position t of the way
+// between the middle two
+fn catmull_rom_weights(t: f32) -> [f32; 4] {
+    let t2 = t * t;
+    let t3 = t2 * t;
+    [
+        0.5 * (-t3 + 2.0 * t2 - t),
+        0.5 * (3.0 * t3 - 5.0 * t2 + 2.0),
+        0.5 * (-3.0 * t3 + 4.0 * t2 + t),
+        0.5 * (t3 - t2),
+    ]
+}
+
+// Bicubically samples a property. The outer taps are clamped to the grid, or
+// wrapped around it in Wrap mode
+fn sample_bicubic(property: &[f32], x: f32, y: f32, nw: u16, nh: u16, mode: SampleMode) -> f32 {
+    let (fnw, fnh) = (nw as f32, nh as f32);
+    let (x, y) = match mode {
+        SampleMode::Wrap => (
+            (x - 1.0).rem_euclid(fnw) + 1.0,
+            (y - 1.0).rem_euclid(fnh) + 1.0,
+        ),
+        SampleMode::Zero if x < 0.5 || x > fnw + 0.5 || y < 0.5 || y > fnh + 0.5 => {
+            return 0.0;
+        }
+        _ => (x.clamp(0.5, fnw + 0.5), y.clamp(0.5, fnh + 0.5)),
+    };
+    let tap = |k: i32, n: u16| -> u16 {
+        match mode {
+            SampleMode::Wrap => ((k - 1).rem_euclid(n as i32) + 1) as u16,
+            _ => k.clamp(0, n as i32 + 1) as u16,
+        }
+    };
+
+    let (x0, y0) = (x.floor(), y.floor());
+    let weights_x = catmull_rom_weights(x - x0);
+    let weights_y = catmull_rom_weights(y - y0);
+    let mut value = 0.0;
+    for (row, weight_y) in weights_y.iter().enumerate() {
+        let j = tap(y0 as i32 - 1 + row as i32, nh);
+        for (column, weight_x) in weights_x.iter().enumerate() {
+            let i = tap(x0 as i32 - 1 + column as i32, nw);
+            value += weight_x * weight_y * property[pure_ix_fn(i, j, nw, nh)];
+        }
+    }
+    value
+}
+
+// Samples a property at a fractional grid position, treating positions outside
+// the grid according to mode
+pub fn sample_property_with(
+    property: &[f32],
+    x: f32,
+    y: f32,
+    nw: u16,
+    nh: u16,
+    mode: SampleMode,
+    interpolation: InterpolationKind,
+) -> f32 {
+    if interpolation == InterpolationKind::Bicubic {
+        return sample_bicubic(property, x, y, nw, nh, mode);
+    }
+    match sample_corners(x, y, nw, nh, mode) {
+        Some((corners, fx, fy)) => lerp(
+            lerp(property[corners[0]], property[corners[1]], fx),
+            lerp(property[corners[2]], property[corners[3]], fx),
+            fy,
+        ),
+        None => 0.0,
+    }
+}
+
+// Bilinearly samples a property at a fractional grid position. The position is
+// clamped the same way advect clamps its back-traced positions by default
+pub fn sample_property(property: &[f32], x: f32, y: f32, nw: u16, nh: u16) -> f32 {
+    sample_property_with(
+        property,
+        x,
+        y,
+        nw,
+        nh,
+        SampleMode::Clamp,
+        InterpolationKind::Bilinear,
+    )
+}
+
+// Whether the cell a position rounds to is solid. Positions past the border
+// count as the border cell they are nearest to
+fn is_solid_at(solids: &[bool], x: f32, y: f32, nw: u16, nh: u16) -> bool {
+    // Float to int casts saturate, so positions left of or below the grid
+    // land on 0
+    solids[pure_ix_fn(x.round() as u16, y.round() as u16, nw, nh)]
+}
+
+// Semi-Lagrangian advection that stops every back-trace at the last point
+// before it enters a solid, found by stepping along it half a cell at a time,
+// and samples bilinearly from the fluid corners around that point only. A
+// point with solids on all corners keeps the value of the cell itself. Only
+// sweeps columns and rows, where solid cells keep their value until
+// apply_solids fills them at the end of the step
+#[allow(clippy::too_many_arguments)]
+pub fn advect_around_solids(
+    nw: u16,
+    nh: u16,
+    b: BoundaryType,
+    property: &mut [f32],
+    prev_property: &[f32],
+    velocity_x: &[f32],
+    velocity_y: &[f32],
+    dt: f32,
+    mode: SampleMode,
+    solids: &[bool],
+    columns: std::ops::Range<u16>,
+    rows: std::ops::Range<u16>,
+) {
+    for j in rows {
+        for i in columns.clone() {
+            let index = pure_ix_fn(i, j, nw, nh);
+            if solids[index] {
+                property[index] = prev_property[index];
+                continue;
+            }
+            let (start_x, start_y) = (i as f32, j as f32);
+            let (trace_x, trace_y) = (-velocity_x[index] * dt, -velocity_y[index] * dt);
+            let steps = (2.0 * trace_x.hypot(trace_y)).ceil().max(1.0) as u32;
+            let (mut x, mut y) = (start_x, start_y);
+            for step in 1..steps + 1 {
+                let t = step as f32 / steps as f32;
+                let (next_x, next_y) = (start_x + trace_x * t, start_y + trace_y * t);
+                if is_solid_at(solids, next_x, next_y, nw, nh) {
+                    break;
+                }
+                (x, y) = (next_x, next_y);
+            }
+
+            property[index] = match sample_corners(x, y, nw, nh, mode) {
+                Some((corners, fx, fy)) => {
+                    let weights = [
+                        (1.0 - fx) * (1.0 - fy),
+                        fx * (1.0 - fy),
+                        (1.0 - fx) * fy,
+                        fx * fy,
+                    ];
+                    let (mut value, mut total) = (0.0, 0.0);
+                    for (&corner, weight) in corners.iter().zip(weights) {
+                        if !solids[corner] {
+                            value += weight * prev_property[corner];
+                            total += weight;
+                        }
+                    }
+                    if total > 0.0 {
+                        value / total
+                    } else {
+                        prev_property[index]
+                    }
+                }
+                None => 0.0,
+            };
+        }
+    }
+    crate::set_bnd!(nw, nh, b, property);
+}
+
+// Copies every cell outside columns and rows from prev_property, border
+// cells included, for the sweeps restricted to a box
+pub fn copy_outside(
+    property: &mut [f32],
+    prev_property: &[f32],
+    nw: u16,
+    nh: u16,
+    columns: std::ops::Range<u16>,
+    rows: std::ops::Range<u16>,
+) {
+    let width = nw as usize + 2;
+    for j in 0..nh as usize + 2 {
+        let row = j * width..(j + 1) * width;
+        if rows.contains(&(j as u16)) {
+            let left = row.start..row.start + columns.start as usize;
+            let right = row.start + columns.end as usize..row.end;
+            property[left.clone()].copy_from_slice(&prev_property[left]);
+            property[right.clone()].copy_from_slice(&prev_property[right]);
+        } else {
+            property[row.clone()].copy_from_slice(&prev_property[row]);
+        }
+    }
+}
+
+// Returns the index and offset from the center of every interior cell within
+// radius of (cx, cy)
+pub fn disc_cells(cx: u16, cy: u16, radius: u16, nw: u16, nh: u16) -> Vec<(usize, f32, f32)> {
+    let mut cells = Vec::new();
+    let min_x = cmp::max(1, cx.saturating_sub(radius));
+    let max_x = cmp::min(nw, cx.saturating_add(radius));
+    let min_y = cmp::max(1, cy.saturating_sub(radius));
+    let max_y = cmp::min(nh, cy.saturating_add(radius));
+    for j in min_y..=max_y {
+        for i in min_x..=max_x {
+            let dx = i as f32 - cx as f32;
+            let dy = j as f32 - cy as f32;
+            if dx * dx + dy * dy <= (radius as f32) * (radius as f32) {
+                cells.push((pure_ix_fn(i, j, nw, nh), dx, dy));
+            }
+        }
+    }
+    cells
+}
+
+// Rasterizes the segment between two grid points with Bresenham's algorithm and
+// returns the index of every cell it crosses. The endpoints are clamped to the
+// interior first
+pub fn line_cells(x0: u16, y0: u16, x1: u16, y1: u16, nw: u16, nh: u16) -> Vec<usize> {
+    let clamp_x = |x: u16| cmp::min(cmp::max(x, 1), nw) as i32;
+    let clamp_y = |y: u16| cmp::min(cmp::max(y, 1), nh) as i32;
+    let (mut x, mut y) = (clamp_x(x0), clamp_y(y0));
+    let (end_x, end_y) = (clamp_x(x1), clamp_y(y1));
+
+    let dx = (end_x - x).abs();
+    let dy = -(end_y - y).abs();
+    let step_x = if x < end_x { 1 } else { -1 };
+    let step_y = if y < end_y { 1 } else { -1 };
+    let mut error = dx + dy;
+
+    let mut cells = Vec::new();
+    loop {
+        cells.push(pure_ix_fn(x as u16, y as u16, nw, nh));
+        if x == end_x && y == end_y {
+            break;
+        }
+        let doubled = 2 * error;
+        if doubled >= dy {
+            error += dy;
+            x += step_x;
+        }
+        if doubled <= dx {
+            error += dx;
+            y += step_y;
+        }
+    }
+    cells
+}
+
+// Staggered (MAC) layout conversions. A MAC grid keeps the x velocity on the
+// nw + 1 vertical faces of every row and the y velocity on the nh + 1
+// horizontal faces of every column, both stored row by row. Face values are
+// the average of the two cells sharing the face, including border cells
+
+pub fn cells_to_faces_x(velocity_x: &[f32], nw: u16, nh: u16) -> Vec<f32> {
+    let mut faces = Vec::with_capacity((nw as usize + 1) * nh as usize);
+    for j in 1..nh + 1 {
+        for i in 0..nw + 1 {
+            faces.push(
+                0.5 * (velocity_x[pure_ix_fn(i, j, nw, nh)]
+                    + velocity_x[pure_ix_fn(i + 1, j, nw, nh)]),
+            );
+        }
+    }
+    faces
+}
+
+pub fn cells_to_faces_y(velocity_y: &[f32], nw: u16, nh: u16) -> Vec<f32> {
+    let mut faces = Vec::with_capacity(nw as usize * (nh as usize + 1));
+    for j in 0..nh + 1 {
+        for i in 1..nw + 1 {
+            faces.push(
+                0.5 * (velocity_y[pure_ix_fn(i, j, nw, nh)]
+                    + velocity_y[pure_ix_fn(i, j + 1, nw, nh)]),
+            );
+        }
+    }
+    faces
+}
+
+// Writes the interior cell centered x velocity from MAC x faces
+pub fn faces_x_to_cells(faces: &[f32], velocity_x: &mut [f32], nw: u16, nh: u16) {
+    let row = nw as usize + 1;
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let face = (j as usize - 1) * row + i as usize;
+            velocity_x[pure_ix_fn(i, j, nw, nh)] = 0.5 * (faces[face - 1] + faces[face]);
+        }
+    }
+}
+
+// Writes the interior cell centered y velocity from MAC y faces
+pub fn faces_y_to_cells(faces: &[f32], velocity_y: &mut [f32], nw: u16, nh: u16) {
+    let row = nw as usize;
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let face = j as usize * row + i as usize - 1;
+            velocity_y[pure_ix_fn(i, j, nw, nh)] = 0.5 * (faces[face - row] + faces[face]);
+        }
+    }
+}
+
+// Boundaries for the pressure and the two velocity components of a projection
+pub fn projection_boundaries(periodic: bool) -> (BoundaryType, BoundaryType, BoundaryType) {
+    if periodic {
+        (
+            BoundaryType::PERIODIC,
+            BoundaryType::PERIODIC,
+            BoundaryType::PERIODIC,
+        )
+    } else {
+        (
+            BoundaryType::NONE,
+            BoundaryType::VERTICAL,
+            BoundaryType::HORIZONTAL,
+        )
+    }
+}
+
+pub fn interior_sum(property: &[f32], nw: u16, nh: u16) -> f32 {
+    let mut sum = 0.0;
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            sum += property[pure_ix_fn(i, j, nw, nh)];
+        }
+    }
+    sum
+}
+
+pub fn remove_interior_mean(property: &mut [f32], nw: u16, nh: u16) {
+    let mean = interior_sum(property, nw, nh) / (nw as f32 * nh as f32);
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            property[pure_ix_fn(i, j, nw, nh)] -= mean;
+        }
+    }
+}
+
+// Steps from every cell to the nearest solid or border cell, moving between
+// edge neighbours. Cells further than max are left at u16::MAX
+pub fn wall_distances(solids: &[bool], nw: u16, nh: u16, max: u16) -> Vec<u16> {
+    let mut distances = vec![u16::MAX; solids.len()];
+    let mut queue = VecDeque::new();
+    for j in 0..nh + 2 {
+        for i in 0..nw + 2 {
+            let index = pure_ix_fn(i, j, nw, nh);
+            if solids[index] || i == 0 || j == 0 || i == nw + 1 || j == nh + 1 {
+                distances[index] = 0;
+                queue.push_back((i, j));
+            }
+        }
+    }
+
+    while let Some((i, j)) = queue.pop_front() {
+        let distance = distances[pure_ix_fn(i, j, nw, nh)];
+        if distance >= max {
+            continue;
+        }
+        // The border cells sit on the edge, wrapping_sub keeps their outward
+        // neighbour out of the interior instead of underflowing
+        for (x, y) in [
+            (i.wrapping_sub(1), j),
+            (i + 1, j),
+            (i, j.wrapping_sub(1)),
+            (i, j + 1),
+        ] {
+            if x < 1 || y < 1 || x > nw || y > nh {
+                continue;
+            }
+            let index = pure_ix_fn(x, y, nw, nh);
+            if distances[index] == u16::MAX {
+                distances[index] = distance + 1;
+                queue.push_back((x, y));
+            }
+        }
+    }
+    distances
+}
+
+// Averages every cell with its mirror across the vertical center line. sign is
+// -1.0 for the x velocity, which flips direction in the mirror
+pub fn mirror_horizontally(property: &mut [f32], sign: f32, nw: u16, nh: u16) {
+    for j in 1..nh + 1 {
+        for i in 1..nw / 2 + 1 {
+            let (left, right) = (pure_ix_fn(i, j, nw, nh), pure_ix_fn(nw + 1 - i, j, nw, nh));
+            let average = 0.5 * (property[left] + sign * property[right]);
+            property[left] = average;
+            property[right] = sign * average;
+        }
+        // The middle column of an odd grid is its own mirror
+        if sign < 0.0 && nw % 2 == 1 {
+            property[pure_ix_fn(nw / 2 + 1, j, nw, nh)] = 0.0;
+        }
+    }
+}
+
+// Averages every cell with its mirror across the horizontal center line
+pub fn mirror_vertically(property: &mut [f32], sign: f32, nw: u16, nh: u16) {
+    for j in 1..nh / 2 + 1 {
+        for i in 1..nw + 1 {
+            let (bottom, top) = (pure_ix_fn(i, j, nw, nh), pure_ix_fn(i, nh + 1 - j, nw, nh));
+            let average = 0.5 * (property[bottom] + sign * property[top]);
+            property[bottom] = average;
+            property[top] = sign * average;
+        }
+    }
+    if sign < 0.0 && nh % 2 == 1 {
+        for i in 1..nw + 1 {
+            property[pure_ix_fn(i, nh / 2 + 1, nw, nh)] = 0.0;
+        }
+    }
+}
+
+// Folds the bit patterns of values into an FNV-1a hash. Bit patterns rather
+// than values, so -0.0 and 0.0 or two NaNs with different payloads differ
+pub fn fnv1a_f32(mut hash: u64, values: &[f32]) -> u64 {
+    const FNV_PRIME: u64 = 0x100_0000_01b3;
+    for value in values {
+        for byte in value.to_bits().to_le_bytes() {
+            hash ^= byte as u64;
+            hash = hash.wrapping_mul(FNV_PRIME);
+        }
+    }
+    hash
+}
+
+// For each of target cells along an axis of n cells, the source cells it
+// covers and how much of each, normalized to sum to 1. Source cells are
+// numbered from 1 like the grid interior
+pub fn box_weights(n: u16, target: u16) -> Vec<Vec<(u16, f32)>> {
+    let scale = n as f32 / target as f32;
+    (0..target)
+        .map(|cell| {
+            let (start, end) = (cell as f32 * scale, (cell + 1) as f32 * scale);
+            let mut weights = Vec::new();
+            for source in start.floor() as u16..cmp::min(end.ceil() as u16, n) {
+                let overlap = (end.min(source as f32 + 1.0) - start.max(source as f32)) / scale;
+                if overlap > 0.0 {
+                    weights.push((source + 1, overlap));
+                }
+            }
+            weights
+        })
+        .collect()
+}
+
+// Gauss-Seidel sweeps a diffusion solve needs to shrink its error by
+// AUTO_ITERATIONS_REDUCTION, between 1 and max. k is the rate dt * diffusion
+// times the mean of the diffusion weights. The slowest error mode shrinks by
+// about (k / (1 + k))^2 per sweep, the square of the Jacobi spectral radius,
+// so that takes ln(reduction) / (2 * ln(k / (1 + k))) sweeps. Weak diffusion
+// converges in a couple of sweeps while strong diffusion runs up to max
+pub fn auto_iterations(k: f32, max: u16) -> u16 {
+    if k <= 0.0 {
+        return 1;
+    }
+    let shrink_per_sweep = 2.0 * (k / (1.0 + k)).ln();
+    let sweeps = (AUTO_ITERATIONS_REDUCTION.ln() / shrink_per_sweep).ceil();
+    cmp::max(cmp::min(sweeps as u16, max), 1)
 }
 
 #[macro_export]
 macro_rules! add_source {
     ($property:expr, $source:expr, $size:expr, $dt:expr) => {
+        add_source!($property, $source, $size, $dt, false);
+    };
+    // Kept sources are added again by the next step
+    ($property:expr, $source:expr, $size:expr, $dt:expr, $keep_source:expr) => {
+        let keep_source: bool = $keep_source;
         for index in 0..$size {
             $property[index] += $dt * $source[index];
-            $source[index] = 0.0;
+            if !keep_source {
+                $source[index] = 0.0;
+            }
         }
     };
 }
@@ -51,99 +787,190 @@ macro_rules! add_source {
 #[macro_export]
 macro_rules! set_bnd {
     ($nw:expr, $nh:expr, $b:expr, $property:expr) => {
-        let max = cmp::max($nw, $nh);
-        for i in 1..(max + 1) {
-            $property[pure_ix_fn(0, i, $nw, $nh)] = match $b {
-                BoundaryType::VERTICAL => -$property[pure_ix_fn(1, i, $nw, $nh)],
-                _ => $property[pure_ix_fn(1, i, $nw, $nh)],
-            };
+        if let BoundaryType::PERIODIC = $b {
+            for j in 1..$nh + 1 {
+                $property[pure_ix_fn(0, j, $nw, $nh)] = $property[pure_ix_fn($nw, j, $nw, $nh)];
+                $property[pure_ix_fn($nw + 1, j, $nw, $nh)] = $property[pure_ix_fn(1, j, $nw, $nh)];
+            }
+            for i in 1..$nw + 1 {
+                $property[pure_ix_fn(i, 0, $nw, $nh)] = $property[pure_ix_fn(i, $nh, $nw, $nh)];
+                $property[pure_ix_fn(i, $nh + 1, $nw, $nh)] = $property[pure_ix_fn(i, 1, $nw, $nh)];
+            }
+            $property[pure_ix_fn(0, 0, $nw, $nh)] = $property[pure_ix_fn($nw, $nh, $nw, $nh)];
+            $property[pure_ix_fn($nw + 1, 0, $nw, $nh)] = $property[pure_ix_fn(1, $nh, $nw, $nh)];
+            $property[pure_ix_fn(0, $nh + 1, $nw, $nh)] = $property[pure_ix_fn($nw, 1, $nw, $nh)];
+            $property[pure_ix_fn($nw + 1, $nh + 1, $nw, $nh)] =
+                $property[pure_ix_fn(1, 1, $nw, $nh)];
+        } else {
+            let max = cmp::max($nw, $nh);
+            for i in 1..(max + 1) {
+                // A border cell of (2 * slip - 1) times its neighbour leaves
+                // slip of the velocity along the wall halfway between them
+                $property[pure_ix_fn(0, i, $nw, $nh)] = match $b {
+                    BoundaryType::VERTICAL | BoundaryType::VERTICAL_SLIP(_) => {
+                        -$property[pure_ix_fn(1, i, $nw, $nh)]
+                    }
+                    BoundaryType::HORIZONTAL_SLIP(slip) => {
+                        (2.0 * slip - 1.0) * $property[pure_ix_fn(1, i, $nw, $nh)]
+                    }
+                    _ => $property[pure_ix_fn(1, i, $nw, $nh)],
+                };
 
-            $property[pure_ix_fn($nw + 1, i, $nw, $nh)] = match $b {
-                BoundaryType::VERTICAL => -$property[pure_ix_fn($nw, i, $nw, $nh)],
-                _ => $property[pure_ix_fn($nw, i, $nw, $nh)],
-            };
+                $property[pure_ix_fn($nw + 1, i, $nw, $nh)] = match $b {
+                    BoundaryType::VERTICAL | BoundaryType::VERTICAL_SLIP(_) => {
+                        -$property[pure_ix_fn($nw, i, $nw, $nh)]
+                    }
+                    BoundaryType::HORIZONTAL_SLIP(slip) => {
+                        (2.0 * slip - 1.0) * $property[pure_ix_fn($nw, i, $nw, $nh)]
+                    }
+                    _ => $property[pure_ix_fn($nw, i, $nw, $nh)],
+                };
 
-            $property[pure_ix_fn(i, 0, $nw, $nh)] = match $b {
-                BoundaryType::HORIZONTAL => -$property[pure_ix_fn(i, 1, $nw, $nh)],
-                _ => $property[pure_ix_fn(i, 1, $nw, $nh)],
-            };
+                $property[pure_ix_fn(i, 0, $nw, $nh)] = match $b {
+                    BoundaryType::HORIZONTAL | BoundaryType::HORIZONTAL_SLIP(_) => {
+                        -$property[pure_ix_fn(i, 1, $nw, $nh)]
+                    }
+                    BoundaryType::VERTICAL_SLIP(slip) => {
+                        (2.0 * slip - 1.0) * $property[pure_ix_fn(i, 1, $nw, $nh)]
+                    }
+                    _ => $property[pure_ix_fn(i, 1, $nw, $nh)],
+                };
 
-            $property[pure_ix_fn(i, $nh + 1, $nw, $nh)] = match $b {
-                BoundaryType::HORIZONTAL => -$property[pure_ix_fn(i, $nh, $nw, $nh)],
-                _ => $property[pure_ix_fn(i, $nh, $nw, $nh)],
-            };
-        }
+                $property[pure_ix_fn(i, $nh + 1, $nw, $nh)] = match $b {
+                    BoundaryType::HORIZONTAL | BoundaryType::HORIZONTAL_SLIP(_) => {
+                        -$property[pure_ix_fn(i, $nh, $nw, $nh)]
+                    }
+                    BoundaryType::VERTICAL_SLIP(slip) => {
+                        (2.0 * slip - 1.0) * $property[pure_ix_fn(i, $nh, $nw, $nh)]
+                    }
+                    _ => $property[pure_ix_fn(i, $nh, $nw, $nh)],
+                };
+            }
 
-        $property[pure_ix_fn(0, 0, $nw, $nh)] =
-            0.5 * ($property[pure_ix_fn(1, 0, $nw, $nh)] + $property[pure_ix_fn(0, 1, $nw, $nh)]);
-        $property[pure_ix_fn(0, $nw + 1, $nw, $nh)] = 0.5
-            * ($property[pure_ix_fn(1, $nw + 1, $nw, $nh)]
-                + $property[pure_ix_fn(0, $nw + 1, $nw, $nh)]);
-        $property[pure_ix_fn($nh + 1, 0, $nw, $nh)] = 0.5
-            * ($property[pure_ix_fn($nh + 1, 0, $nw, $nh)]
-                + $property[pure_ix_fn($nh + 1, 1, $nw, $nh)]);
-        $property[pure_ix_fn($nh + 1, $nw + 1, $nw, $nh)] = 0.5
-            * ($property[pure_ix_fn($nh, $nw + 1, $nw, $nh)]
-                + $property[pure_ix_fn($nh + 1, $nw, $nw, $nh)]);
+            $property[pure_ix_fn(0, 0, $nw, $nh)] = 0.5
+                * ($property[pure_ix_fn(1, 0, $nw, $nh)] + $property[pure_ix_fn(0, 1, $nw, $nh)]);
+            $property[pure_ix_fn(0, $nw + 1, $nw, $nh)] = 0.5
+                * ($property[pure_ix_fn(1, $nw + 1, $nw, $nh)]
+                    + $property[pure_ix_fn(0, $nw + 1, $nw, $nh)]);
+            $property[pure_ix_fn($nh + 1, 0, $nw, $nh)] = 0.5
+                * ($property[pure_ix_fn($nh + 1, 0, $nw, $nh)]
+                    + $property[pure_ix_fn($nh + 1, 1, $nw, $nh)]);
+            $property[pure_ix_fn($nh + 1, $nw + 1, $nw, $nh)] = 0.5
+                * ($property[pure_ix_fn($nh, $nw + 1, $nw, $nh)]
+                    + $property[pure_ix_fn($nh + 1, $nw, $nw, $nh)]);
+        }
     };
 }
 
 #[macro_export]
 macro_rules! advect {
-    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $velocity_x:expr, $velocity_y:expr, $dt:expr) => {
-        let fnw = $nw as f32;
-        let fnh = $nh as f32;
-        for j in 1..$nh + 1 {
-            for i in 1..$nw + 1 {
-                let index = pure_ix_fn(i, j, $nw, $nh) as usize;
+    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $velocity_x:expr, $velocity_y:expr, $dt:expr, $mode:expr, $interpolation:expr) => {
+        advect!(
+            $nw,
+            $nh,
+            $b,
+            $property,
+            $prev_property,
+            $velocity_x,
+            $velocity_y,
+            $dt,
+            $mode,
+            $interpolation,
+            1..$nw + 1,
+            1..$nh + 1
+        );
+    };
+    // Only traces the cells in $columns and $rows, the others keep their value
+    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $velocity_x:expr, $velocity_y:expr, $dt:expr, $mode:expr, $interpolation:expr, $columns:expr, $rows:expr) => {
+        let (columns, rows): (std::ops::Range<u16>, std::ops::Range<u16>) = ($columns, $rows);
+        for j in rows.clone() {
+            for i in columns.clone() {
+                let index = pure_ix_fn(i, j, $nw, $nh);
 
-                let mut inital_pos_x = i as f32 - $velocity_x[pure_ix_fn(i, j, $nw, $nh)] * $dt;
-                let mut inital_pos_y = j as f32 - $velocity_y[pure_ix_fn(i, j, $nw, $nh)] * $dt;
+                let inital_pos_x = i as f32 - $velocity_x[pure_ix_fn(i, j, $nw, $nh)] * $dt;
+                let inital_pos_y = j as f32 - $velocity_y[pure_ix_fn(i, j, $nw, $nh)] * $dt;
 
-                if (inital_pos_x < 0.5) {
-                    inital_pos_x = 0.5;
-                }
+                $property[index] = sample_property_with(
+                    &$prev_property,
+                    inital_pos_x,
+                    inital_pos_y,
+                    $nw,
+                    $nh,
+                    $mode,
+                    $interpolation,
+                );
+            }
+        }
 
-                if (inital_pos_x > fnw + 0.5) {
-                    inital_pos_x = fnw + 0.5;
-                }
+        set_bnd!($nw, $nh, $b, $property);
+    };
+}
 
-                if (inital_pos_y < 0.5) {
-                    inital_pos_y = 0.5;
-                }
+// BFECC advection: advects forward, advects the result back, uses half the
+// round trip error to correct the source and advects the corrected source.
+// The result is clamped to the cells the plain back-trace samples from so the
+// correction cannot overshoot
+#[macro_export]
+macro_rules! bfecc_advect {
+    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $velocity_x:expr, $velocity_y:expr, $dt:expr, $mode:expr, $interpolation:expr) => {
+        let mut forward = $prev_property.clone();
+        advect!(
+            $nw,
+            $nh,
+            $b,
+            forward,
+            $prev_property,
+            $velocity_x,
+            $velocity_y,
+            $dt,
+            $mode,
+            $interpolation
+        );
 
-                if (inital_pos_y > fnh + 0.5) {
-                    inital_pos_y = fnh + 0.5;
-                }
+        let mut backward = $prev_property.clone();
+        advect!(
+            $nw,
+            $nh,
+            $b,
+            backward,
+            forward,
+            $velocity_x,
+            $velocity_y,
+            -$dt,
+            $mode,
+            $interpolation
+        );
 
-                let imaginary_x = inital_pos_x.fract();
-                let imaginary_y = inital_pos_y.fract();
-
-                let point_1_x = inital_pos_x.floor() as u16;
-                let point_1_y = inital_pos_y.floor() as u16;
-
-                let point_2_x = inital_pos_x.ceil() as u16;
-                let point_2_y = inital_pos_y.floor() as u16;
-
-                let point_3_x = inital_pos_x.floor() as u16;
-                let point_3_y = inital_pos_y.ceil() as u16;
-
-                let point_4_x = inital_pos_x.ceil() as u16;
-                let point_4_y = inital_pos_y.ceil() as u16;
-
-                $property[index] = lerp(
-                    lerp(
-                        $prev_property[pure_ix_fn(point_1_x, point_1_y, $nw, $nh)],
-                        $prev_property[pure_ix_fn(point_2_x, point_2_y, $nw, $nh)],
-                        imaginary_x,
-                    ),
-                    lerp(
-                        $prev_property[pure_ix_fn(point_3_x, point_3_y, $nw, $nh)],
-                        $prev_property[pure_ix_fn(point_4_x, point_4_y, $nw, $nh)],
-                        imaginary_x,
-                    ),
-                    imaginary_y,
-                );
+        let corrected: PropertyType = $prev_property
+            .iter()
+            .zip(backward.iter())
+            .map(|(original, round_trip)| original + 0.5 * (original - round_trip))
+            .collect();
+        advect!(
+            $nw,
+            $nh,
+            $b,
+            $property,
+            corrected,
+            $velocity_x,
+            $velocity_y,
+            $dt,
+            $mode,
+            $interpolation
+        );
+
+        for j in 1..$nh + 1 {
+            for i in 1..$nw + 1 {
+                let index = pure_ix_fn(i, j, $nw, $nh);
+                let x = i as f32 - $velocity_x[index] * $dt;
+                let y = j as f32 - $velocity_y[index] * $dt;
+                // Back-traces that read nothing have nothing to limit against
+                if let Some((corners, _, _)) = sample_corners(x, y, $nw, $nh, $mode) {
+                    let corners = corners.map(|corner| $prev_property[corner]);
+                    let min = corners.iter().cloned().fold(f32::INFINITY, f32::min);
+                    let max = corners.iter().cloned().fold(f32::NEG_INFINITY, f32::max);
+                    $property[index] = $property[index].clamp(min, max);
+                }
             }
         }
 
@@ -151,9 +978,10 @@ macro_rules! advect {
     };
 }
 
+// Divergence of the velocity, with the pressure zeroed as the starting guess
 #[macro_export]
-macro_rules! project {
-    ($nw:expr, $nh:expr, $velocity_x:expr, $velocity_y:expr, $poisson_values:expr, $divergence_values:expr) => {
+macro_rules! pressure_divergence {
+    ($nw:expr, $nh:expr, $velocity_x:expr, $velocity_y:expr, $poisson_values:expr, $divergence_values:expr, $scalar_bnd:expr) => {
         for j in 1..$nh + 1 {
             for i in 1..$nw + 1 {
                 let index = pure_ix_fn(i, j, $nw, $nh);
@@ -167,25 +995,15 @@ macro_rules! project {
             }
         }
 
-        set_bnd!($nw, $nh, BoundaryType::NONE, $divergence_values);
-        set_bnd!($nw, $nh, BoundaryType::NONE, $poisson_values);
-
-        for _ in 0..GAUSS_SEIDEL_ITERATIONS {
-            for j in 1..$nh + 1 {
-                for i in 1..$nw + 1 {
-                    let index = pure_ix_fn(i, j, $nw, $nh);
-                    $poisson_values[index] = ($poisson_values[pure_ix_fn(i - 1, j, $nw, $nh)]
-                        + $poisson_values[pure_ix_fn(i + 1, j, $nw, $nh)]
-                        + $poisson_values[pure_ix_fn(i, j - 1, $nw, $nh)]
-                        + $poisson_values[pure_ix_fn(i, j + 1, $nw, $nh)]
-                        - $divergence_values[index])
-                        / 4.0
-                }
-            }
-        }
-
-        set_bnd!($nw, $nh, BoundaryType::NONE, $poisson_values);
+        set_bnd!($nw, $nh, $scalar_bnd, $divergence_values);
+        set_bnd!($nw, $nh, $scalar_bnd, $poisson_values);
+    };
+}
 
+// Removes the solved pressure's gradient from the velocity
+#[macro_export]
+macro_rules! subtract_pressure_gradient {
+    ($nw:expr, $nh:expr, $velocity_x:expr, $velocity_y:expr, $poisson_values:expr, $x_bnd:expr, $y_bnd:expr) => {
         for j in 1..$nh + 1 {
             for i in 1..$nw + 1 {
                 let index = pure_ix_fn(i, j, $nw, $nh);
@@ -197,27 +1015,124 @@ macro_rules! project {
                     * 0.5;
             }
         }
-        set_bnd!($nw, $nh, BoundaryType::VERTICAL, $velocity_x);
-        set_bnd!($nw, $nh, BoundaryType::HORIZONTAL, $velocity_y);
+        set_bnd!($nw, $nh, $x_bnd, $velocity_x);
+        set_bnd!($nw, $nh, $y_bnd, $velocity_y);
     };
 }
 
 #[macro_export]
-macro_rules! diffuse {
-    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $diffusion:expr, $dt:expr) => {
-        let k = $dt * $diffusion;
+macro_rules! project {
+    ($nw:expr, $nh:expr, $velocity_x:expr, $velocity_y:expr, $poisson_values:expr, $divergence_values:expr, $omega:expr, $periodic:expr) => {
+        let periodic: bool = $periodic;
+        let (scalar_bnd, x_bnd, y_bnd) = projection_boundaries(periodic);
+
+        pressure_divergence!(
+            $nw,
+            $nh,
+            $velocity_x,
+            $velocity_y,
+            $poisson_values,
+            $divergence_values,
+            scalar_bnd
+        );
+
         for _ in 0..GAUSS_SEIDEL_ITERATIONS {
             for j in 1..$nh + 1 {
                 for i in 1..$nw + 1 {
-                    let index = pure_ix_fn(i, j, $nw, $nh) as usize;
+                    let index = pure_ix_fn(i, j, $nw, $nh);
+                    let value = ($poisson_values[pure_ix_fn(i - 1, j, $nw, $nh)]
+                        + $poisson_values[pure_ix_fn(i + 1, j, $nw, $nh)]
+                        + $poisson_values[pure_ix_fn(i, j - 1, $nw, $nh)]
+                        + $poisson_values[pure_ix_fn(i, j + 1, $nw, $nh)]
+                        - $divergence_values[index])
+                        / 4.0;
+                    $poisson_values[index] =
+                        (1.0 - $omega) * $poisson_values[index] + $omega * value;
+                }
+            }
+
+            // Without walls the pressure is only defined up to a constant, so
+            // the solve is free to drift. Pin it by removing the mean, and
+            // wrap the border every sweep so the edges see each other
+            if periodic {
+                remove_interior_mean(&mut $poisson_values, $nw, $nh);
+                set_bnd!($nw, $nh, scalar_bnd, $poisson_values);
+            }
+        }
+
+        set_bnd!($nw, $nh, scalar_bnd, $poisson_values);
 
-                    $property[index] = ($prev_property[index]
-                        + (k * ($property[pure_ix_fn(i + 1, j, $nw, $nh) as usize]
-                            + $property[pure_ix_fn(i - 1, j, $nw, $nh) as usize]
-                            + $property[pure_ix_fn(i, j + 1, $nw, $nh) as usize]
-                            + $property[pure_ix_fn(i, j - 1, $nw, $nh) as usize]))
+        subtract_pressure_gradient!(
+            $nw,
+            $nh,
+            $velocity_x,
+            $velocity_y,
+            $poisson_values,
+            x_bnd,
+            y_bnd
+        );
+    };
+}
+
+#[macro_export]
+macro_rules! diffuse {
+    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $diffusion:expr, $dt:expr, $omega:expr, $weights:expr) => {
+        diffuse!(
+            $nw,
+            $nh,
+            $b,
+            $property,
+            $prev_property,
+            $diffusion,
+            $dt,
+            $omega,
+            $weights,
+            None::<&[f32]>,
+            GAUSS_SEIDEL_ITERATIONS as u16
+        );
+    };
+    // A diffusion field gives every cell its own coefficient in place of
+    // $diffusion, and $iterations replaces the fixed sweep count
+    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $diffusion:expr, $dt:expr, $omega:expr, $weights:expr, $diffusion_field:expr, $iterations:expr) => {
+        diffuse!(
+            $nw,
+            $nh,
+            $b,
+            $property,
+            $prev_property,
+            $diffusion,
+            $dt,
+            $omega,
+            $weights,
+            $diffusion_field,
+            $iterations,
+            1..$nw + 1,
+            1..$nh + 1
+        );
+    };
+    // Only sweeps the cells in $columns and $rows, the others keep their value
+    ($nw:expr, $nh:expr, $b:expr, $property:expr, $prev_property:expr, $diffusion:expr, $dt:expr, $omega:expr, $weights:expr, $diffusion_field:expr, $iterations:expr, $columns:expr, $rows:expr) => {
+        let (columns, rows): (std::ops::Range<u16>, std::ops::Range<u16>) = ($columns, $rows);
+        let diffusion: f32 = $diffusion;
+        let diffusion_field: Option<&[f32]> = $diffusion_field;
+        // Weights of the horizontal and vertical neighbours, (1, 1) diffuses
+        // the same way in every direction
+        let (wx, wy): (f32, f32) = $weights;
+        for _ in 0..$iterations {
+            for j in rows.clone() {
+                for i in columns.clone() {
+                    let index = pure_ix_fn(i, j, $nw, $nh);
+                    let k = $dt * diffusion_field.map_or(diffusion, |field| field[index]);
+
+                    let value = ($prev_property[index]
+                        + (k * (wx
+                            * ($property[pure_ix_fn(i + 1, j, $nw, $nh)]
+                                + $property[pure_ix_fn(i - 1, j, $nw, $nh)])
+                            + wy * ($property[pure_ix_fn(i, j + 1, $nw, $nh)]
+                                + $property[pure_ix_fn(i, j - 1, $nw, $nh)])))
                             / 4.0)
-                        / (1.0 + k)
+                        / (1.0 + k * (wx + wy) / 2.0);
+                    $property[index] = (1.0 - $omega) * $property[index] + $omega * value;
                 }
             }
 
@@ -225,3 +1140,69 @@ macro_rules! diffuse {
         }
     };
 }
+
+// Largest remaining error of the diffusion system solved by diffuse
+#[macro_export]
+macro_rules! diffuse_residual {
+    ($nw:expr, $nh:expr, $property:expr, $prev_property:expr, $diffusion:expr, $dt:expr, $weights:expr, $diffusion_field:expr) => {{
+        let diffusion: f32 = $diffusion;
+        let diffusion_field: Option<&[f32]> = $diffusion_field;
+        let (wx, wy): (f32, f32) = $weights;
+        let mut residual: f32 = 0.0;
+        for j in 1..$nh + 1 {
+            for i in 1..$nw + 1 {
+                let index = pure_ix_fn(i, j, $nw, $nh);
+                let k = $dt * diffusion_field.map_or(diffusion, |field| field[index]);
+                let neighbours = wx
+                    * ($property[pure_ix_fn(i + 1, j, $nw, $nh)]
+                        + $property[pure_ix_fn(i - 1, j, $nw, $nh)])
+                    + wy * ($property[pure_ix_fn(i, j + 1, $nw, $nh)]
+                        + $property[pure_ix_fn(i, j - 1, $nw, $nh)]);
+                let error = (1.0 + k * (wx + wy) / 2.0) * $property[index]
+                    - k * neighbours / 4.0
+                    - $prev_property[index];
+                residual = residual.max(error.abs());
+            }
+        }
+        residual
+    }};
+}
+
+// Largest remaining error of the pressure poisson equation solved by project
+#[macro_export]
+macro_rules! poisson_residual {
+    ($nw:expr, $nh:expr, $poisson_values:expr, $divergence_values:expr) => {{
+        let mut residual: f32 = 0.0;
+        for j in 1..$nh + 1 {
+            for i in 1..$nw + 1 {
+                let index = pure_ix_fn(i, j, $nw, $nh);
+                let neighbours = $poisson_values[pure_ix_fn(i - 1, j, $nw, $nh)]
+                    + $poisson_values[pure_ix_fn(i + 1, j, $nw, $nh)]
+                    + $poisson_values[pure_ix_fn(i, j - 1, $nw, $nh)]
+                    + $poisson_values[pure_ix_fn(i, j + 1, $nw, $nh)];
+                let error = neighbours - 4.0 * $poisson_values[index] - $divergence_values[index];
+                residual = residual.max(error.abs());
+            }
+        }
+        residual
+    }};
+}
+
+// Replaces every interior cell with the average of its 3x3 neighbourhood. The
+// border is refreshed afterwards so edge cells average against copies of
+// themselves rather than stale values
+pub fn box_blur(property: &mut PropertyType, b: BoundaryType, nw: u16, nh: u16) {
+    let source = property.clone();
+    for j in 1..nh + 1 {
+        for i in 1..nw + 1 {
+            let mut sum = 0.0;
+            for y in j - 1..=j + 1 {
+                for x in i - 1..=i + 1 {
+                    sum += source[pure_ix_fn(x, y, nw, nh)];
+                }
+            }
+            property[pure_ix_fn(i, j, nw, nh)] = sum / 9.0;
+        }
+    }
+    set_bnd!(nw, nh, b, property);
+}
@@ -3,6 +3,12 @@
 #![cfg(target_arch = "wasm32")]
 
 extern crate wasm_bindgen_test;
+use smoke_rust::{
+    replay, AdvectionScheme, BlendMode, BoundaryMode, ConjugateGradient, Field, Fluid, FluidConfig,
+    FluidStatic, FrameReader, GaussSeidel, IncompressibilityMethod, InterpolationKind,
+    LinearSystem, Recorder, SampleMode, Side, SolidAdvection, Solver, SolverKind,
+    SourceApplication, Stepper, Symmetry, ToneMap,
+};
 use wasm_bindgen_test::*;
 
 wasm_bindgen_test_configure!(run_in_browser);
@@ -11,3 +17,2228 @@ wasm_bindgen_test_configure!(run_in_browser);
 fn pass() {
     assert_eq!(1 + 1, 2);
 }
+
+// Solves the diffusion system for a single source with plain Gauss-Seidel run
+// to convergence, copying edge cells into the border like set_bnd does
+fn reference_diffusion(n: usize, k: f32, center: (usize, usize), source: f32) -> Vec<f32> {
+    let stride = n + 2;
+    let mut x = vec![0.0; stride * stride];
+    for _ in 0..2000 {
+        for j in 1..=n {
+            for i in 1..=n {
+                let b = if (i, j) == center { source } else { 0.0 };
+                let neighbours = x[(i - 1) + j * stride]
+                    + x[(i + 1) + j * stride]
+                    + x[i + (j - 1) * stride]
+                    + x[i + (j + 1) * stride];
+                x[i + j * stride] = (b + k * neighbours / 4.0) / (1.0 + k);
+            }
+        }
+        for i in 1..=n {
+            x[i] = x[i + stride];
+            x[i + (n + 1) * stride] = x[i + n * stride];
+            x[i * stride] = x[1 + i * stride];
+            x[(n + 1) + i * stride] = x[n + i * stride];
+        }
+    }
+    x
+}
+
+fn diffusion_error(configure: impl Fn(&mut FluidConfig)) -> f32 {
+    let (n, dt, diffusion) = (16, 1.0, 50.0);
+    let mut config = FluidConfig::new(n, n, diffusion);
+    configure(&mut config);
+    let mut fluid = Fluid::new(config, dt);
+    fluid.add_density(fluid.ix(n / 2, n / 2), 100.0);
+    fluid.simulate();
+
+    // With no velocity a single step leaves the diffused source in density
+    let n = n as usize;
+    let reference = reference_diffusion(n, dt * diffusion, (n / 2, n / 2), dt * 100.0);
+    let mut error: f32 = 0.0;
+    for y in 1..=n {
+        for x in 1..=n {
+            let value = fluid.get_density_at_index(fluid.ix(x as u16, y as u16));
+            error = error.max((value - reference[x + y * (n + 2)]).abs());
+        }
+    }
+    error
+}
+
+#[wasm_bindgen_test]
+fn over_relaxation_converges_faster() {
+    assert!(diffusion_error(|config| config.set_omega(1.5)) < diffusion_error(|_| ()));
+}
+
+#[wasm_bindgen_test]
+fn auto_iterations_follow_the_diffusion_rate() {
+    let fixed = diffusion_error(|_| ());
+    let auto = diffusion_error(|config| config.set_auto_iterations(1000));
+    assert!(auto < fixed / 10.0);
+    // The cap still bounds the work
+    let capped = diffusion_error(|config| config.set_auto_iterations(1));
+    assert!(capped > fixed);
+}
+
+#[wasm_bindgen_test]
+fn ix_does_not_wrap_on_large_grids() {
+    let n = 200;
+    let fluid = Fluid::new(FluidConfig::new(n, n, 0.0), 0.1);
+    assert_eq!(fluid.ix(n + 1, n + 1), fluid.get_size() - 1);
+    assert_eq!(fluid.get_size(), (n as usize + 2) * (n as usize + 2));
+}
+
+#[wasm_bindgen_test]
+fn partial_steps_match_simulate() {
+    let n = 24;
+    let setup = || {
+        let mut fluid = Fluid::new(FluidConfig::new(n, n, 0.5), 0.5);
+        fluid.add_density(fluid.ix(12, 12), 20.0);
+        fluid.add_velocity(fluid.ix(12, 12), 30.0, -10.0);
+        fluid
+    };
+
+    let mut whole = setup();
+    let mut partial = setup();
+    for _ in 0..3 {
+        whole.simulate();
+        partial.begin_step();
+        while !partial.step_partial(n) {}
+    }
+
+    assert_eq!(
+        whole.get_density_expensive(),
+        partial.get_density_expensive()
+    );
+    assert_eq!(
+        whole.get_velocity_x_expensive(),
+        partial.get_velocity_x_expensive()
+    );
+    assert_eq!(
+        whole.get_velocity_y_expensive(),
+        partial.get_velocity_y_expensive()
+    );
+}
+
+#[wasm_bindgen_test]
+fn dissipation_does_not_depend_on_step_size() {
+    let decay_with_steps = |dt: f32, steps: u32| {
+        let mut config = FluidConfig::new(8, 8, 0.0);
+        config.set_dissipation(0.5);
+        let mut fluid = Fluid::new(config, 0.5);
+        let index = fluid.ix(4, 4);
+        fluid.add_density(index, 10.0);
+        fluid.simulate();
+        fluid.set_dt(dt);
+        for _ in 0..steps {
+            fluid.simulate();
+        }
+        fluid.get_density_at_index(index)
+    };
+
+    let coarse = decay_with_steps(0.5, 2);
+    let fine = decay_with_steps(0.25, 4);
+    assert!((coarse - fine).abs() < 1e-5);
+    assert!((coarse - 5.0 * 0.5_f32.powf(1.5)).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn diffusion_step_spreads_a_spike() {
+    let mut fluid = Fluid::new(FluidConfig::new(9, 9, 1.0), 1.0);
+    let center = fluid.ix(5, 5);
+    fluid.add_density(center, 10.0);
+    fluid.step_diffuse_density();
+
+    // The diffused field is swapped into the buffer advection reads from, so
+    // advect it through the still velocity field to read it back
+    fluid.step_advect_density();
+    let peak = fluid.get_density_at_index(center);
+    assert!(peak > 0.0 && peak < 10.0);
+    for (x, y) in [(4, 5), (6, 5), (5, 4), (5, 6)] {
+        let neighbour = fluid.get_density_at_index(fluid.ix(x, y));
+        assert!(neighbour > 0.0 && neighbour < peak);
+    }
+}
+
+#[wasm_bindgen_test]
+fn diffusion_weights_favour_one_direction() {
+    let spread = |weight_x: f32, weight_y: f32| {
+        let mut config = FluidConfig::new(9, 9, 1.0);
+        config.set_diffusion_weights(weight_x, weight_y);
+        let mut fluid = Fluid::new(config, 1.0);
+        fluid.add_density(fluid.ix(5, 5), 10.0);
+        fluid.step_diffuse_density();
+        fluid.step_advect_density();
+        (
+            fluid.get_density_at_index(fluid.ix(6, 5)),
+            fluid.get_density_at_index(fluid.ix(5, 6)),
+        )
+    };
+
+    let (across, up) = spread(1.0, 1.0);
+    assert!((across - up).abs() < 1e-6);
+    let (across, up) = spread(2.0, 1.0);
+    assert!(across > up && up > 0.0);
+    // Without a vertical weight the spike only spreads along its row
+    let (across, up) = spread(1.0, 0.0);
+    assert!(across > 0.0);
+    assert_eq!(up, 0.0);
+}
+
+#[wasm_bindgen_test]
+fn periodic_projection_stays_bounded() {
+    let n = 16;
+    let mut config = FluidConfig::new(n, n, 0.1);
+    config.set_boundary_mode(BoundaryMode::Periodic);
+    let mut fluid = Fluid::new(config, 0.5);
+    fluid.set_residual_tolerance(f32::MAX);
+    for step in 0..100 {
+        if step < 10 {
+            fluid.add_velocity(fluid.ix(n, n / 2), 20.0, 5.0);
+            fluid.add_density(fluid.ix(n, n / 2), 10.0);
+        }
+        fluid.simulate();
+    }
+
+    assert!(fluid.last_projection_residual().is_finite());
+    assert!(fluid.last_projection_residual() < 1.0);
+    for index in 0..fluid.get_size() {
+        assert!(fluid.get_velocity_x(index).is_finite());
+        assert!(fluid.get_velocity_y(index).is_finite());
+    }
+
+    // Flow pushed out of the right edge comes back in on the left
+    assert!(fluid.get_density_at_index(fluid.ix(1, n / 2)) > 1e-3);
+}
+
+#[wasm_bindgen_test]
+fn gauss_seidel_solver_matches_built_in() {
+    let n = 16;
+    let run = |custom: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(n, n, 0.1), 0.5);
+        if custom {
+            fluid.set_solver(Box::new(GaussSeidel::default()));
+        }
+        for _ in 0..5 {
+            fluid.add_velocity(fluid.ix(n / 2, n / 2), 10.0, -5.0);
+            fluid.add_density(fluid.ix(n / 2, n / 2), 10.0);
+            fluid.simulate();
+        }
+        (0..fluid.get_size())
+            .map(|index| fluid.get_density_at_index(index))
+            .collect::<Vec<f32>>()
+    };
+
+    let built_in = run(false);
+    let custom = run(true);
+    for (a, b) in built_in.iter().zip(custom.iter()) {
+        assert!((a - b).abs() < 1e-3);
+    }
+}
+
+#[wasm_bindgen_test]
+fn conjugate_gradient_reduces_divergence_faster() {
+    let n = 64;
+    let mut divergence = vec![0.0; (n as usize + 2) * (n as usize + 2)];
+    for j in 1..n + 1 {
+        for i in 1..n + 1 {
+            divergence[i as usize + (n as usize + 2) * j as usize] =
+                ((i * 7 + j * 13) % 11) as f32 - 5.0;
+        }
+    }
+    let system = LinearSystem::poisson(n, n, &divergence, false);
+    let initial = system.residual(&system.initial);
+
+    // A preconditioned conjugate gradient iteration costs about three
+    // Gauss-Seidel sweeps, so compare at the same amount of work
+    let conjugate_gradient = ConjugateGradient { iterations: 30 }.solve(&system);
+    let gauss_seidel = GaussSeidel {
+        iterations: 90,
+        omega: 1.0,
+    }
+    .solve(&system);
+
+    let conjugate_gradient = system.residual(&conjugate_gradient) / initial;
+    let gauss_seidel = system.residual(&gauss_seidel) / initial;
+    assert!(conjugate_gradient < gauss_seidel / 5.0);
+}
+
+#[wasm_bindgen_test]
+fn closed_box_conserves_mass() {
+    let n = 24;
+    let mut config = FluidConfig::new(n, n, 0.001);
+    config.set_conserve_mass(true);
+    let mut fluid = Fluid::new(config, 0.5);
+    let mass = |fluid: &Fluid| {
+        let mut total = 0.0;
+        for y in 1..n + 1 {
+            for x in 1..n + 1 {
+                total += fluid.get_density_at_index(fluid.ix(x, y));
+            }
+        }
+        total
+    };
+
+    let source = fluid.ix(n - 2, n / 2);
+    fluid.add_density(source, 20.0);
+    fluid.simulate();
+    fluid.add_density(source, 0.0);
+    let initial = mass(&fluid);
+
+    // Keep pushing the density into the right wall
+    let stirrer = fluid.ix(n - 4, n / 2);
+    fluid.add_velocity(stirrer, 30.0, 10.0);
+    for step in 0..300 {
+        if step == 50 {
+            fluid.add_velocity(stirrer, 0.0, 0.0);
+        }
+        fluid.simulate();
+    }
+    assert!((mass(&fluid) - initial).abs() < initial * 1e-3);
+}
+
+#[wasm_bindgen_test]
+fn static_fluid_matches_fluid() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.01), 0.5);
+    let mut fluid_static = FluidStatic::<16, { 18 * 18 }>::new(0.01, 0.5);
+    for step in 0..20 {
+        if step < 5 {
+            fluid.add_velocity(fluid.ix(8, 8), 10.0, -4.0);
+            fluid_static.add_velocity(fluid_static.ix(8, 8), 10.0, -4.0);
+            fluid.add_density(fluid.ix(8, 8), 5.0);
+            fluid_static.add_density(fluid_static.ix(8, 8), 5.0);
+        }
+        fluid.simulate();
+        fluid_static.simulate();
+    }
+
+    for index in 0..fluid.get_size() {
+        assert_eq!(
+            fluid.get_density_at_index(index),
+            fluid_static.get_density_at_index(index)
+        );
+        assert_eq!(
+            fluid.get_velocity_x(index),
+            fluid_static.get_velocity_x(index)
+        );
+    }
+}
+
+// Advects a density spike at (8, 8) for one step through a uniform field
+// pointing right. Diffusion is off, so the diffusion stages only move the
+// density between buffers: the second one brings the advected field back into
+// the one get_density_at_index reads
+fn advect_spike(velocity_x: f32, dt: f32) -> Fluid {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), dt);
+    let size = fluid.get_size();
+    fluid.set_advection_velocity(vec![velocity_x; size], vec![0.0; size]);
+    fluid.add_density(fluid.ix(8, 8), 10.0 / dt);
+    fluid.step_diffuse_density();
+    fluid.step_advect_density();
+    fluid.step_diffuse_density();
+    fluid
+}
+
+#[wasm_bindgen_test]
+fn advection_moves_a_spike_by_velocity_times_dt() {
+    let fluid = advect_spike(4.0, 0.5);
+    assert!((fluid.get_density_at_index(fluid.ix(10, 8)) - 10.0).abs() < 1e-4);
+    assert!(fluid.get_density_at_index(fluid.ix(8, 8)).abs() < 1e-4);
+
+    // Between cells the spike is split bilinearly, which keeps its center
+    let fluid = advect_spike(1.5, 1.0);
+    let (mut mass, mut moment) = (0.0, 0.0);
+    for x in 1..17 {
+        let density = fluid.get_density_at_index(fluid.ix(x, 8));
+        mass += density;
+        moment += density * x as f32;
+    }
+    assert!((mass - 10.0).abs() < 1e-4);
+    assert!((moment / mass - 9.5).abs() < 1e-4);
+    assert!((fluid.get_density_at_index(fluid.ix(9, 8)) - 5.0).abs() < 1e-4);
+    assert!((fluid.get_density_at_index(fluid.ix(10, 8)) - 5.0).abs() < 1e-4);
+}
+
+#[wasm_bindgen_test]
+fn advection_through_still_fluid_changes_nothing() {
+    let fluid = advect_spike(0.0, 1.0);
+    for index in 0..fluid.get_size() {
+        let expected = if index == fluid.ix(8, 8) { 10.0 } else { 0.0 };
+        assert_eq!(fluid.get_density_at_index(index), expected);
+    }
+}
+
+// A fluid holding a single density spike at (1, 8), with the sample mode set.
+// The second diffusion stage brings the spike, with its border filled in,
+// into the buffer sample_density reads
+fn edge_spike(mode: SampleMode) -> Fluid {
+    let mut config = FluidConfig::new(16, 16, 0.0);
+    config.set_sample_mode(mode);
+    let mut fluid = Fluid::new(config, 1.0);
+    fluid.add_density(fluid.ix(1, 8), 10.0);
+    fluid.step_diffuse_density();
+    fluid.step_diffuse_density();
+    fluid
+}
+
+#[wasm_bindgen_test]
+fn clamp_sampling_sticks_to_the_edge() {
+    let fluid = edge_spike(SampleMode::Clamp);
+    assert!((fluid.sample_density(-5.0, 8.0) - 10.0).abs() < 1e-5);
+    assert!(fluid.sample_density(30.0, 8.0).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn wrap_sampling_reenters_from_the_opposite_edge() {
+    let fluid = edge_spike(SampleMode::Wrap);
+    assert!((fluid.sample_density(17.0, 8.0) - 10.0).abs() < 1e-5);
+    assert!((fluid.sample_density(16.5, 8.0) - 5.0).abs() < 1e-5);
+    assert!((fluid.sample_density(-15.0, 8.0) - 10.0).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn zero_sampling_reads_nothing_outside() {
+    let fluid = edge_spike(SampleMode::Zero);
+    assert_eq!(fluid.sample_density(-5.0, 8.0), 0.0);
+    assert_eq!(fluid.sample_density(1.0, 30.0), 0.0);
+    assert!((fluid.sample_density(1.0, 8.0) - 10.0).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn wrapped_advection_carries_density_across_the_edge() {
+    let mut config = FluidConfig::new(16, 16, 0.0);
+    config.set_sample_mode(SampleMode::Wrap);
+    let mut fluid = Fluid::new(config, 1.0);
+    let size = fluid.get_size();
+    fluid.set_advection_velocity(vec![2.0; size], vec![0.0; size]);
+    fluid.add_density(fluid.ix(16, 8), 10.0);
+    fluid.step_diffuse_density();
+    fluid.step_advect_density();
+    fluid.step_diffuse_density();
+    assert!((fluid.get_density_at_index(fluid.ix(2, 8)) - 10.0).abs() < 1e-5);
+
+    // With Zero the cells the flow enters from start out empty
+    let mut config = FluidConfig::new(16, 16, 0.0);
+    config.set_sample_mode(SampleMode::Zero);
+    let mut fluid = Fluid::new(config, 1.0);
+    fluid.set_advection_velocity(vec![2.0; size], vec![0.0; size]);
+    fluid.add_density(fluid.ix(1, 8), 10.0);
+    fluid.step_diffuse_density();
+    fluid.step_advect_density();
+    fluid.step_diffuse_density();
+    assert_eq!(fluid.get_density_at_index(fluid.ix(1, 8)), 0.0);
+    assert!((fluid.get_density_at_index(fluid.ix(3, 8)) - 10.0).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn inspect_reports_steps_and_mass() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 1.0);
+    assert_eq!(fluid.inspect().get_step_count(), 0);
+    fluid.add_density(fluid.ix(8, 8), 10.0);
+    fluid.add_velocity(fluid.ix(8, 8), 1.0, 0.0);
+    fluid.simulate_steps(3);
+
+    let state = fluid.inspect();
+    assert_eq!(state.get_step_count(), 3);
+    assert_eq!(state.get_dt(), 1.0);
+    assert!(state.get_mass() > 0.0);
+    assert!(state.get_energy() > 0.0);
+    assert!(state.get_max_speed() > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn bicubic_sampling_reproduces_a_ramp() {
+    let mut config = FluidConfig::new(16, 16, 0.0);
+    config.set_interpolation(InterpolationKind::Bicubic);
+    let mut fluid = Fluid::new(config, 1.0);
+    for y in 1..17 {
+        for x in 1..17 {
+            fluid.add_density(fluid.ix(x, y), x as f32);
+        }
+    }
+    fluid.step_diffuse_density();
+    fluid.step_diffuse_density();
+
+    assert!((fluid.sample_density(5.3, 7.6) - 5.3).abs() < 1e-4);
+    assert!((fluid.sample_density(9.0, 2.5) - 9.0).abs() < 1e-4);
+    // Points next to the edge read clamped taps
+    assert!(fluid.sample_density(1.2, 8.0).is_finite());
+}
+
+#[wasm_bindgen_test]
+fn initial_grids_round_trip() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 3, 0.0), 1.0);
+    let values: Vec<f32> = (0..12).map(|value| value as f32).collect();
+    fluid.set_initial_density_grid(&values);
+    fluid.set_initial_velocity_x_grid(&values);
+    assert_eq!(fluid.get_initial_density_grid(), values);
+    assert_eq!(fluid.get_initial_velocity_x_grid(), values);
+    assert_eq!(fluid.get_initial_velocity_y_grid(), vec![0.0; 12]);
+
+    // The next step continues from the initial buffers
+    fluid.step_diffuse_density();
+    assert_eq!(fluid.get_density_at_index(fluid.ix(2, 1)), 1.0);
+}
+
+#[wasm_bindgen_test]
+fn symmetry_keeps_a_centered_source_mirrored() {
+    let n = 16;
+    let mut config = FluidConfig::new(n, n, 0.01);
+    config.set_symmetry(Symmetry::Horizontal);
+    let mut fluid = Fluid::new(config, 0.5);
+    for _ in 0..30 {
+        for x in [n / 2, n / 2 + 1] {
+            fluid.add_density(fluid.ix(x, 4), 10.0);
+            fluid.add_velocity(fluid.ix(x, 4), 0.0, 8.0);
+        }
+        fluid.simulate();
+    }
+
+    for y in 1..n + 1 {
+        for x in 1..n / 2 + 1 {
+            let (left, right) = (fluid.ix(x, y), fluid.ix(n + 1 - x, y));
+            assert_eq!(
+                fluid.get_density_at_index(left),
+                fluid.get_density_at_index(right)
+            );
+            assert_eq!(fluid.get_velocity_x(left), -fluid.get_velocity_x(right));
+        }
+    }
+}
+
+// Modified Bessel function of the first kind of order zero
+fn bessel_i0(x: f64) -> f64 {
+    let mut term = 1.0;
+    let mut sum = 1.0;
+    for k in 1..30 {
+        term *= (x / 2.0) * (x / 2.0) / (k * k) as f64;
+        sum += term;
+    }
+    sum
+}
+
+// Error of the diffused peak of a unit spike against the exact solution of
+// the diffusion the solver discretizes, du/dt = diffusion / 4 * laplacian(u).
+// Per axis that is e^(-2at) * I0(2at) at the center with a = diffusion / 4
+fn substep_peak_error(substeps: u16) -> f64 {
+    let (n, diffusion, dt) = (31, 1.0, 2.0);
+    let mut config = FluidConfig::new(n, n, diffusion);
+    config.set_diffusion_substeps(substeps);
+    let mut fluid = Fluid::new(config, dt);
+    fluid.add_density(fluid.ix(16, 16), 1.0 / dt);
+    fluid.step_diffuse_density();
+    let peak = fluid.get_initial_density_grid()[15 * n as usize + 15] as f64;
+
+    let x = 2.0 * (diffusion as f64 / 4.0) * dt as f64;
+    let exact = ((-x).exp() * bessel_i0(x)).powi(2);
+    (peak - exact).abs()
+}
+
+#[wasm_bindgen_test]
+fn diffusion_substeps_approach_the_exact_solution() {
+    let one = substep_peak_error(1);
+    let four = substep_peak_error(4);
+    let sixteen = substep_peak_error(16);
+    // Each substep is a backward Euler step, so the error shrinks about as
+    // fast as the substep does
+    assert!(four < one / 2.0);
+    assert!(sixteen < four / 2.0);
+    assert!(sixteen < 0.02);
+}
+
+#[wasm_bindgen_test]
+fn replay_reproduces_a_recorded_run() {
+    let mut recorder = Recorder::new();
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.01), 0.5);
+    let center = fluid.ix(8, 8);
+    for step in 0..10 {
+        if step % 3 == 0 {
+            recorder.add_density(&mut fluid, center, 10.0);
+            recorder.add_velocity(&mut fluid, center, 4.0, -2.0);
+        }
+        if step == 5 {
+            recorder.set_dt(&mut fluid, 0.25);
+        }
+        recorder.simulate(&mut fluid);
+    }
+
+    let mut replayed = Fluid::new(FluidConfig::new(16, 16, 0.01), 0.5);
+    replay(&mut replayed, &recorder.to_json()).unwrap();
+    for index in 0..fluid.get_size() {
+        assert_eq!(
+            fluid.get_density_at_index(index),
+            replayed.get_density_at_index(index)
+        );
+        assert_eq!(fluid.get_velocity_x(index), replayed.get_velocity_x(index));
+    }
+    assert!(replay(&mut replayed, "not json").is_err());
+}
+
+#[wasm_bindgen_test]
+fn downsampling_averages_boxes() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 3, 0.0), 1.0);
+    let values: Vec<f32> = (0..12).map(|value| value as f32).collect();
+    fluid.set_initial_density_grid(&values);
+    fluid.step_diffuse_density();
+
+    assert_eq!(
+        fluid.density_downsampled(2, 3),
+        vec![0.5, 2.5, 4.5, 6.5, 8.5, 10.5]
+    );
+    assert_eq!(fluid.density_downsampled(1, 1), vec![5.5]);
+    // Three columns into two splits the middle one between them
+    let mut fluid = Fluid::new(FluidConfig::new(3, 1, 0.0), 1.0);
+    fluid.set_initial_density_grid(&[0.0, 3.0, 6.0]);
+    fluid.step_diffuse_density();
+    let halves = fluid.density_downsampled(2, 1);
+    assert!((halves[0] - 1.0).abs() < 1e-5 && (halves[1] - 5.0).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn out_of_range_sources_are_ignored() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 0.1);
+    let size = fluid.get_size();
+
+    assert!(fluid.add_density(size - 1, 1.0));
+    assert!(!fluid.add_density(size, 1.0));
+    assert!(!fluid.add_density(usize::MAX, 1.0));
+    assert!(fluid.add_velocity(size - 1, 1.0, 1.0));
+    assert!(!fluid.add_velocity(size, 1.0, 1.0));
+    assert!(!fluid.add_velocity(usize::MAX, 1.0, 1.0));
+    assert!(fluid.add_density_smooth(size - 1, 1.0, 1.0));
+    assert!(!fluid.add_density_smooth(size, 1.0, 1.0));
+    assert!(!fluid.add_density_smooth(usize::MAX, 1.0, 1.0));
+    fluid.simulate();
+}
+
+#[wasm_bindgen_test]
+fn diffusion_schedule_interpolates_keyframes() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.5), 0.1);
+    fluid.set_diffusion_schedule(&[10, 20], &[1.0, 3.0]);
+
+    // Holds the first value before the first keyframe
+    fluid.simulate_steps(5);
+    assert_eq!(fluid.get_config().get_diffusion(), 1.0);
+    // The diffusion of step 15 was set when it started
+    fluid.simulate_steps(11);
+    assert!((fluid.get_config().get_diffusion() - 2.0).abs() < 1e-6);
+    fluid.simulate_steps(10);
+    assert_eq!(fluid.get_config().get_diffusion(), 3.0);
+
+    fluid.clear_diffusion_schedule();
+    fluid.set_config_diffusion(0.5);
+    fluid.simulate();
+    assert_eq!(fluid.get_config().get_diffusion(), 0.5);
+}
+
+#[wasm_bindgen_test]
+fn diffusion_field_blocks_spreading() {
+    let n = 8;
+    let mut fluid = Fluid::new(FluidConfig::new(n, n, 5.0), 0.1);
+    // A wall of zero diffusion between columns 4 and 5
+    for y in 1..=n {
+        fluid.set_diffusion_at(fluid.ix(4, y), 0.0);
+        fluid.set_diffusion_at(fluid.ix(5, y), 0.0);
+    }
+    fluid.add_density(fluid.ix(2, 4), 100.0);
+    fluid.simulate();
+
+    assert!(fluid.get_density_at_index(fluid.ix(3, 4)) > 0.0);
+    assert_eq!(fluid.get_density_at_index(fluid.ix(5, 4)), 0.0);
+    assert_eq!(fluid.get_density_at_index(fluid.ix(7, 4)), 0.0);
+
+    // Cells without a value of their own follow a change of the configured
+    // diffusion
+    fluid.set_config_diffusion(0.0);
+    let density = fluid.get_initial_density_grid();
+    fluid.simulate();
+    assert_eq!(fluid.get_initial_density_grid(), density);
+
+    fluid.set_config_diffusion(5.0);
+    fluid.clear_diffusion_field();
+    fluid.simulate();
+    assert!(fluid.get_density_at_index(fluid.ix(7, 4)) > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn single_cell_grid_does_not_panic() {
+    for periodic in [false, true] {
+        let mut config = FluidConfig::new(1, 1, 0.5);
+        if periodic {
+            config.set_boundary_mode(BoundaryMode::Periodic);
+            config.set_sample_mode(SampleMode::Wrap);
+            config.set_advection_scheme(AdvectionScheme::Bfecc);
+            config.set_interpolation(InterpolationKind::Bicubic);
+            config.set_pressure_solver(SolverKind::ConjugateGradient);
+            config.set_conserve_mass(true);
+            config.set_symmetry(Symmetry::Both);
+        }
+        let mut fluid = Fluid::new(config, 0.1);
+        let center = fluid.ix(1, 1);
+        fluid.add_density(center, 10.0);
+        fluid.add_velocity(center, 1.0, 1.0);
+        fluid.add_vortex(1, 1, 2, 1.0);
+        fluid.add_sink(1, 1, 1.0);
+        fluid.add_density_line(0, 0, 3, 3, 1.0);
+        fluid.add_velocity_line(0, 0, 3, 3, 1.0, 1.0, 1.0);
+        fluid.set_solid(1, 1, false);
+        fluid.set_circular_domain();
+        fluid.set_sponge(2, 1.0);
+        fluid.set_density_force_coupling(1.0);
+        fluid.simulate_steps(3);
+        fluid.simulate_until_steady(0.0, 2);
+        fluid.begin_step();
+        while !fluid.step_partial(1) {}
+        fluid.blur_density(1);
+        fluid.inspect();
+        fluid.cfl_number();
+        fluid.density_histogram(4, 1.0);
+        fluid.sample_density(0.7, 0.7);
+        fluid.integrate_density_along_ray(0.0, 0.0, 1.0, 1.0, 4);
+        fluid.trace_streamline(1.0, 1.0, 0.5, 4);
+        fluid.get_velocity_x_faces();
+        fluid.get_velocity_y_faces();
+        fluid.get_velocity_polar_grid();
+        fluid.get_initial_density_grid();
+        fluid.reset();
+    }
+}
+
+#[wasm_bindgen_test]
+fn circular_domain_keeps_density_inside() {
+    let n = 24;
+    let mut fluid = Fluid::new(FluidConfig::new(n, n, 0.001), 0.1);
+    fluid.set_circular_domain();
+    // Smoke over the whole grid, stirred against the wall
+    for y in 1..=n {
+        for x in 1..=n {
+            fluid.add_density(fluid.ix(x, y), 10.0);
+        }
+    }
+    fluid.add_vortex(12, 12, 11, 5.0);
+    fluid.simulate_steps(20);
+
+    // Solid cells next to the fluid copy its density, so beyond the cells
+    // lining the circle there is none
+    let center = (n as f32 + 1.0) / 2.0;
+    let radius = n as f32 / 2.0;
+    let (mut inside, mut edge) = (0.0, 0.0);
+    for y in 0..=n + 1 {
+        for x in 0..=n + 1 {
+            let distance = (x as f32 - center).hypot(y as f32 - center);
+            let density = fluid.get_density_at_index(fluid.ix(x, y));
+            if distance > radius + 1.5 {
+                assert_eq!(density, 0.0, "({}, {})", x, y);
+            } else if distance > radius {
+                edge += density;
+            } else {
+                inside += density;
+            }
+        }
+    }
+    assert!(inside > 0.0 && edge > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn memory_bytes_counts_every_buffer() {
+    let fluid = Fluid::new(FluidConfig::new(10, 6, 0.0), 0.1);
+    // Sixteen f32 buffers and the solid mask, each of get_size cells
+    assert_eq!(Fluid::memory_bytes(10, 6), fluid.get_size() * (16 * 4 + 1));
+    assert_eq!(Fluid::memory_bytes(1, 1), 9 * 65);
+}
+
+#[wasm_bindgen_test]
+#[should_panic(expected = "the grid needs at least one interior cell")]
+fn empty_grid_is_rejected() {
+    FluidConfig::new(0, 4, 0.5);
+}
+
+#[wasm_bindgen_test]
+fn interior_row_range_covers_one_row() {
+    let fluid = Fluid::new(FluidConfig::new(5, 3, 0.0), 0.1);
+    let range = fluid.interior_row_range(2);
+    assert_eq!(range.start, fluid.ix(1, 2));
+    assert_eq!(range.end, fluid.ix(5, 2) + 1);
+    assert_eq!(fluid.get_density_expensive()[range].len(), 5);
+}
+
+#[wasm_bindgen_test]
+fn compute_speed_fills_interior_speeds() {
+    let mut fluid = Fluid::new(FluidConfig::new(6, 4, 0.0), 0.1);
+    fluid.add_velocity(fluid.ix(3, 2), 30.0, 40.0);
+    fluid.simulate();
+
+    let mut speeds = vec![0.0; 6 * 4];
+    fluid.compute_speed(&mut speeds);
+    for (cell, &speed) in speeds.iter().enumerate() {
+        let index = fluid.ix(cell as u16 % 6 + 1, cell as u16 / 6 + 1);
+        let expected = fluid
+            .get_velocity_x(index)
+            .hypot(fluid.get_velocity_y(index));
+        assert_eq!(speed, expected);
+    }
+    assert_eq!(
+        speeds.iter().cloned().fold(0.0, f32::max),
+        fluid.max_velocity()
+    );
+}
+
+#[wasm_bindgen_test]
+fn shallow_water_bump_spreads_as_a_wave() {
+    let n = 32;
+    let mut fluid = Fluid::new(FluidConfig::new(n, n, 0.0), 0.1);
+    fluid.set_shallow_water(true, 9.8);
+    let mut heights = vec![1.0; n as usize * n as usize];
+    heights[(n as usize / 2 - 1) * n as usize + n as usize / 2 - 1] = 2.0;
+    fluid.set_initial_density_grid(&heights);
+
+    fluid.simulate_steps(5);
+    let grid = fluid.get_initial_density_grid();
+    let at = |x: usize, y: usize| grid[(y - 1) * n as usize + x - 1];
+    let center = n as usize / 2;
+    assert!(at(center, center) < 2.0);
+    assert!(at(center + 2, center) > 1.0);
+    // Without the projection the flow keeps the divergence that drives it
+    assert!(fluid.max_divergence() > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn reinhard_keeps_large_values_below_one() {
+    for value in [0.0, 0.5, 1.0, 10.0, 1e6] {
+        let mapped = ToneMap::reinhard().apply(value);
+        assert!((0.0..1.0).contains(&mapped));
+    }
+    assert_eq!(ToneMap::linear().apply(3.0), 1.0);
+    assert!((ToneMap::gamma(2.0).apply(0.25) - 0.5).abs() < 1e-6);
+
+    let mut fluid = Fluid::new(FluidConfig::new(2, 2, 0.0), 1.0);
+    fluid.add_density(fluid.ix(1, 1), 1000.0);
+    fluid.simulate();
+    let pixels = fluid.render_to_rgba(1000.0, &ToneMap::reinhard());
+    assert_eq!(pixels.len(), 2 * 2 * 4);
+    assert!(pixels[2] > 0 && pixels[2] < 255);
+    assert_eq!(pixels[3], 255);
+}
+
+#[wasm_bindgen_test]
+fn energy_spectrum_bins_by_wavenumber() {
+    let n = 16;
+    let mut fluid = Fluid::new(FluidConfig::new(n, n, 0.0), 0.1);
+    // Three periods of a wave along x, sampled on the faces
+    let faces_x: Vec<f32> = (0..(n + 1) * n)
+        .map(|face| {
+            let x = (face % (n + 1)) as f32 - 0.5;
+            (2.0 * std::f32::consts::PI * 3.0 * x / n as f32).sin()
+        })
+        .collect();
+    fluid.set_velocity_faces(faces_x, vec![0.0; (n * (n + 1)) as usize]);
+
+    let spectrum = fluid.energy_spectrum();
+    let total: f32 = spectrum.iter().sum();
+    assert!((total - fluid.inspect().get_energy()).abs() < 1e-3 * total);
+    assert!(spectrum[3] > 0.99 * total);
+}
+
+#[wasm_bindgen_test]
+fn rewind_restores_earlier_steps() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.1), 0.1);
+    fluid.set_history_capacity(3);
+    fluid.add_density(fluid.ix(4, 4), 50.0);
+    fluid.add_velocity(fluid.ix(4, 4), 10.0, 5.0);
+    fluid.simulate_steps(2);
+    let after_two = fluid.get_density_expensive();
+    fluid.simulate_steps(3);
+    let after_five = fluid.get_density_expensive();
+    assert_eq!(fluid.history_len(), 3);
+
+    // Only the last three steps are kept
+    assert!(!fluid.rewind(4));
+    assert!(fluid.rewind(3));
+    assert_eq!(fluid.get_steps(), 2);
+    assert_eq!(fluid.get_density_expensive(), after_two);
+    assert_eq!(fluid.history_len(), 0);
+
+    // The rewound state continues like the original did
+    fluid.simulate_steps(3);
+    assert_eq!(fluid.get_density_expensive(), after_five);
+    fluid.set_history_capacity(0);
+    assert_eq!(fluid.history_len(), 0);
+    assert!(!fluid.rewind(1));
+}
+
+#[wasm_bindgen_test]
+fn rewind_restores_the_artificial_compressibility_pressure() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.1), 0.1);
+    fluid.set_config_incompressibility(IncompressibilityMethod::ArtificialCompressibility);
+    fluid.set_history_capacity(2);
+    fluid.add_velocity(fluid.ix(4, 4), 10.0, 5.0);
+    fluid.simulate();
+    fluid.simulate_steps(2);
+    let after_three = (
+        fluid.get_density_expensive(),
+        fluid.get_velocity_x_expensive(),
+    );
+
+    // The pressure carried into step two comes back with the velocity
+    assert!(fluid.rewind(2));
+    fluid.simulate_steps(2);
+    assert_eq!(
+        (
+            fluid.get_density_expensive(),
+            fluid.get_velocity_x_expensive()
+        ),
+        after_three
+    );
+}
+
+#[wasm_bindgen_test]
+fn artificial_compressibility_reduces_divergence() {
+    let n = 16;
+    let mut config = FluidConfig::new(n, n, 0.0);
+    config.set_incompressibility(IncompressibilityMethod::ArtificialCompressibility);
+    config.set_sound_speed(5.0);
+    let mut fluid = Fluid::new(config, 0.1);
+    // Flow spreading out from the center
+    let center = (n as f32 + 1.0) / 2.0;
+    let cells = || (0..n * n).map(|cell| ((cell % n + 1) as f32, (cell / n + 1) as f32));
+    let velocity_x: Vec<f32> = cells().map(|(x, _)| 0.1 * (x - center)).collect();
+    let velocity_y: Vec<f32> = cells().map(|(_, y)| 0.1 * (y - center)).collect();
+    fluid.set_initial_velocity_x_grid(&velocity_x);
+    fluid.set_initial_velocity_y_grid(&velocity_y);
+    let initial = fluid.max_divergence();
+
+    // Undamped pressure waves keep sloshing, so the divergence oscillates
+    // instead of settling, but stays well below where it started
+    let mut total = 0.0;
+    for _ in 0..20 {
+        fluid.simulate();
+        assert!(fluid.max_velocity() < 2.0);
+        total += fluid.max_divergence();
+    }
+    assert!(total / 20.0 < 0.5 * initial);
+}
+
+#[wasm_bindgen_test]
+fn streamline_follows_the_flow_until_it_leaves() {
+    let mut fluid = Fluid::new(FluidConfig::new(10, 8, 0.0), 0.1);
+    fluid.set_velocity_from_fn(|_, _| (1.0, 0.0));
+    let points = fluid.trace_streamline(2.0, 4.0, 1.0, 100);
+    // One cell a step along the row, stopping at the last column
+    let expected: Vec<f32> = (2..=10).flat_map(|x| [x as f32, 4.0]).collect();
+    assert_eq!(points, expected);
+    assert_eq!(fluid.trace_streamline(2.0, 4.0, 1.0, 3).len(), 2 * 4);
+
+    // A still fluid leaves only the seed, a seed outside the grid nothing
+    let still = Fluid::new(FluidConfig::new(10, 8, 0.0), 0.1);
+    assert_eq!(still.trace_streamline(2.0, 4.0, 1.0, 100), vec![2.0, 4.0]);
+    assert!(fluid.trace_streamline(0.0, 4.0, 1.0, 100).is_empty());
+}
+
+#[wasm_bindgen_test]
+fn polar_velocity_matches_components() {
+    let setup = || Fluid::new(FluidConfig::new(8, 8, 0.0), 0.1);
+    let (mut polar, mut components) = (setup(), setup());
+    let index = polar.ix(4, 4);
+    let angle = std::f32::consts::FRAC_PI_3;
+    assert!(polar.add_velocity_polar(index, angle, 10.0));
+    components.add_velocity(index, 10.0 * angle.cos(), 10.0 * angle.sin());
+    polar.simulate();
+    components.simulate();
+    assert_eq!(
+        polar.get_velocity_x_expensive(),
+        components.get_velocity_x_expensive()
+    );
+    assert!(!polar.add_velocity_polar(usize::MAX, angle, 10.0));
+}
+
+#[wasm_bindgen_test]
+fn state_checksum_is_deterministic() {
+    let run = |density: f32| {
+        let mut fluid = Fluid::new(FluidConfig::new(12, 12, 0.2), 0.1);
+        fluid.add_density(fluid.ix(6, 6), density);
+        fluid.add_velocity(fluid.ix(6, 6), 4.0, -2.0);
+        fluid.simulate_steps(5);
+        fluid.state_checksum()
+    };
+    assert_eq!(run(10.0), run(10.0));
+    assert_ne!(run(10.0), run(10.5));
+}
+
+#[wasm_bindgen_test]
+fn every_step_sources_keep_emitting() {
+    let mass_after_three_steps = |application: SourceApplication| {
+        let mut config = FluidConfig::new(8, 8, 0.0);
+        config.set_source_application(application);
+        let mut fluid = Fluid::new(config, 0.5);
+        fluid.add_density(fluid.ix(4, 4), 10.0);
+        fluid.simulate_steps(3);
+        let mass = fluid.inspect().get_mass();
+        fluid.clear_sources();
+        fluid.simulate();
+        assert_eq!(fluid.inspect().get_mass(), mass);
+        mass
+    };
+    assert_eq!(mass_after_three_steps(SourceApplication::OncePerFrame), 5.0);
+    assert_eq!(mass_after_three_steps(SourceApplication::EveryStep), 15.0);
+}
+
+#[wasm_bindgen_test]
+fn coupled_fluids_share_their_seam() {
+    let mut left = Fluid::new(FluidConfig::new(6, 4, 0.0), 1.0);
+    let mut right = Fluid::new(FluidConfig::new(3, 4, 0.0), 1.0);
+    left.add_density(left.ix(6, 2), 8.0);
+    left.simulate();
+    right.simulate();
+
+    left.couple_boundary(&mut right, Side::Right);
+    assert_eq!(left.get_density_at_index(left.ix(6, 2)), 4.0);
+    assert_eq!(right.get_density_at_index(right.ix(1, 2)), 4.0);
+}
+
+#[wasm_bindgen_test]
+#[should_panic(expected = "coupled edges must have the same length")]
+fn coupling_needs_matching_edges() {
+    let mut bottom = Fluid::new(FluidConfig::new(6, 4, 0.0), 1.0);
+    let mut top = Fluid::new(FluidConfig::new(5, 4, 0.0), 1.0);
+    bottom.couple_boundary(&mut top, Side::Top);
+}
+
+#[wasm_bindgen_test]
+fn ascii_shows_the_densest_cell() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 3, 0.0), 1.0);
+    fluid.add_density(fluid.ix(2, 2), 5.0);
+    fluid.simulate();
+    assert_eq!(fluid.to_ascii(" .:-=+*#%@"), "    \n @  \n    \n");
+}
+
+#[wasm_bindgen_test]
+fn conserve_momentum_removes_drift() {
+    let run = |conserve_momentum: bool| {
+        let mut config = FluidConfig::new(12, 12, 0.1);
+        config.set_conserve_momentum(conserve_momentum);
+        let mut fluid = Fluid::new(config, 0.1);
+        fluid.add_velocity(fluid.ix(6, 6), 10.0, 0.0);
+        fluid.simulate_steps(5);
+        fluid.total_momentum()
+    };
+    // The walls soak up momentum unless it is put back
+    let (drifting_x, _) = run(false);
+    assert!((drifting_x - 1.0).abs() > 1e-2);
+    let (momentum_x, momentum_y) = run(true);
+    assert!((momentum_x - 1.0).abs() < 1e-4);
+    assert!(momentum_y.abs() < 1e-4);
+}
+
+#[wasm_bindgen_test]
+fn wall_friction_slows_flow_along_walls() {
+    let speed_next_to_wall = |wall_friction: f32| {
+        let n = 12;
+        let mut config = FluidConfig::new(n, n, 1.0);
+        config.set_wall_friction(wall_friction);
+        let mut fluid = Fluid::new(config, 0.1);
+        fluid.set_initial_velocity_x_grid(&vec![1.0; n as usize * n as usize]);
+        fluid.simulate_steps(3);
+        fluid.get_initial_velocity_x_grid()[n as usize / 2]
+    };
+    let free_slip = speed_next_to_wall(1.0);
+    let partial = speed_next_to_wall(0.5);
+    let no_slip = speed_next_to_wall(0.0);
+    assert!(no_slip < partial && partial < free_slip);
+}
+
+#[wasm_bindgen_test]
+fn stencil_stamps_clipped_density() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 1.0);
+    // A 3x2 stencil hanging off the right edge of the grid
+    let stencil = [255, 0, 51, 102, 255, 255];
+    fluid.add_density_stencil(&stencil, 3, 2, 3, 1, 10.0);
+    fluid.simulate();
+
+    let density = |x, y| fluid.get_density_at_index(fluid.ix(x, y));
+    assert_eq!(density(3, 1), 10.0);
+    assert_eq!(density(4, 1), 0.0);
+    assert_eq!(density(3, 2), 4.0);
+    assert_eq!(density(4, 2), 10.0);
+    assert_eq!(fluid.inspect().get_mass(), 24.0);
+}
+
+#[wasm_bindgen_test]
+fn enstrophy_measures_rotation() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    assert_eq!(fluid.enstrophy(), 0.0);
+
+    // Solid body rotation about the center, with a curl of 0.2
+    let center = 8.5;
+    let faces_x: Vec<f32> = (0..17 * 16)
+        .map(|face| -0.1 * ((face / 17 + 1) as f32 - center))
+        .collect();
+    let faces_y: Vec<f32> = (0..16 * 17)
+        .map(|face| 0.1 * ((face % 16 + 1) as f32 - center))
+        .collect();
+    fluid.set_velocity_faces(faces_x, faces_y);
+    // At least the 14 * 14 cells clear of the walls see all of it
+    assert!(fluid.enstrophy() >= 0.5 * 0.2 * 0.2 * 14.0 * 14.0 - 1e-3);
+}
+
+#[wasm_bindgen_test]
+fn velocity_faces_round_trip_through_the_cells() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 0.1);
+    // x faces growing along each row, y faces growing up each column
+    let faces_x: Vec<f32> = (0..5 * 4).map(|face| (face % 5) as f32).collect();
+    let faces_y: Vec<f32> = (0..4 * 5).map(|face| (face / 4) as f32).collect();
+    fluid.set_velocity_faces(faces_x.clone(), faces_y.clone());
+
+    // Cells hold the average of their two faces
+    let index = fluid.ix(2, 1);
+    assert_eq!(fluid.get_velocity_x(index), 1.5);
+    assert_eq!(fluid.get_velocity_y(index), 0.5);
+
+    // Interior faces come back as they went in, the walls let nothing through
+    let round_trip_x = fluid.get_velocity_x_faces();
+    let round_trip_y = fluid.get_velocity_y_faces();
+    for (face, (&sent, &received)) in faces_x.iter().zip(&round_trip_x).enumerate() {
+        let expected = if face % 5 == 0 || face % 5 == 4 {
+            0.0
+        } else {
+            sent
+        };
+        assert_eq!(received, expected, "x face {}", face);
+    }
+    for (face, (&sent, &received)) in faces_y.iter().zip(&round_trip_y).enumerate() {
+        let expected = if face / 4 == 0 || face / 4 == 4 {
+            0.0
+        } else {
+            sent
+        };
+        assert_eq!(received, expected, "y face {}", face);
+    }
+}
+
+#[wasm_bindgen_test]
+fn grids_with_border_show_the_walls() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 3, 0.0), 0.1);
+    fluid.set_velocity_faces(vec![1.0; 5 * 3], vec![0.0; 4 * 4]);
+    let stride = fluid.get_stride() as usize;
+    let velocity_x = fluid.get_velocity_x_grid_with_border();
+    assert_eq!(velocity_x.len(), fluid.get_size());
+    assert_eq!(fluid.get_density_grid_with_border().len(), fluid.get_size());
+    assert_eq!(
+        fluid.get_velocity_y_grid_with_border().len(),
+        fluid.get_size()
+    );
+
+    // The walls reflect the velocity into them
+    for row in 1..4 {
+        assert_eq!(velocity_x[row * stride], -velocity_x[row * stride + 1]);
+        assert_eq!(velocity_x[row * stride + 5], -velocity_x[row * stride + 4]);
+    }
+}
+
+#[wasm_bindgen_test]
+fn auto_substep_keeps_cfl_below_one() {
+    let fast = |max_substeps| {
+        let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 1.0);
+        fluid.set_velocity_faces(vec![2.5; 9 * 8], vec![0.0; 8 * 9]);
+        fluid.set_auto_substep(true, max_substeps);
+        let cfl = fluid.cfl_number();
+        fluid.simulate();
+        (cfl, fluid.get_steps())
+    };
+
+    let (cfl, steps) = fast(10);
+    assert!(cfl > 2.0 && cfl < 3.0);
+    assert_eq!(steps, 3);
+    // Capped frames run as many substeps as allowed
+    assert_eq!(fast(2).1, 2);
+
+    let mut slow = Fluid::new(FluidConfig::new(8, 8, 0.0), 0.1);
+    slow.set_auto_substep(true, 10);
+    slow.simulate();
+    assert_eq!(slow.get_steps(), 1);
+}
+
+#[wasm_bindgen_test]
+fn timed_step_matches_simulate() {
+    let run = |timed: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(32, 32, 0.01), 0.1);
+        fluid.add_density(fluid.ix(16, 16), 100.0);
+        fluid.add_velocity(fluid.ix(16, 16), 5.0, 2.0);
+        let timings = if timed {
+            Some(fluid.simulate_timed())
+        } else {
+            fluid.simulate();
+            None
+        };
+        (fluid.state_checksum(), timings)
+    };
+    let (checksum, timings) = run(true);
+    assert_eq!(checksum, run(false).0);
+
+    let timings = timings.unwrap();
+    for ms in [
+        timings.get_diffuse_ms(),
+        timings.get_advect_ms(),
+        timings.get_project_ms(),
+        timings.get_other_ms(),
+    ] {
+        assert!(ms >= 0.0);
+    }
+    assert!(timings.get_total_ms() > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn density_gradient_paints_velocity() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 0.1);
+    // Density rising to the right
+    let ramp: Vec<f32> = (0..64).map(|cell| (cell % 8) as f32).collect();
+    fluid.set_initial_density_grid(&ramp);
+
+    fluid.velocity_from_density_gradient(2.0, false);
+    let (velocity_x, velocity_y) = (
+        fluid.get_initial_velocity_x_grid(),
+        fluid.get_initial_velocity_y_grid(),
+    );
+    assert_eq!(velocity_x[3 * 8 + 4], 2.0);
+    assert_eq!(velocity_y[3 * 8 + 4], 0.0);
+
+    fluid.velocity_from_density_gradient(2.0, true);
+    assert_eq!(fluid.get_initial_velocity_x_grid()[3 * 8 + 4], 0.0);
+    assert_eq!(fluid.get_initial_velocity_y_grid()[3 * 8 + 4], 2.0);
+}
+
+#[wasm_bindgen_test]
+fn npy_export_has_header_and_rows() {
+    let mut fluid = Fluid::new(FluidConfig::new(3, 2, 0.0), 0.1);
+    let values = [1.0, 2.0, 3.0, 4.0, 5.0, 6.0];
+    fluid.set_initial_density_grid(&values);
+    fluid.set_config_diffusion(0.0);
+    fluid.simulate();
+
+    let npy = fluid.to_npy(Field::Density);
+    assert_eq!(&npy[..8], b"\x93NUMPY\x01\x00");
+    let header_len = u16::from_le_bytes([npy[8], npy[9]]) as usize;
+    let header = std::str::from_utf8(&npy[10..10 + header_len]).unwrap();
+    assert!(header.starts_with("{'descr': '<f4', 'fortran_order': False, 'shape': (2, 3), }"));
+    assert!(header.ends_with('\n'));
+    assert_eq!((10 + header_len) % 64, 0);
+
+    let data: Vec<f32> = npy[10 + header_len..]
+        .chunks(4)
+        .map(|bytes| f32::from_le_bytes([bytes[0], bytes[1], bytes[2], bytes[3]]))
+        .collect();
+    let border = fluid.get_density_grid_with_border();
+    let interior: Vec<f32> = (1..3)
+        .flat_map(|row| border[row * 5 + 1..row * 5 + 4].to_vec())
+        .collect();
+    assert_eq!(data, interior);
+}
+
+#[wasm_bindgen_test]
+fn projection_leaves_the_velocity_divergence_free() {
+    let mut fluid = Fluid::new(FluidConfig::new(32, 32, 0.0), 0.05);
+    fluid.set_config_boundary_mode(BoundaryMode::Periodic);
+    fluid.set_config_dx(2.0 * std::f32::consts::PI / 32.0);
+    // A pure gradient flow, all of it is divergence for the projection to take
+    fluid.set_velocity_from_fn(|x, y| ((4.0 * x).sin(), (4.0 * y).sin()));
+    let before = fluid.max_divergence();
+    fluid.simulate();
+    fluid.assert_divergence_free(0.1 * before);
+}
+
+#[wasm_bindgen_test]
+#[cfg(debug_assertions)]
+#[should_panic(expected = "velocity divergence")]
+fn unprojected_velocity_fails_the_divergence_check() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    // Flow streaming out of the centre, nothing has projected it yet
+    fluid.set_velocity_from_fn(|x, y| (x, y));
+    fluid.assert_divergence_free(0.1);
+}
+
+#[wasm_bindgen_test]
+fn polar_velocity_gives_angle_and_speed() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 0.1);
+    // Still in the first column, up and to the left everywhere else
+    let (first_column, _) = fluid.cell_to_world(1.0, 1.0);
+    fluid.set_velocity_from_fn(|x, _| {
+        if x <= first_column {
+            (0.0, 0.0)
+        } else {
+            (-3.0, 3.0)
+        }
+    });
+
+    let (angle, speed) = fluid.get_velocity_polar_at_index(fluid.ix(3, 2));
+    assert!((angle - 3.0 * std::f32::consts::FRAC_PI_4).abs() < 1e-6);
+    assert!((speed - 3.0 * 2.0_f32.sqrt()).abs() < 1e-5);
+    assert_eq!(
+        fluid.get_velocity_polar_at_index(fluid.ix(1, 2)),
+        (0.0, 0.0)
+    );
+
+    // The grid holds the same pairs for the interior, row by row
+    let grid = fluid.get_velocity_polar_grid();
+    assert_eq!(grid.len(), 4 * 4);
+    for y in 1..=4 {
+        for x in 1..=4 {
+            let cell = (y - 1) as usize * 4 + x as usize - 1;
+            assert_eq!(
+                grid[cell],
+                fluid.get_velocity_polar_at_index(fluid.ix(x, y))
+            );
+        }
+    }
+}
+
+#[wasm_bindgen_test]
+fn taylor_green_vortex_from_fn() {
+    let mut fluid = Fluid::new(FluidConfig::new(32, 32, 0.0), 0.05);
+    fluid.set_config_boundary_mode(BoundaryMode::Periodic);
+    fluid.set_config_dx(2.0 * std::f32::consts::PI / 32.0);
+    fluid.set_velocity_from_fn(|x, y| (x.sin() * y.cos(), -x.cos() * y.sin()));
+
+    let (x, y) = fluid.cell_to_world(5.0, 9.0);
+    let index = fluid.ix(5, 9);
+    assert!((fluid.get_velocity_x(index) - x.sin() * y.cos()).abs() < 1e-6);
+    assert!((fluid.get_velocity_y(index) + x.cos() * y.sin()).abs() < 1e-6);
+    assert!(fluid.max_divergence() < 1e-4);
+
+    // Without viscosity the vortices are a steady flow, only numerical
+    // diffusion wears them down
+    let energy = fluid.inspect().get_energy();
+    fluid.simulate_steps(10);
+    let ratio = fluid.inspect().get_energy() / energy;
+    assert!(ratio > 0.8 && ratio <= 1.0);
+}
+
+#[wasm_bindgen_test]
+fn presented_frames_are_snapshots() {
+    fn assert_shareable<T: Send + Sync>() {}
+    assert_shareable::<FrameReader>();
+
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 0.1);
+    let reader = fluid.frame_reader();
+    let first = reader.latest();
+    assert_eq!(first.get_steps(), 0);
+    assert_eq!(first.get_density().len(), 16);
+
+    fluid.add_density(fluid.ix(2, 2), 10.0);
+    fluid.simulate();
+    // Nothing is published until present
+    assert_eq!(reader.latest().get_steps(), 0);
+    fluid.present();
+    let second = reader.clone().latest();
+    assert_eq!(second.get_steps(), 1);
+    assert_eq!(second.get_density()[4 + 1], 1.0);
+    // Frames already taken are not touched by later ones, and the slot lets
+    // go of the frames it swaps out once no reader is taking them
+    assert!(first.get_density().iter().all(|&density| density == 0.0));
+    assert_eq!(std::sync::Arc::strong_count(&first), 1);
+    fluid.present();
+    assert_eq!(std::sync::Arc::strong_count(&second), 1);
+    assert_eq!(reader.latest().get_steps(), 1);
+}
+
+#[wasm_bindgen_test]
+fn residuals_are_only_measured_with_a_tolerance() {
+    let run = |tolerance: Option<f32>| {
+        let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.1), 0.1);
+        if let Some(tolerance) = tolerance {
+            fluid.set_residual_tolerance(tolerance);
+        }
+        fluid.add_density(fluid.ix(8, 8), 100.0);
+        fluid.add_velocity(fluid.ix(8, 8), 20.0, 5.0);
+        fluid.simulate();
+        fluid
+    };
+
+    let untracked = run(None);
+    assert_eq!(untracked.last_diffuse_residual(), 0.0);
+    assert_eq!(untracked.last_projection_residual(), 0.0);
+    assert!(untracked.has_converged());
+
+    let tracked = run(Some(f32::MAX));
+    assert!(tracked.last_diffuse_residual() > 0.0);
+    assert!(tracked.last_projection_residual() > 0.0);
+    assert!(tracked.has_converged());
+    // One residual pass per diffusion solve: velocity x, y and density
+    assert_eq!(
+        tracked.last_diffuse_sweeps(),
+        untracked.last_diffuse_sweeps() + 3
+    );
+    assert_eq!(tracked.state_checksum(), untracked.state_checksum());
+
+    assert!(!run(Some(0.0)).has_converged());
+}
+
+#[wasm_bindgen_test]
+fn step_delta_is_only_measured_when_tracked() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.1), 0.1);
+    fluid.add_velocity(fluid.ix(8, 8), 20.0, 5.0);
+    fluid.simulate();
+    assert_eq!(fluid.last_step_delta(), 0.0);
+
+    fluid.set_step_delta_tracking(true);
+    fluid.simulate();
+    assert!(fluid.last_step_delta() > 0.0);
+
+    // The steady loop measures whatever the setting, and leaves it as it was
+    fluid.set_step_delta_tracking(false);
+    let steps = fluid.simulate_until_steady(1e-3, 500);
+    assert!(steps > 1 && steps < 500, "{}", steps);
+    assert!(fluid.last_step_delta() < 1e-3);
+    fluid.simulate();
+    assert_eq!(fluid.last_step_delta(), 0.0);
+}
+
+#[wasm_bindgen_test]
+fn incremental_solve_needs_fewer_sweeps() {
+    let run = |incremental: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(32, 32, 1.0), 0.1);
+        fluid.set_config_incremental_solve(incremental);
+        // A broad blob, slowly varying from cell to cell
+        let blob: Vec<f32> = (0..32 * 32)
+            .map(|cell| {
+                let (dx, dy) = ((cell % 32) as f32 - 15.5, (cell / 32) as f32 - 15.5);
+                100.0 * (-(dx * dx + dy * dy) / 100.0).exp()
+            })
+            .collect();
+        fluid.set_initial_density_grid(&blob);
+        let mut sweeps = 0;
+        for _ in 0..5 {
+            fluid.simulate();
+            sweeps += fluid.last_diffuse_sweeps();
+        }
+        (sweeps, fluid.get_initial_density_grid())
+    };
+    let (full_sweeps, full) = run(false);
+    let (sweeps, density) = run(true);
+    assert!(sweeps < full_sweeps);
+    // Within 0.1% of the peak of the fixed sweep count's result
+    for (full, incremental) in full.iter().zip(&density) {
+        assert!((full - incremental).abs() < 0.1);
+    }
+}
+
+#[wasm_bindgen_test]
+fn backtrace_map_follows_the_velocity() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 3, 0.0), 0.5);
+    let size = fluid.get_size();
+    fluid.set_advection_velocity(vec![2.0; size], vec![-1.0; size]);
+
+    let mut map = vec![(0.0, 0.0); 4 * 3];
+    fluid.backtrace_map(&mut map);
+    assert_eq!(map[0], (0.0, 1.5));
+    assert_eq!(map[4 * 2 + 3], (3.0, 3.5));
+}
+
+#[wasm_bindgen_test]
+fn max_speed_clamps_injected_velocity() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.set_max_speed(2.0);
+    fluid.add_velocity(fluid.ix(8, 8), 3000.0, 4000.0);
+    fluid.simulate();
+    assert!(fluid.max_velocity() <= 2.0 + 1e-5);
+    assert!(fluid.max_velocity() > 1.0);
+
+    // The direction survives the clamp
+    let index = fluid.ix(8, 8);
+    let (velocity_x, velocity_y) = (fluid.get_velocity_x(index), fluid.get_velocity_y(index));
+    assert!(velocity_x > 0.0 && velocity_y > velocity_x);
+}
+
+#[wasm_bindgen_test]
+fn bfecc_keeps_a_moving_bump_sharper() {
+    let run = |scheme: AdvectionScheme| {
+        let mut config = FluidConfig::new(32, 8, 0.0);
+        config.set_advection_scheme(scheme);
+        let mut fluid = Fluid::new(config, 1.0);
+        let size = fluid.get_size();
+        // Half a cell a step, the worst case for interpolation smearing
+        fluid.set_advection_velocity(vec![0.5; size], vec![0.0; size]);
+        let bump: Vec<f32> = (0..32 * 8)
+            .map(|cell| {
+                let x = (cell % 32) as f32 - 7.5;
+                10.0 * (-x * x / 4.0).exp()
+            })
+            .collect();
+        fluid.set_initial_density_grid(&bump);
+        fluid.simulate_steps(20);
+        fluid.get_initial_density_grid()
+    };
+    let peak = |density: &[f32]| density.iter().cloned().fold(0.0, f32::max);
+    let (plain, bfecc) = (
+        run(AdvectionScheme::SemiLagrangian),
+        run(AdvectionScheme::Bfecc),
+    );
+    assert!(peak(&bfecc) > peak(&plain));
+    // The clamping stops the correction from overshooting
+    assert!(peak(&bfecc) <= 10.0);
+    assert!(bfecc.iter().all(|&density| density >= 0.0));
+}
+
+#[wasm_bindgen_test]
+fn diffusion_and_coordinates_follow_dx() {
+    // Cells twice as wide take four times the diffusion to spread as far
+    let diffuse = |diffusion: f32, dx: f32| {
+        let mut config = FluidConfig::new(12, 12, diffusion);
+        config.set_dx(dx);
+        let mut fluid = Fluid::new(config, 0.1);
+        fluid.add_density(fluid.ix(6, 6), 100.0);
+        fluid.simulate_steps(3);
+        fluid.get_density_grid_with_border()
+    };
+    assert_eq!(diffuse(0.4, 2.0), diffuse(0.1, 1.0));
+    assert_ne!(diffuse(0.1, 2.0), diffuse(0.1, 1.0));
+
+    let mut config = FluidConfig::new(12, 12, 0.0);
+    config.set_dx(0.5);
+    config.set_origin(-3.0, 2.0);
+    let fluid = Fluid::new(config, 0.1);
+    assert_eq!(fluid.cell_to_world(1.0, 1.0), (-2.75, 2.25));
+    assert_eq!(fluid.cell_to_world(12.5, 0.5), (3.0, 2.0));
+    assert_eq!(fluid.world_to_cell(-2.75, 2.25), (1.0, 1.0));
+}
+
+#[wasm_bindgen_test]
+fn advection_traces_back_in_cells_of_dx() {
+    // Moving 2 units a step across cells 2 units wide is one cell a step
+    let advect = |dx: f32, speed: f32| {
+        let mut fluid = Fluid::new(FluidConfig::new(12, 12, 0.0), 0.5);
+        fluid.set_config_dx(dx);
+        let size = fluid.get_size();
+        fluid.set_advection_velocity(vec![speed; size], vec![0.5 * speed; size]);
+        fluid.add_density(fluid.ix(4, 4), 10.0);
+        fluid.simulate_steps(3);
+        fluid.get_density_grid_with_border()
+    };
+    assert_eq!(advect(2.0, 4.0), advect(1.0, 2.0));
+
+    let mut map = vec![(0.0, 0.0); 12 * 12];
+    let mut fluid = Fluid::new(FluidConfig::new(12, 12, 0.0), 0.5);
+    fluid.set_config_dx(2.0);
+    let size = fluid.get_size();
+    fluid.set_advection_velocity(vec![4.0; size], vec![0.0; size]);
+    fluid.backtrace_map(&mut map);
+    assert_eq!(map[12 + 5], (5.0, 2.0));
+}
+
+#[wasm_bindgen_test]
+fn age_grows_downstream_of_a_source() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 4, 0.0), 0.5);
+    fluid.set_track_age(true);
+    let size = fluid.get_size();
+    // One cell a step to the right
+    fluid.set_advection_velocity(vec![2.0; size], vec![0.0; size]);
+    fluid.set_config_source_application(SourceApplication::EveryStep);
+    fluid.add_density(fluid.ix(2, 2), 10.0);
+    fluid.simulate_steps(8);
+
+    let age = |x| fluid.get_age_at_index(fluid.ix(x, 2));
+    assert_eq!(age(3), 0.5);
+    assert_eq!(age(6), 2.0);
+    assert!(age(9) > age(6));
+    // Nothing has reached the far end or the other rows yet
+    assert_eq!(age(14), 0.0);
+    assert_eq!(fluid.get_age_at_index(fluid.ix(6, 4)), 0.0);
+}
+
+#[wasm_bindgen_test]
+fn rewind_restores_the_ages() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 4, 0.0), 0.5);
+    fluid.set_track_age(true);
+    fluid.set_history_capacity(2);
+    let size = fluid.get_size();
+    fluid.set_advection_velocity(vec![2.0; size], vec![0.0; size]);
+    fluid.add_density(fluid.ix(2, 2), 10.0);
+    fluid.simulate_steps(3);
+    let ages = |fluid: &Fluid| {
+        (1..=16)
+            .map(|x| fluid.get_age_at_index(fluid.ix(x, 2)))
+            .collect::<Vec<f32>>()
+    };
+    let after_three = ages(&fluid);
+
+    fluid.simulate_steps(2);
+    assert_ne!(ages(&fluid), after_three);
+    assert!(fluid.rewind(2));
+    assert_eq!(ages(&fluid), after_three);
+}
+
+#[wasm_bindgen_test]
+fn composite_density_blends_layers() {
+    let blend = |mode| {
+        let mut fluid = Fluid::new(FluidConfig::new(2, 1, 0.0), 0.1);
+        fluid.set_initial_density_grid(&[0.5, 2.0]);
+        fluid.composite_density(&[0.5, 1.5], mode);
+        fluid.get_initial_density_grid()
+    };
+    assert_eq!(blend(BlendMode::Add), vec![1.0, 3.5]);
+    assert_eq!(blend(BlendMode::Max), vec![0.5, 2.0]);
+    assert_eq!(blend(BlendMode::Multiply), vec![0.25, 3.0]);
+    assert_eq!(blend(BlendMode::Over), vec![0.75, 1.5]);
+}
+
+#[wasm_bindgen_test]
+fn axisymmetric_diffusion_conserves_ring_mass() {
+    // Each cell stands for a ring around the axis, so the amount of smoke is
+    // the density weighted by the radius of the cell
+    let ring_mass = |axisymmetric: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.5), 0.1);
+        fluid.set_axisymmetric(axisymmetric);
+        fluid.add_density(fluid.ix(2, 8), 100.0);
+        fluid.simulate();
+        let start: Vec<f32> = fluid.get_initial_density_grid();
+        fluid.simulate_steps(20);
+        let end = fluid.get_initial_density_grid();
+        let weighted = |density: &[f32]| -> f32 {
+            density
+                .iter()
+                .enumerate()
+                .map(|(cell, density)| ((cell % 16) as f32 + 0.5) * density)
+                .sum()
+        };
+        weighted(&end) / weighted(&start)
+    };
+    assert!((ring_mass(true) - 1.0).abs() < 1e-3);
+    // The planar diffusion spreads the smoke out as evenly as near the axis,
+    // which fills the bigger outer rings with more of it than there was
+    assert!(ring_mass(false) > 1.01);
+}
+
+#[wasm_bindgen_test]
+fn sample_density_debug_shows_the_corners() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 1.0);
+    fluid.add_density(fluid.ix(2, 2), 8.0);
+    fluid.simulate();
+
+    let (corners, weights, value) = fluid.sample_density_debug(2.25, 1.5).unwrap();
+    assert_eq!(
+        corners,
+        [
+            fluid.ix(2, 1),
+            fluid.ix(3, 1),
+            fluid.ix(2, 2),
+            fluid.ix(3, 2)
+        ]
+    );
+    assert_eq!(weights, [0.375, 0.125, 0.375, 0.125]);
+    assert_eq!(value, 3.0);
+    assert_eq!(value, fluid.sample_density(2.25, 1.5));
+
+    fluid.set_config_sample_mode(SampleMode::Zero);
+    assert!(fluid.sample_density_debug(-1.0, 2.0).is_none());
+}
+
+#[wasm_bindgen_test]
+fn coriolis_turns_the_flow_clockwise() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 0.1);
+    fluid.set_config_boundary_mode(BoundaryMode::Periodic);
+    fluid.set_coriolis(0.5);
+    fluid.set_velocity_from_fn(|_, _| (1.0, 0.0));
+    fluid.simulate();
+
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+    // Turned by 0.5 * 0.1 radians without gaining speed
+    for (velocity_x, velocity_y) in velocity_x.iter().zip(&velocity_y) {
+        assert!((velocity_x - 0.05f32.cos()).abs() < 1e-5);
+        assert!((velocity_y + 0.05f32.sin()).abs() < 1e-5);
+        assert!((velocity_x.hypot(*velocity_y) - 1.0).abs() < 1e-5);
+    }
+}
+
+#[wasm_bindgen_test]
+fn velocity_samples_are_centered() {
+    let mut fluid = Fluid::new(FluidConfig::new(10, 7, 0.0), 0.1);
+    fluid.set_velocity_from_fn(|x, y| (x, -y));
+
+    // 9 cells between the first and last column, 6 between the first and
+    // last row: the left over 1 and 2 cells are split
+    let samples = fluid.velocity_samples(4);
+    let cells: Vec<(f32, f32)> = samples
+        .chunks(4)
+        .map(|sample| (sample[0], sample[1]))
+        .collect();
+    assert_eq!(
+        cells,
+        vec![
+            (1.0, 2.0),
+            (5.0, 2.0),
+            (9.0, 2.0),
+            (1.0, 6.0),
+            (5.0, 6.0),
+            (9.0, 6.0)
+        ]
+    );
+    let (x, y) = fluid.cell_to_world(5.0, 6.0);
+    assert_eq!(&samples[16..20], &[5.0, 6.0, x, -y]);
+    assert_eq!(fluid.velocity_samples(1).len(), 4 * 10 * 7);
+    assert!(fluid.velocity_samples(0).is_empty());
+}
+
+#[wasm_bindgen_test]
+fn sample_corners_at_the_edges() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 3, 0.0), 0.1);
+    let corners = |fluid: &Fluid, x: f32, y: f32| {
+        fluid
+            .sample_density_debug(x, y)
+            .map(|(corners, weights, _)| (corners, weights))
+    };
+
+    // Exact cell centers put all of the weight on the bottom left corner
+    assert_eq!(
+        corners(&fluid, 2.0, 3.0),
+        Some((
+            [
+                fluid.ix(2, 3),
+                fluid.ix(3, 3),
+                fluid.ix(2, 4),
+                fluid.ix(3, 4)
+            ],
+            [1.0, 0.0, 0.0, 0.0]
+        ))
+    );
+
+    // Clamp stops halfway into the border cells on either side
+    assert_eq!(
+        corners(&fluid, 0.0, 0.0),
+        Some((
+            [
+                fluid.ix(0, 0),
+                fluid.ix(1, 0),
+                fluid.ix(0, 1),
+                fluid.ix(1, 1)
+            ],
+            [0.25, 0.25, 0.25, 0.25]
+        ))
+    );
+    assert_eq!(
+        corners(&fluid, 4.9, 3.9),
+        Some((
+            [
+                fluid.ix(4, 3),
+                fluid.ix(5, 3),
+                fluid.ix(4, 4),
+                fluid.ix(5, 4)
+            ],
+            [0.25, 0.25, 0.25, 0.25]
+        ))
+    );
+
+    fluid.set_config_sample_mode(SampleMode::Zero);
+    assert_eq!(corners(&fluid, 0.0, 2.0), None);
+    assert_eq!(corners(&fluid, 4.9, 2.0), None);
+    assert_eq!(corners(&fluid, 2.0, 3.0).unwrap().1, [1.0, 0.0, 0.0, 0.0]);
+
+    // Wrap never reads the border, 0 is the last column and n + 0.9 is most
+    // of the way from the last column back to the first
+    fluid.set_config_sample_mode(SampleMode::Wrap);
+    assert_eq!(
+        corners(&fluid, 0.0, 3.0),
+        Some((
+            [
+                fluid.ix(4, 3),
+                fluid.ix(1, 3),
+                fluid.ix(4, 1),
+                fluid.ix(1, 1)
+            ],
+            [1.0, 0.0, 0.0, 0.0]
+        ))
+    );
+    let (wrapped, weights) = corners(&fluid, 4.9, 1.0).unwrap();
+    assert_eq!(
+        wrapped,
+        [
+            fluid.ix(4, 1),
+            fluid.ix(1, 1),
+            fluid.ix(4, 2),
+            fluid.ix(1, 2)
+        ]
+    );
+    assert!((weights[1] - 0.9).abs() < 1e-5);
+}
+
+#[wasm_bindgen_test]
+fn presets_set_the_scene() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.preset_jet(Side::Left, 4.0, 4);
+    assert_eq!(
+        fluid.get_config().get_source_application(),
+        SourceApplication::EveryStep
+    );
+    fluid.simulate_steps(40);
+    // The jet keeps feeding smoke in and carries it across the middle
+    assert!(fluid.get_density_at_index(fluid.ix(1, 8)) > 0.1);
+    assert!(fluid.get_density_at_index(fluid.ix(1, 2)) < 1e-3);
+    assert!(fluid.get_density_at_index(fluid.ix(10, 8)) > 1e-3);
+    assert!(fluid.get_velocity_x(fluid.ix(6, 8)) > 0.0);
+
+    let mut fluid = Fluid::new(FluidConfig::new(32, 32, 0.0), 0.1);
+    fluid.preset_vortex_pair();
+    let centroid_y = |fluid: &Fluid| {
+        let (mut mass, mut moment) = (0.0, 0.0);
+        for y in 1..=32 {
+            for x in 1..=32 {
+                let density = fluid.get_density_at_index(fluid.ix(x, y));
+                mass += density;
+                moment += density * y as f32;
+            }
+        }
+        moment / mass
+    };
+    let start = centroid_y(&fluid);
+    fluid.simulate_steps(20);
+    assert!(centroid_y(&fluid) > start + 2.0);
+    let (_, momentum_y) = fluid.total_momentum();
+    assert!(momentum_y > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn mid_step_hook_runs_before_advection() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 0.1);
+    let seen = std::rc::Rc::new(std::cell::RefCell::new(Vec::new()));
+    let record = seen.clone();
+    fluid.set_mid_step_hook(move |fluid| {
+        // The source pushed in this step is already in the velocity
+        record
+            .borrow_mut()
+            .push(fluid.get_velocity_x(fluid.ix(4, 4)));
+        fluid.set_velocity_from_fn(|_, _| (0.0, 0.0));
+    });
+    fluid.add_velocity(fluid.ix(4, 4), 10.0, 0.0);
+    fluid.add_density(fluid.ix(4, 4), 10.0);
+    fluid.simulate_steps(3);
+
+    let seen = seen.borrow();
+    assert_eq!(seen.len(), 3);
+    assert!(seen[0] > 0.0);
+    assert_eq!(seen[1], 0.0);
+    // With the velocity gone before advection the density never moves
+    assert_eq!(fluid.get_density_at_index(fluid.ix(4, 4)), 1.0);
+    assert_eq!(fluid.max_velocity(), 0.0);
+
+    fluid.clear_mid_step_hook();
+    fluid.add_velocity(fluid.ix(4, 4), 10.0, 0.0);
+    fluid.simulate();
+    assert_eq!(seen.len(), 3);
+    assert!(fluid.max_velocity() > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn active_region_tracking_matches_the_full_grid() {
+    let run = |tracking: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(48, 48, 0.01), 0.1);
+        fluid.set_active_region_tracking(tracking, 1e-4);
+        for _ in 0..10 {
+            fluid.add_density(fluid.ix(12, 12), 50.0);
+            fluid.add_velocity(fluid.ix(12, 12), 5.0, 5.0);
+            fluid.simulate();
+        }
+        fluid
+    };
+    let (full, tracked) = (run(false), run(true));
+
+    let mut largest_error: f32 = 0.0;
+    for y in 1..=48 {
+        for x in 1..=48 {
+            let index = full.ix(x, y);
+            largest_error = largest_error.max(
+                (full.get_density_at_index(index) - tracked.get_density_at_index(index)).abs(),
+            );
+        }
+    }
+    assert!(largest_error < 1e-3, "{}", largest_error);
+    // Cells well outside the plume are never swept, the full grid diffuses a
+    // trace into them
+    assert!(full.get_density_at_index(full.ix(21, 12)) > 0.0);
+    assert_eq!(tracked.get_density_at_index(tracked.ix(21, 12)), 0.0);
+}
+
+#[wasm_bindgen_test]
+fn stepped_advection_does_not_reuse_the_active_region() {
+    let run = |tracking: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(48, 48, 0.0), 0.5);
+        fluid.set_active_region_tracking(tracking, 1e-4);
+        fluid.add_density(fluid.ix(12, 12), 50.0);
+        fluid.simulate();
+        // The flow only starts after the box was found, so it has no margin
+        // for it
+        for y in 1..=48 {
+            for x in 1..=48 {
+                fluid.add_velocity(fluid.ix(x, y), 8.0, 0.0);
+            }
+        }
+        fluid.simulate();
+        for _ in 0..4 {
+            fluid.step_advect_density();
+        }
+        (0..fluid.get_size())
+            .map(|index| fluid.get_density_at_index(index))
+            .collect::<Vec<_>>()
+    };
+    assert_eq!(run(false), run(true));
+}
+
+#[wasm_bindgen_test]
+fn getters_see_the_frame_right_after_simulate() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.5);
+    let size = fluid.get_size();
+    fluid.set_advection_velocity(vec![2.0; size], vec![0.0; size]);
+    fluid.add_density(fluid.ix(8, 8), 20.0);
+    fluid.add_velocity(fluid.ix(4, 4), 6.0, 0.0);
+    fluid.simulate();
+
+    // Nothing diffuses, so the 10 the source adds moves exactly one cell
+    assert_eq!(fluid.get_density_at_index(fluid.ix(9, 8)), 10.0);
+    assert_eq!(fluid.get_density_at_index(fluid.ix(8, 8)), 0.0);
+
+    // The velocity getters read the frame the next step continues from
+    let initial_velocity_x = fluid.get_initial_velocity_x_grid();
+    let initial_velocity_y = fluid.get_initial_velocity_y_grid();
+    for y in 1..=16 {
+        for x in 1..=16 {
+            let cell = (y as usize - 1) * 16 + x as usize - 1;
+            assert_eq!(
+                fluid.get_velocity_x(fluid.ix(x, y)),
+                initial_velocity_x[cell]
+            );
+            assert_eq!(
+                fluid.get_velocity_y(fluid.ix(x, y)),
+                initial_velocity_y[cell]
+            );
+        }
+    }
+}
+
+#[wasm_bindgen_test]
+fn elapsed_time_adds_up_the_steps() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 0.1);
+    fluid.set_history_capacity(4);
+    fluid.simulate_steps(3);
+    fluid.set_dt(0.25);
+    fluid.simulate();
+    assert!((fluid.get_elapsed_time() - 0.55).abs() < 1e-6);
+
+    // Rewinding goes back in time too
+    assert!(fluid.rewind(1));
+    assert!((fluid.get_elapsed_time() - 0.3).abs() < 1e-6);
+
+    fluid.clear();
+    assert_eq!(fluid.get_elapsed_time(), 0.0);
+    assert_eq!(fluid.get_steps(), 3);
+
+    // Substeps split the frame without adding time
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 1.0);
+    fluid.set_velocity_faces(vec![2.5; 9 * 8], vec![0.0; 8 * 9]);
+    fluid.set_auto_substep(true, 10);
+    fluid.simulate();
+    assert_eq!(fluid.get_steps(), 3);
+    assert!((fluid.get_elapsed_time() - 1.0).abs() < 1e-6);
+}
+
+#[wasm_bindgen_test]
+fn rewind_undoes_a_whole_substepped_frame() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 1.0);
+    fluid.set_velocity_faces(vec![2.5; 9 * 8], vec![0.0; 8 * 9]);
+    fluid.set_auto_substep(true, 10);
+    fluid.set_history_capacity(4);
+    let before = fluid.state_checksum();
+    fluid.simulate();
+    assert_eq!(fluid.get_steps(), 3);
+    assert_eq!(fluid.history_len(), 1);
+
+    assert!(fluid.rewind(1));
+    assert_eq!(fluid.get_steps(), 0);
+    assert_eq!(fluid.state_checksum(), before);
+}
+
+#[wasm_bindgen_test]
+fn apply_sources_only_adds_the_sources() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.5), 0.5);
+    fluid.add_density(fluid.ix(4, 4), 10.0);
+    fluid.add_velocity(fluid.ix(4, 4), 2.0, -4.0);
+    fluid.apply_sources();
+
+    // One dt of each source lands on its cell alone, nothing diffuses or
+    // moves and no step is counted
+    let cell = (4 - 1) * 8 + 4 - 1;
+    let density = fluid.get_initial_density_grid();
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+    for index in 0..8 * 8 {
+        let expected = if index == cell {
+            (5.0, 1.0, -2.0)
+        } else {
+            (0.0, 0.0, 0.0)
+        };
+        assert_eq!(
+            (density[index], velocity_x[index], velocity_y[index]),
+            expected
+        );
+    }
+    assert_eq!(fluid.get_steps(), 0);
+
+    // The sources are used up
+    fluid.apply_sources();
+    assert_eq!(fluid.get_initial_density_grid(), density);
+}
+
+#[wasm_bindgen_test]
+fn smooth_density_eases_towards_the_target() {
+    let mut fluid = Fluid::new(FluidConfig::new(8, 8, 0.0), 0.5);
+    let cell = (4 - 1) * 8 + 4 - 1;
+    fluid.add_density_smooth(fluid.ix(4, 4), 5.0, 4.0);
+
+    // At most rate * dt a step, landing exactly on the target and staying
+    let mut reached = Vec::new();
+    for _ in 0..4 {
+        fluid.apply_sources();
+        reached.push(fluid.get_initial_density_grid()[cell]);
+    }
+    assert_eq!(reached, vec![2.0, 4.0, 5.0, 5.0]);
+
+    // A new target for the same cell replaces the old one, going down too
+    fluid.add_density_smooth(fluid.ix(4, 4), 10.0, 4.0);
+    fluid.add_density_smooth(fluid.ix(4, 4), 1.0, 4.0);
+    fluid.apply_sources();
+    assert_eq!(fluid.get_initial_density_grid()[cell], 3.0);
+}
+
+#[wasm_bindgen_test]
+fn density_force_points_down_the_gradient() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.set_density_force_coupling(2.0);
+    fluid.add_density(fluid.ix(8, 8), 100.0);
+    fluid.apply_sources();
+    // The projection takes out a pure gradient, so stop the step right
+    // after the force: two free stages and the force's 16 rows
+    fluid.begin_step();
+    assert!(!fluid.step_partial(16));
+
+    let cell = |x: usize, y: usize| (y - 1) * 16 + x - 1;
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+    // 10 of density across two cells, times coupling * dt / 2
+    assert!((velocity_x[cell(9, 8)] - 1.0).abs() < 1e-5);
+    assert!((velocity_x[cell(7, 8)] + 1.0).abs() < 1e-5);
+    assert!((velocity_y[cell(8, 9)] - 1.0).abs() < 1e-5);
+    assert!((velocity_y[cell(8, 7)] + 1.0).abs() < 1e-5);
+    assert_eq!(velocity_x[cell(8, 8)], 0.0);
+    assert_eq!(velocity_x[cell(12, 12)], 0.0);
+}
+
+#[wasm_bindgen_test]
+fn stepper_runs_whole_steps_and_carries_the_rest() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 4, 0.0), 0.1);
+    let mut stepper = Stepper::new(0.25, 4);
+    assert_eq!(stepper.advance(&mut fluid, 0.375), 1);
+    assert_eq!(stepper.get_alpha(), 0.5);
+    // The half step left over completes with the next frame
+    assert_eq!(stepper.advance(&mut fluid, 0.125), 1);
+    assert_eq!(stepper.get_alpha(), 0.0);
+    assert_eq!(fluid.get_steps(), 2);
+
+    // A long pause runs the capped steps and drops the rest of the whole
+    // steps, keeping the fraction for interpolation
+    assert_eq!(stepper.advance(&mut fluid, 10.125), 4);
+    assert_eq!(stepper.get_alpha(), 0.5);
+    assert_eq!(fluid.get_steps(), 6);
+    assert_eq!(stepper.advance(&mut fluid, 0.0), 0);
+
+    stepper.reset();
+    assert_eq!(stepper.get_alpha(), 0.0);
+}
+
+#[wasm_bindgen_test]
+fn velocity_line_leaves_no_gaps() {
+    let mut fluid = Fluid::new(FluidConfig::new(10, 10, 0.0), 0.1);
+    fluid.add_velocity_line(2, 3, 8, 6, 1.0, 0.5, 2.0);
+    fluid.apply_sources();
+
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+    let stroke: Vec<(usize, usize)> = (0..10 * 10)
+        .filter(|&cell| velocity_x[cell] != 0.0)
+        .map(|cell| (cell % 10 + 1, cell / 10 + 1))
+        .collect();
+    // One cell in every column the shallow line spans, moving up a row at a
+    // time from one end to the other
+    assert_eq!(stroke.len(), 7);
+    for (column, &(x, _)) in (2..=8).zip(&stroke) {
+        assert_eq!(x, column);
+    }
+    for pair in stroke.windows(2) {
+        assert!(pair[1].1 - pair[0].1 <= 1);
+    }
+    assert_eq!((stroke[0].1, stroke[6].1), (3, 6));
+    for cell in (0..10 * 10).filter(|&cell| velocity_x[cell] != 0.0) {
+        assert!((velocity_x[cell] - 0.2).abs() < 1e-6);
+        assert!((velocity_y[cell] - 0.1).abs() < 1e-6);
+    }
+
+    // Endpoints off the grid are clamped to the interior
+    let mut fluid = Fluid::new(FluidConfig::new(10, 10, 0.0), 0.1);
+    fluid.add_density_line(0, 5, 50, 5, 10.0);
+    fluid.apply_sources();
+    let density = fluid.get_initial_density_grid();
+    let row: Vec<f32> = density[4 * 10..5 * 10].to_vec();
+    assert_eq!(row, vec![1.0; 10]);
+    assert_eq!(density.iter().sum::<f32>(), 10.0);
+}
+
+#[wasm_bindgen_test]
+fn density_histogram_buckets_the_interior() {
+    let mut fluid = Fluid::new(FluidConfig::new(4, 2, 0.0), 0.1);
+    fluid.set_initial_density_grid(&[-1.0, 0.0, 0.5, 1.0, 2.4, 2.5, 3.9, 100.0]);
+    // Nothing moves or spreads, the step only brings the values to the front
+    fluid.simulate();
+
+    // Out of range values land in the first and last bins
+    assert_eq!(fluid.density_histogram(4, 4.0), vec![3, 1, 2, 2]);
+    assert_eq!(fluid.density_histogram(1, 4.0), vec![8]);
+    assert_eq!(fluid.density_histogram(2, 0.0), vec![8, 0]);
+    assert!(fluid.density_histogram(0, 4.0).is_empty());
+}
+
+#[wasm_bindgen_test]
+fn vortex_swirls_counterclockwise() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.add_vortex(8, 8, 4, 10.0);
+    fluid.add_vortex(8, 8, 4, 10.0);
+    fluid.apply_sources();
+
+    let cell = |x: usize, y: usize| (y - 1) * 16 + x - 1;
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+    // Tangential, fading from the center out to the radius, and adding up
+    // over both calls: 2 * 10 * (1 - 1 / 4) * dt next to the center
+    assert!((velocity_y[cell(9, 8)] - 1.5).abs() < 1e-5);
+    assert!((velocity_y[cell(10, 8)] - 1.0).abs() < 1e-5);
+    assert_eq!(velocity_x[cell(10, 8)], 0.0);
+    assert!((velocity_y[cell(7, 8)] + 1.5).abs() < 1e-5);
+    assert!((velocity_x[cell(8, 9)] + 1.5).abs() < 1e-5);
+    assert!((velocity_x[cell(8, 7)] - 1.5).abs() < 1e-5);
+    assert_eq!(velocity_x[cell(8, 8)], 0.0);
+    assert_eq!(velocity_y[cell(13, 8)], 0.0);
+}
+
+#[wasm_bindgen_test]
+fn sink_drains_and_pulls_inwards() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    for y in 1..=16 {
+        for x in 1..=16 {
+            fluid.add_density(fluid.ix(x, y), 10.0);
+        }
+    }
+    fluid.apply_sources();
+    fluid.add_sink(8, 8, 5.0);
+    fluid.apply_sources();
+
+    let cell = |x: usize, y: usize| (y - 1) * 16 + x - 1;
+    let density = fluid.get_initial_density_grid();
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+    // The center loses strength * dt and the drain fades further out
+    assert!((density[cell(8, 8)] - 0.5).abs() < 1e-5);
+    assert!(density[cell(9, 8)] > density[cell(8, 8)]);
+    assert!(density[cell(9, 8)] < 1.0);
+    assert_eq!(density[cell(14, 8)], 1.0);
+    assert!(velocity_x[cell(10, 8)] < 0.0);
+    assert!(velocity_x[cell(6, 8)] > 0.0);
+    assert!(velocity_y[cell(8, 10)] < 0.0);
+    assert!(velocity_y[cell(8, 6)] > 0.0);
+}
+
+#[wasm_bindgen_test]
+fn sponge_damps_next_to_walls_and_solids() {
+    let run = |sponge: bool| {
+        let mut fluid = Fluid::new(FluidConfig::new(24, 24, 0.0), 0.1);
+        if sponge {
+            fluid.set_sponge(3, 5.0);
+        }
+        // Placed after the sponge, which has to notice it
+        fluid.set_solid(12, 12, true);
+        fluid.set_velocity_from_fn(|_, _| (0.0, 1.0));
+        fluid.simulate();
+        let speed = |x: u16, y: u16| {
+            let index = fluid.ix(x, y);
+            fluid
+                .get_velocity_x(index)
+                .hypot(fluid.get_velocity_y(index))
+        };
+        [speed(1, 6), speed(13, 12), speed(6, 6)]
+    };
+    let (free, damped) = (run(false), run(true));
+    // Cells right next to a wall or the solid lose strength * dt of their
+    // speed, cells further than the width keep it
+    assert!((damped[0] - 0.5 * free[0]).abs() < 1e-5);
+    assert!((damped[1] - 0.5 * free[1]).abs() < 1e-5);
+    assert_eq!(damped[2], free[2]);
+}
+
+#[wasm_bindgen_test]
+fn explosion_pushes_outwards() {
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.explode(8, 8, 4, 20.0);
+    fluid.apply_sources();
+    let cell = |x: usize, y: usize| (y - 1) * 16 + x - 1;
+    let density = fluid.get_initial_density_grid();
+    let velocity_x = fluid.get_initial_velocity_x_grid();
+    let velocity_y = fluid.get_initial_velocity_y_grid();
+
+    // Only the inner half of the radius gets smoke
+    assert!(density[cell(8, 8)] > density[cell(10, 8)]);
+    assert!(density[cell(10, 8)] > 0.0);
+    assert_eq!(density[cell(11, 8)], 0.0);
+    assert!(velocity_x[cell(10, 8)] > 0.0);
+    assert!(velocity_x[cell(6, 8)] < 0.0);
+    assert!(velocity_y[cell(8, 10)] > 0.0);
+    assert!(velocity_y[cell(8, 6)] < 0.0);
+    assert_eq!(velocity_x[cell(8, 8)], 0.0);
+    // Further out the push is weaker
+    assert!(velocity_x[cell(12, 8)] < velocity_x[cell(10, 8)]);
+
+    // A blast in a corner stays inside the grid
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.explode(1, 16, 6, 20.0);
+    fluid.simulate();
+    assert!(fluid.get_density_at_index(fluid.ix(1, 16)) > 0.0);
+
+    // An implosion pulls inwards and still puffs the same smoke
+    let mut fluid = Fluid::new(FluidConfig::new(16, 16, 0.0), 0.1);
+    fluid.explode(8, 8, 4, -20.0);
+    fluid.apply_sources();
+    let imploded = fluid.get_initial_density_grid();
+    assert_eq!(imploded, density);
+    assert!(fluid.get_initial_velocity_x_grid()[cell(10, 8)] < 0.0);
+}
+
+#[wasm_bindgen_test]
+fn advection_does_not_cross_solid_walls() {
+    let run = |solid_advection: SolidAdvection| {
+        let mut fluid = Fluid::new(FluidConfig::new(16, 8, 0.0), 1.0);
+        fluid.set_config_solid_advection(solid_advection);
+        for y in 1..=8 {
+            fluid.set_solid(9, y, true);
+        }
+        // A flow of three cells a step aimed straight at the wall
+        let size = fluid.get_size();
+        fluid.set_advection_velocity(vec![3.0; size], vec![0.0; size]);
+        for y in 1..=8 {
+            fluid.add_density(fluid.ix(7, y), 10.0);
+        }
+        fluid.simulate_steps(2);
+        let mut behind_the_wall = 0.0;
+        for y in 1..=8 {
+            for x in 10..=16 {
+                behind_the_wall += fluid.get_density_at_index(fluid.ix(x, y));
+            }
+        }
+        behind_the_wall
+    };
+
+    assert_eq!(run(SolidAdvection::Clamp), 0.0);
+    // Tracing through the wall picks up the density in front of it
+    assert!(run(SolidAdvection::Through) > 1.0);
+}